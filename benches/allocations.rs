@@ -0,0 +1,86 @@
+//! Counts the allocations made sending and receiving packets. It replaces the
+//! global allocator, so it runs on its own rather than among the tests.
+//!
+//! Run with `cargo bench --features bench`, or with `cargo test --features
+//! bench --bench allocations` to skip the optimized build.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use animus_lib::network::bench::{Encoder, Receiver};
+
+const PACKETS: usize = 1000;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn allocations<F: FnOnce()>(f: F) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    f();
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn encoding(mut encoder: Encoder) -> usize {
+    // once the pool has a frame to hand out
+    encoder.encode();
+    allocations(|| {
+        for _ in 0..PACKETS {
+            encoder.encode();
+        }
+    })
+}
+
+fn receiving(mut receiver: Receiver) -> usize {
+    receiver.receive();
+    allocations(|| {
+        for _ in 0..PACKETS {
+            receiver.receive();
+        }
+    })
+}
+
+fn check(what: &str, allocations: usize, expected: usize) {
+    println!("{what}: {allocations} allocations for {PACKETS} packets");
+    assert_eq!(allocations, expected, "allocations {what}");
+}
+
+fn main() {
+    check(
+        "encoding path targets",
+        encoding(Encoder::path_targets(PACKETS + 1)),
+        0,
+    );
+    check(
+        "receiving path targets",
+        receiving(Receiver::path_targets(PACKETS + 1)),
+        0,
+    );
+    check(
+        "encoding messages",
+        encoding(Encoder::messages(PACKETS + 1)),
+        0,
+    );
+    // just the contents of each message, copied out of the frame once
+    check(
+        "receiving messages",
+        receiving(Receiver::messages(PACKETS + 1)),
+        PACKETS,
+    );
+}
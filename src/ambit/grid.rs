@@ -0,0 +1,179 @@
+use bevy::{
+    prelude::{Entity, RemovedComponents, ResMut, Resource},
+    utils::HashMap,
+};
+
+use super::view::DistanceMetric;
+use crate::path::plugin::Position;
+
+/// Width and height of a cell. Queries look at every cell their area touches,
+/// so this is about the distance most queries cover.
+const CELL_SIZE: i32 = 8;
+
+// resources
+/// Positioned entities bucketed into square cells, so that finding those near
+/// a point only looks at the cells around it.
+#[derive(Resource, Default)]
+pub(crate) struct SpatialGrid {
+    cells: HashMap<(i32, i32), Vec<Entity>>,
+    positions: HashMap<Entity, Position>,
+}
+
+impl SpatialGrid {
+    pub(crate) fn position(&self, entity: Entity) -> Option<Position> {
+        self.positions.get(&entity).copied()
+    }
+
+    /// Puts `entity` at `position` and returns where it was before.
+    pub(crate) fn insert(&mut self, entity: Entity, position: Position) -> Option<Position> {
+        let previous = self.positions.insert(entity, position);
+
+        match previous {
+            Some(previous) if cell(previous) == cell(position) => {}
+            _ => {
+                if let Some(previous) = previous {
+                    self.remove_from_cell(entity, previous);
+                }
+                self.cells.entry(cell(position)).or_default().push(entity);
+            }
+        }
+
+        previous
+    }
+
+    pub(crate) fn remove(&mut self, entity: Entity) -> Option<Position> {
+        let position = self.positions.remove(&entity)?;
+        self.remove_from_cell(entity, position);
+        Some(position)
+    }
+
+    /// Entities less than `distance` away from `center`, measured by `metric`.
+    pub(crate) fn closer_than(
+        &self,
+        center: Position,
+        distance: u32,
+        metric: DistanceMetric,
+    ) -> impl Iterator<Item = (Entity, Position)> + '_ {
+        let reach = distance.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let corner = |sign: i32| Position {
+            x: center.x.saturating_add(sign * reach),
+            y: center.y.saturating_add(sign * reach),
+        };
+
+        // every metric sees within the square
+        self.in_rect(corner(-1), corner(1))
+            .filter(move |(_, position)| metric.closer_than(center, *position, distance))
+    }
+
+    /// Entities inside the rectangle from `min` to `max`, both included.
+    pub(crate) fn in_rect(
+        &self,
+        min: Position,
+        max: Position,
+    ) -> impl Iterator<Item = (Entity, Position)> + '_ {
+        let (min_cell, max_cell) = (cell(min), cell(max));
+
+        (min_cell.0..=max_cell.0)
+            .flat_map(move |x| (min_cell.1..=max_cell.1).map(move |y| (x, y)))
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .map(|&entity| (entity, self.positions[&entity]))
+            .filter(move |(_, p)| (min.x..=max.x).contains(&p.x) && (min.y..=max.y).contains(&p.y))
+    }
+
+    fn remove_from_cell(&mut self, entity: Entity, position: Position) {
+        let cell = cell(position);
+        let Some(entities) = self.cells.get_mut(&cell) else {
+            return;
+        };
+
+        entities.retain(|&other| other != entity);
+        if entities.is_empty() {
+            self.cells.remove(&cell);
+        }
+    }
+}
+
+// util
+fn cell(position: Position) -> (i32, i32) {
+    (
+        position.x.div_euclid(CELL_SIZE),
+        position.y.div_euclid(CELL_SIZE),
+    )
+}
+
+// systems
+/// Entities are despawned when commands are applied at the end of a stage, so
+/// this runs in a later one to see them go.
+pub(super) fn remove_despawned_from_grid(
+    mut grid: ResMut<SpatialGrid>,
+    removed: RemovedComponents<Position>,
+) {
+    for entity in removed.iter() {
+        grid.remove(entity);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ambit::view::DistanceMetric::{Chebyshev, Taxicab};
+
+    fn sorted<I>(iter: I) -> Vec<u32>
+    where
+        I: Iterator<Item = (Entity, Position)>,
+    {
+        let mut ids = iter.map(|(entity, _)| entity.index()).collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn queries_only_find_entities_in_range() {
+        let mut grid = SpatialGrid::default();
+        for (index, (x, y)) in [(0, 0), (3, 3), (-4, 2), (7, 0), (20, 20)]
+            .into_iter()
+            .enumerate()
+        {
+            grid.insert(Entity::from_raw(index as u32), Position { x, y });
+        }
+
+        assert_eq!(
+            sorted(grid.closer_than(Position { x: 0, y: 0 }, 7, Taxicab)),
+            [0, 1, 2]
+        );
+        assert_eq!(
+            sorted(grid.closer_than(Position { x: 0, y: 0 }, 8, Taxicab)),
+            [0, 1, 2, 3]
+        );
+        assert!(sorted(grid.closer_than(Position { x: 0, y: 0 }, 0, Taxicab)).is_empty());
+        assert_eq!(
+            sorted(grid.closer_than(Position { x: 0, y: 0 }, 4, Chebyshev)),
+            [0, 1]
+        );
+        assert_eq!(
+            sorted(grid.in_rect(Position { x: -4, y: 0 }, Position { x: 3, y: 2 })),
+            [0, 2]
+        );
+    }
+
+    #[test]
+    fn moved_entities_are_found_at_their_new_position() {
+        let mut grid = SpatialGrid::default();
+        let entity = Entity::from_raw(0);
+
+        assert_eq!(grid.insert(entity, Position { x: 1, y: 1 }), None);
+        assert_eq!(
+            grid.insert(entity, Position { x: 30, y: -9 }),
+            Some(Position { x: 1, y: 1 })
+        );
+
+        assert!(sorted(grid.closer_than(Position { x: 1, y: 1 }, 5, Taxicab)).is_empty());
+        assert_eq!(
+            sorted(grid.closer_than(Position { x: 30, y: -9 }, 1, Taxicab)),
+            [0]
+        );
+        assert_eq!(grid.remove(entity), Some(Position { x: 30, y: -9 }));
+        assert!(grid.cells.is_empty());
+    }
+}
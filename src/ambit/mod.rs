@@ -1,2 +1,6 @@
+pub(crate) mod grid;
 pub(crate) mod packet;
 pub mod plugin;
+pub(crate) mod rule;
+pub(crate) mod sight;
+pub(crate) mod view;
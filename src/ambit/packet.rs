@@ -1,10 +1,22 @@
 use speedy::{Readable, Writable};
 
+use super::plugin::EntityKind;
 use crate::id::NetworkId;
 
-#[derive(Readable, Writable, Debug, PartialEq, Eq, PartialOrd, Clone, Copy)]
+/// Everything a client needs to build an entity that came into view.
+#[derive(Readable, Writable, Debug, PartialEq, Eq, PartialOrd, Clone)]
 pub(crate) struct SpawnEntity {
     pub(crate) id: NetworkId,
+    pub(crate) kind: EntityKind,
+    /// Where the entity is headed, like [`PathTarget`].
+    ///
+    /// [`PathTarget`]: crate::path::packet::PathTarget
+    pub(crate) x: i32,
+    pub(crate) y: i32,
+    pub(crate) current_or_next_x: i32,
+    pub(crate) current_or_next_y: i32,
+    pub(crate) speed: u32,
+    pub(crate) name: Option<String>,
 }
 
 #[derive(Readable, Writable, Debug, PartialEq, Eq, PartialOrd, Clone, Copy)]
@@ -1,37 +1,73 @@
-use bevy::prelude::{
-    Changed, Commands, Component, EventReader, EventWriter, IntoSystemDescriptor, Plugin, Query,
-    Res, ResMut,
+use bevy::{
+    prelude::{
+        Changed, Commands, Component, CoreStage, Entity, IntoSystemDescriptor, Plugin, Query, Res,
+        ResMut, With,
+    },
+    utils::{HashMap, HashSet},
 };
+use speedy::{Readable, Writable};
 use tracing::error;
 
-use super::packet::{DespawnEntity, QueryEntity, SpawnEntity};
+use super::{
+    grid::{remove_despawned_from_grid, SpatialGrid},
+    packet::{DespawnEntity, SpawnEntity},
+    rule::{Hidden, Stealth, Verdict, VisibilityRuleAppExt, VisibleTo},
+    sight::{LineOfSight, Obstacles},
+    view::ViewRange,
+};
 use crate::{
     id::{NetworkId, NetworkToWorld},
-    network::plugin::{Client, Network, Packets, Server},
-    path::plugin::{MaybeNextPosition, Position},
+    network::{
+        budget::PendingUpdates,
+        plugin::{Client, Packets, Server},
+        spectate::Spectator,
+    },
+    path::plugin::{Heading, MaybeNextPosition, Path, Position},
     stat::MovementSpeed,
 };
 
 // plugin
 pub struct AmbitPlugin;
 
+pub(crate) struct ServerAmbitPlugin;
+
+pub(crate) struct ClientAmbitPlugin;
+
 impl Plugin for AmbitPlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
         #[cfg(feature = "server")]
-        {
-            app.add_event::<VisibilityCollision>();
-            app.add_system(
-                raise_events_on_collisions
-                    .after("set_position")
-                    .label("collision"),
-            );
-            app.add_system(notify_visibility_change_to_clients);
-        }
+        app.add_plugin(ServerAmbitPlugin);
 
         #[cfg(feature = "client")]
-        {
-            app.add_system(receive_visibility_change_from_server);
-        }
+        app.add_plugin(ClientAmbitPlugin);
+    }
+}
+
+impl Plugin for ServerAmbitPlugin {
+    fn build(&self, app: &mut bevy::prelude::App) {
+        app.init_resource::<SpatialGrid>();
+        app.init_resource::<Obstacles>();
+        app.add_visibility_rule::<Hidden>();
+        app.add_visibility_rule::<VisibleTo>();
+        app.add_visibility_rule::<Stealth>();
+        app.add_system_to_stage(CoreStage::PostUpdate, remove_despawned_from_grid);
+        app.add_system(index_moved_entities.after("set_position").label("grid"));
+        app.add_system(
+            update_visible_entities
+                .after("grid")
+                .after("viewpoints")
+                .label("visibility"),
+        );
+    }
+}
+
+impl Plugin for ClientAmbitPlugin {
+    fn build(&self, app: &mut bevy::prelude::App) {
+        app.add_system(
+            receive_visibility_change_from_server
+                .after("spawn_self")
+                .label("spawn_entities"),
+        );
     }
 }
 
@@ -41,85 +77,142 @@ impl Plugin for AmbitPlugin {
 #[derive(Component)]
 pub(crate) struct Player;
 
-// event
-struct VisibilityCollision {
-    ids: [NetworkId; 2],
-    kind: VisibilityCollisionKind,
+/// What an entity is, which decides what clients build it from. Entities
+/// without one are players.
+#[derive(Readable, Writable, Component, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd)]
+pub(crate) enum EntityKind {
+    #[default]
+    Player,
+    Npc,
+    Item,
+    Projectile,
 }
 
-// util
-enum VisibilityCollisionKind {
-    Enter,
-    Leave,
+/// The name clients show above an entity.
+#[derive(Component, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct DisplayName(pub(crate) String);
+
+/// The entities a client has been told to spawn, and not yet to despawn.
+#[derive(Component, Default, Debug)]
+pub(crate) struct VisibleEntities {
+    ids: HashSet<NetworkId>,
+    /// The entities that came into view on the last visibility update.
+    entered: Vec<(NetworkId, Entity)>,
+    /// What visibility rules decided about entities since the last update.
+    verdicts: HashMap<Entity, Verdict>,
 }
 
-// system
-fn raise_events_on_collisions(
-    changed_positions: Query<
-        (&Position, Option<&MaybeNextPosition>, &NetworkId),
-        Changed<Position>,
-    >,
-    positions: Query<(&Position, &NetworkId)>,
-    mut collisions: EventWriter<VisibilityCollision>,
-) {
-    for (position1, maybe_next_position, network1) in changed_positions.iter() {
-        for (position2, network2) in positions.iter() {
-            if *network2 == *network1 {
-                continue;
-            }
+impl VisibleEntities {
+    pub(crate) fn contains(&self, id: NetworkId) -> bool {
+        self.ids.contains(&id)
+    }
 
-            let kind = if position1.taxi_distance(*position2) < 12
-                && maybe_next_position
-                    .and_then(|m| m.position())
-                    .map_or(false, |pos| pos.taxi_distance(*position2) >= 12)
-            {
-                VisibilityCollisionKind::Leave
-            } else if position1.taxi_distance(*position2) >= 10
-                && maybe_next_position
-                    .and_then(|m| m.position())
-                    .map_or(true, |pos| pos.taxi_distance(*position2) < 10)
-            {
-                VisibilityCollisionKind::Enter
-            } else {
-                continue;
-            };
+    pub(crate) fn iter(&self) -> impl Iterator<Item = NetworkId> + '_ {
+        self.ids.iter().copied()
+    }
 
-            collisions.send(VisibilityCollision {
-                ids: [*network1, *network2],
-                kind,
-            });
-        }
+    pub(crate) fn entered(&self) -> &[(NetworkId, Entity)] {
+        &self.entered
+    }
+
+    /// Records a rule's `verdict` about `entity`, unless another rule already
+    /// hid it.
+    pub(crate) fn judge(&mut self, entity: Entity, verdict: Verdict) {
+        let judged = self.verdicts.entry(entity).or_insert(verdict);
+        *judged = (*judged).max(verdict);
     }
 }
 
-fn notify_visibility_change_to_clients(
-    mut collisions: EventReader<VisibilityCollision>,
-    network_to_world: Res<NetworkToWorld<Server>>,
-    clients: Query<(&NetworkId, &Network<Client>)>,
+// system
+fn index_moved_entities(
+    mut grid: ResMut<SpatialGrid>,
+    moved: Query<(Entity, &Position), Changed<Position>>,
 ) {
-    for collision in collisions.iter() {
-        for (id1, id2) in [(0, 1), (1, 0)] {
-            let Some(entity) = network_to_world.get(&collision.ids[id1]) else {
-                continue;
-            };
+    for (entity, &position) in moved.iter() {
+        grid.insert(entity, position);
+    }
+}
 
-            let Ok((id, client)) = clients.get(*entity) else {
-                continue;
-            };
+/// Works out what every client sees from scratch, so that joins, teleports
+/// and despawns are covered as well as movement, and tells the clients what
+/// changed.
+#[allow(clippy::type_complexity)]
+#[allow(clippy::too_many_arguments)]
+fn update_visible_entities(
+    grid: Res<SpatialGrid>,
+    obstacles: Res<Obstacles>,
+    mut clients: Query<(
+        Entity,
+        Option<&Position>,
+        Option<&Spectator>,
+        Option<&mut LineOfSight>,
+        &mut VisibleEntities,
+        &mut PendingUpdates,
+    )>,
+    ranges: Query<&ViewRange>,
+    sighted: Query<(), With<LineOfSight>>,
+    ids: Query<&NetworkId>,
+    entities: Query<EntityState>,
+    network_to_world: Res<NetworkToWorld<Server>>,
+) {
+    for (entity, position, spectator, mut sight, mut visible, mut updates) in clients.iter_mut() {
+        // clients in the login queue see nothing yet
+        let center = position
+            .copied()
+            .or_else(|| spectator.and_then(Spectator::center));
+        // spectators watching an entity see as far as it does
+        let viewer = spectator.and_then(Spectator::watched).unwrap_or(entity);
+        let range = ranges.get(viewer).copied().unwrap_or_default();
+        let verdicts = std::mem::take(&mut visible.verdicts);
 
-            if *id == collision.ids[id2] {
-                continue;
+        let mut now = HashSet::new();
+        if let Some(center) = center {
+            for (other, position) in grid.closer_than(center, range.leave(), range.metric) {
+                let Ok(&id) = ids.get(other) else {
+                    continue;
+                };
+                if other == entity || verdicts.get(&other) == Some(&Verdict::Hide) {
+                    continue;
+                }
+                // entities already in view stay until they are out of range
+                if !(visible.contains(id) || range.enters(center, position)) {
+                    continue;
+                }
+                // however close, entities behind obstacles are out of view
+                let in_sight = match &mut sight {
+                    Some(sight) if viewer == entity => sight.sees(&obstacles, center, position),
+                    _ if sighted.contains(viewer) => obstacles.clear_between(center, position),
+                    _ => true,
+                };
+                if !in_sight {
+                    continue;
+                }
+                now.insert(id);
             }
 
-            let _ = match collision.kind {
-                VisibilityCollisionKind::Enter => client.send(SpawnEntity {
-                    id: collision.ids[id2],
-                }),
-                VisibilityCollisionKind::Leave => client.send(DespawnEntity {
-                    id: collision.ids[id2],
-                }),
+            let shown = verdicts
+                .iter()
+                .filter(|&(_, &verdict)| verdict == Verdict::Show)
+                .filter_map(|(&other, _)| ids.get(other).ok());
+            now.extend(shown);
+        }
+
+        for &id in visible.ids.difference(&now) {
+            let _ = updates.push(id, DespawnEntity { id });
+        }
+        let mut entered = Vec::new();
+        for &id in now.difference(&visible.ids) {
+            let Some((other, spawn)) = network_to_world.get(&id).and_then(|&other| {
+                let spawn = spawn_entity(id, entities.get(other).ok()?);
+                Some((other, spawn))
+            }) else {
+                continue;
             };
+            let _ = updates.push(id, spawn);
+            entered.push((id, other));
         }
+        visible.ids = now;
+        visible.entered = entered;
     }
 }
 
@@ -128,21 +221,35 @@ fn receive_visibility_change_from_server(
     spawn_entities: Res<Packets<SpawnEntity>>,
     despawn_entities: Res<Packets<DespawnEntity>>,
     mut network_to_world: ResMut<NetworkToWorld<Client>>,
-    server: Query<&Network<Server>>,
 ) {
-    if !spawn_entities.is_empty() {
-        let Ok(server) = server.get_single() else {
-            error!("Client not yet connected");
-            return;
+    for spawn in spawn_entities.iter() {
+        let origin = Position {
+            x: spawn.current_or_next_x,
+            y: spawn.current_or_next_y,
+        };
+        let target = Position {
+            x: spawn.x,
+            y: spawn.y,
         };
 
-        for spawn in spawn_entities.iter() {
-            let entity = commands.spawn((spawn.id, MovementSpeed(3), Player)).id();
-            if let Some(prev) = network_to_world.insert(spawn.id, entity) {
-                error!("entity already existed");
-                commands.entity(prev).despawn();
-            }
-            let _ = server.send(QueryEntity { id: spawn.id });
+        let mut entity = commands.spawn((
+            spawn.id,
+            spawn.kind,
+            MovementSpeed(spawn.speed as usize),
+            origin,
+            Path::between(origin, target),
+            MaybeNextPosition::default(),
+        ));
+        if spawn.kind == EntityKind::Player {
+            entity.insert(Player);
+        }
+        if let Some(name) = spawn.name {
+            entity.insert(DisplayName(name));
+        }
+
+        if let Some(prev) = network_to_world.insert(spawn.id, entity.id()) {
+            error!("entity already existed");
+            commands.entity(prev).despawn();
         }
     }
 
@@ -154,3 +261,188 @@ fn receive_visibility_change_from_server(
         commands.entity(entity).despawn();
     }
 }
+
+// util
+type EntityState<'a> = (
+    Option<&'a EntityKind>,
+    &'a Position,
+    Option<&'a Path>,
+    Option<&'a Heading>,
+    Option<&'a MovementSpeed>,
+    Option<&'a DisplayName>,
+);
+
+fn spawn_entity(id: NetworkId, entity: EntityState) -> SpawnEntity {
+    let (kind, &position, path, heading, speed, name) = entity;
+    let empty = Path::default();
+    let Heading { target, origin } = Heading::of(position, path.unwrap_or(&empty), heading);
+
+    SpawnEntity {
+        id,
+        kind: kind.copied().unwrap_or_default(),
+        x: target.x,
+        y: target.y,
+        current_or_next_x: origin.x,
+        current_or_next_y: origin.y,
+        speed: speed.map_or(0, |speed| **speed as u32),
+        name: name.map(|name| name.0.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DisplayName, EntityKind, Player, ViewRange};
+    use crate::{
+        ambit::{
+            sight::{LineOfSight, Obstacles},
+            view::DistanceMetric,
+        },
+        id::{NetworkId, NetworkToWorld},
+        network::{
+            harness::Harness,
+            plugin::{Client, Server},
+        },
+        path::{packet::PathTargetRequest, plugin::Position},
+        stat::MovementSpeed,
+    };
+
+    fn sees(harness: &Harness, client: usize, id: NetworkId) -> bool {
+        harness.clients[client]
+            .world
+            .resource::<NetworkToWorld<Client>>()
+            .contains_key(&id)
+    }
+
+    #[test]
+    fn walking_out_of_view_despawns_on_other_clients() {
+        let mut harness = Harness::new(2);
+        harness.wait_for_players();
+        let walker = harness.client_id(0).unwrap();
+        harness.wait_until_client_sees(1, walker, Position { x: 0, y: 0 });
+
+        harness.send_from_client(0, PathTargetRequest { x: 0, y: 13 });
+
+        harness.step_until("the walker is despawned on the other client", |harness| {
+            !sees(harness, 1, walker)
+        });
+        // the walker itself still arrives
+        harness.wait_until_client_sees(0, walker, Position { x: 0, y: 13 });
+    }
+
+    #[test]
+    fn late_joiners_see_players_standing_still() {
+        let mut harness = Harness::new(1);
+        harness.wait_for_players();
+        harness.add_client();
+        harness.wait_for_players();
+        let (first, second) = (harness.client_id(0).unwrap(), harness.client_id(1).unwrap());
+
+        harness.wait_until_client_sees(1, first, Position { x: 0, y: 0 });
+        harness.wait_until_client_sees(0, second, Position { x: 0, y: 0 });
+    }
+
+    #[test]
+    fn disconnected_players_despawn_on_other_clients() {
+        let mut harness = Harness::new(2);
+        harness.wait_for_players();
+        let leaver = harness.client_id(1).unwrap();
+        harness.wait_until_client_sees(0, leaver, Position { x: 0, y: 0 });
+
+        harness.clients.pop();
+
+        harness.step_until("the leaver is despawned on the other client", |harness| {
+            !sees(harness, 0, leaver)
+        });
+    }
+
+    #[test]
+    fn spawned_entities_arrive_with_their_kind_and_state() {
+        let mut harness = Harness::new(1);
+        harness.wait_for_players();
+        let guard = NetworkId::from(1000);
+        let entity = harness
+            .server
+            .world
+            .spawn((
+                guard,
+                EntityKind::Npc,
+                DisplayName("Guard".to_owned()),
+                MovementSpeed(5),
+                Position { x: 3, y: 0 },
+            ))
+            .id();
+        harness
+            .server
+            .world
+            .resource_mut::<NetworkToWorld<Server>>()
+            .insert(guard, entity);
+
+        harness.wait_until_client_sees(0, guard, Position { x: 3, y: 0 });
+
+        let world = &mut harness.clients[0].world;
+        let entity = world.resource::<NetworkToWorld<Client>>()[&guard];
+        let entity = world.entity(entity);
+        assert_eq!(entity.get::<EntityKind>(), Some(&EntityKind::Npc));
+        assert_eq!(entity.get::<DisplayName>().unwrap().0, "Guard");
+        assert_eq!(**entity.get::<MovementSpeed>().unwrap(), 5);
+        assert!(!entity.contains::<Player>());
+    }
+
+    #[test]
+    fn scouts_see_further_than_they_are_seen() {
+        let mut harness = Harness::new(2);
+        harness.wait_for_players();
+        let (scout, walker) = (harness.client_id(0).unwrap(), harness.client_id(1).unwrap());
+        harness.wait_until_client_sees(0, walker, Position { x: 0, y: 0 });
+        harness.wait_until_client_sees(1, scout, Position { x: 0, y: 0 });
+
+        let entity = harness.server.world.resource::<NetworkToWorld<Server>>()[&scout];
+        harness.server.world.entity_mut(entity).insert(ViewRange {
+            enter: 20,
+            hysteresis: 2,
+            metric: DistanceMetric::Euclidean,
+        });
+
+        harness.send_from_client(1, PathTargetRequest { x: 9, y: 9 });
+        harness.step_until("the walker lost sight of the scout", |harness| {
+            !sees(harness, 1, scout)
+        });
+        // while the scout still sees it
+        harness.wait_until_client_sees(0, walker, Position { x: 9, y: 9 });
+    }
+
+    #[test]
+    fn obstacles_hide_entities_from_those_with_line_of_sight() {
+        let mut harness = Harness::new(2);
+        harness.wait_for_players();
+        let (walker, watcher) = (harness.client_id(0).unwrap(), harness.client_id(1).unwrap());
+        harness.send_from_client(0, PathTargetRequest { x: 4, y: 0 });
+        harness.wait_until_client_sees(1, walker, Position { x: 4, y: 0 });
+
+        let entity = harness.server.world.resource::<NetworkToWorld<Server>>()[&watcher];
+        harness
+            .server
+            .world
+            .entity_mut(entity)
+            .insert(LineOfSight::default());
+        let wall = Position { x: 2, y: 0 };
+        harness
+            .server
+            .world
+            .resource_mut::<Obstacles>()
+            .insert(wall);
+
+        harness.step_until("the wall hides the walker", |harness| {
+            !sees(harness, 1, walker)
+        });
+        // the walker has no line of sight to need
+        assert!(sees(&harness, 0, watcher));
+
+        harness
+            .server
+            .world
+            .resource_mut::<Obstacles>()
+            .remove(wall);
+        harness.wait_until_client_sees(1, walker, Position { x: 4, y: 0 });
+    }
+}
@@ -0,0 +1,164 @@
+use bevy::{
+    ecs::query::{QueryItem, ReadOnlyWorldQuery},
+    prelude::{App, Component, Entity, IntoSystemDescriptor, Query},
+    utils::HashSet,
+};
+
+use super::plugin::VisibleEntities;
+use crate::{id::NetworkId, network::spectate::Spectator};
+
+/// What a rule decides about a viewer seeing an entity. Where rules disagree
+/// hiding wins, so that nothing shows what one rule hides.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Verdict {
+    /// Seen wherever the viewer is, as long as it sees anything at all.
+    Show,
+    /// Never seen, however close.
+    Hide,
+}
+
+/// A component that decides who sees the entity it is on, instead of distance
+/// and line of sight.
+pub(crate) trait VisibilityRule: Component {
+    /// What the rule needs to know about each viewer.
+    type Viewer: ReadOnlyWorldQuery;
+
+    /// Whether the client `viewer` sees this entity, or `None` to leave it to
+    /// distance and line of sight. Spectators watching an entity are judged as
+    /// that entity.
+    fn verdict(&self, viewer: NetworkId, data: QueryItem<'_, Self::Viewer>) -> Option<Verdict>;
+}
+
+pub(crate) trait VisibilityRuleAppExt {
+    /// Judges visibility by `R` wherever it is on an entity.
+    fn add_visibility_rule<R: VisibilityRule>(&mut self) -> &mut Self;
+}
+
+impl VisibilityRuleAppExt for App {
+    fn add_visibility_rule<R: VisibilityRule>(&mut self) -> &mut Self {
+        self.add_system(
+            apply_visibility_rule::<R>
+                .before("visibility")
+                .label("visibility_rules"),
+        )
+    }
+}
+
+// components
+/// Hides an entity from every client but its own, like a game master.
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub(crate) struct Hidden;
+
+impl VisibilityRule for Hidden {
+    type Viewer = ();
+
+    fn verdict(&self, _: NetworkId, _: ()) -> Option<Verdict> {
+        Some(Verdict::Hide)
+    }
+}
+
+/// Hides an entity from every client not in the list, like party members.
+#[derive(Component, Clone, Debug, Default)]
+pub(crate) struct VisibleTo(pub(crate) HashSet<NetworkId>);
+
+impl VisibilityRule for VisibleTo {
+    type Viewer = ();
+
+    fn verdict(&self, viewer: NetworkId, _: ()) -> Option<Verdict> {
+        (!self.0.contains(&viewer)).then_some(Verdict::Hide)
+    }
+}
+
+/// Hides an entity from viewers with less [`Detection`].
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub(crate) struct Stealth(pub(crate) u32);
+
+/// How stealthy the entities a viewer can still see may be.
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub(crate) struct Detection(pub(crate) u32);
+
+impl VisibilityRule for Stealth {
+    type Viewer = Option<&'static Detection>;
+
+    fn verdict(&self, _: NetworkId, detection: Option<&Detection>) -> Option<Verdict> {
+        let detection = detection.copied().unwrap_or_default();
+        (self.0 > detection.0).then_some(Verdict::Hide)
+    }
+}
+
+// systems
+fn apply_visibility_rule<R: VisibilityRule>(
+    targets: Query<(Entity, &R)>,
+    viewers: Query<(&NetworkId, R::Viewer)>,
+    mut clients: Query<(Entity, Option<&Spectator>, &mut VisibleEntities)>,
+) {
+    for (target, rule) in targets.iter() {
+        for (client, spectator, mut visible) in clients.iter_mut() {
+            let viewer = spectator.and_then(Spectator::watched).unwrap_or(client);
+            if viewer == target {
+                continue;
+            }
+            let Ok((&id, data)) = viewers.get(viewer) else {
+                continue;
+            };
+            if let Some(verdict) = rule.verdict(id, data) {
+                visible.judge(target, verdict);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Detection, Hidden, Stealth, VisibleTo};
+    use crate::{
+        id::{NetworkId, NetworkToWorld},
+        network::{
+            harness::Harness,
+            plugin::{Client, Server},
+        },
+    };
+
+    fn sees(harness: &Harness, client: usize, id: NetworkId) -> bool {
+        harness.clients[client]
+            .world
+            .resource::<NetworkToWorld<Client>>()
+            .contains_key(&id)
+    }
+
+    #[test]
+    fn rules_hide_and_reveal_entities() {
+        let mut harness = Harness::new(3);
+        harness.wait_for_players();
+        let ids: Vec<_> = (0..3).map(|i| harness.client_id(i).unwrap()).collect();
+        harness.step_until("everyone sees each other", |harness| {
+            (0..3).all(|i| ids.iter().filter(|&&id| sees(harness, i, id)).count() == 3)
+        });
+        let entity = harness.server.world.resource::<NetworkToWorld<Server>>()[&ids[0]];
+
+        harness.server.world.entity_mut(entity).insert(Hidden);
+        harness.step_until("nobody else sees the hidden player", |harness| {
+            !sees(harness, 1, ids[0]) && !sees(harness, 2, ids[0])
+        });
+        assert!(sees(&harness, 0, ids[0]));
+
+        harness.server.world.entity_mut(entity).remove::<Hidden>();
+        let party = VisibleTo([ids[1]].into_iter().collect());
+        harness.server.world.entity_mut(entity).insert(party);
+        harness.step_until("only the party sees the player", |harness| {
+            sees(harness, 1, ids[0]) && !sees(harness, 2, ids[0])
+        });
+
+        harness
+            .server
+            .world
+            .entity_mut(entity)
+            .remove::<VisibleTo>();
+        harness.server.world.entity_mut(entity).insert(Stealth(3));
+        let scout = harness.server.world.resource::<NetworkToWorld<Server>>()[&ids[2]];
+        harness.server.world.entity_mut(scout).insert(Detection(3));
+        harness.step_until("only the scout sees through the stealth", |harness| {
+            !sees(harness, 1, ids[0]) && sees(harness, 2, ids[0])
+        });
+    }
+}
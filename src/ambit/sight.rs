@@ -0,0 +1,118 @@
+use bevy::{
+    prelude::{Component, Resource},
+    utils::{HashMap, HashSet},
+};
+
+use crate::path::plugin::Position;
+
+// resources
+/// Tiles that nothing can be seen through.
+#[derive(Resource, Default, Debug)]
+pub(crate) struct Obstacles {
+    tiles: HashSet<Position>,
+    /// Changes whenever a tile does, so that cached sight lines go stale.
+    revision: u64,
+}
+
+impl Obstacles {
+    pub(crate) fn blocks(&self, position: Position) -> bool {
+        self.tiles.contains(&position)
+    }
+
+    /// Makes `position` block sight, returning whether it did not already.
+    pub(crate) fn insert(&mut self, position: Position) -> bool {
+        let inserted = self.tiles.insert(position);
+        self.revision += u64::from(inserted);
+        inserted
+    }
+
+    pub(crate) fn remove(&mut self, position: Position) -> bool {
+        let removed = self.tiles.remove(&position);
+        self.revision += u64::from(removed);
+        removed
+    }
+
+    /// Whether nothing blocks the straight line from `from` to `to`. The ends
+    /// themselves never block, so entities standing in a doorway are seen.
+    pub(crate) fn clear_between(&self, from: Position, to: Position) -> bool {
+        !between(from, to).any(|position| self.blocks(position))
+    }
+}
+
+// components
+/// Makes an entity see only what it has a line of sight to, on top of what is
+/// in its view range.
+#[derive(Component, Default, Debug)]
+pub(crate) struct LineOfSight {
+    origin: Option<Position>,
+    revision: u64,
+    /// Whether each target looked at from `origin` was in sight.
+    cache: HashMap<Position, bool>,
+}
+
+impl LineOfSight {
+    /// Whether `target` is in sight from `origin`, remembered until either the
+    /// origin or the obstacles change.
+    pub(crate) fn sees(
+        &mut self,
+        obstacles: &Obstacles,
+        origin: Position,
+        target: Position,
+    ) -> bool {
+        if self.origin != Some(origin) || self.revision != obstacles.revision {
+            self.origin = Some(origin);
+            self.revision = obstacles.revision;
+            self.cache.clear();
+        }
+
+        *self
+            .cache
+            .entry(target)
+            .or_insert_with(|| obstacles.clear_between(origin, target))
+    }
+}
+
+// util
+/// The tiles strictly between `from` and `to` on a straight line, one for each
+/// step along the longer axis.
+fn between(from: Position, to: Position) -> impl Iterator<Item = Position> {
+    let dx = i64::from(to.x) - i64::from(from.x);
+    let dy = i64::from(to.y) - i64::from(from.y);
+    let steps = dx.abs().max(dy.abs());
+    // rounds to the nearest tile, halves towards positive infinity
+    let offset = move |delta: i64, step: i64| (2 * delta * step + steps).div_euclid(2 * steps);
+
+    (1..steps).map(move |step| Position {
+        x: (i64::from(from.x) + offset(dx, step)) as i32,
+        y: (i64::from(from.y) + offset(dy, step)) as i32,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn obstacles_block_sight_until_removed() {
+        let mut obstacles = Obstacles::default();
+        let mut sight = LineOfSight::default();
+        let origin = Position { x: 0, y: 0 };
+        let target = Position { x: 4, y: 2 };
+
+        assert_eq!(
+            between(origin, target).collect::<Vec<_>>(),
+            [(1, 1), (2, 1), (3, 2)].map(|(x, y)| Position { x, y })
+        );
+        assert!(sight.sees(&obstacles, origin, target));
+
+        obstacles.insert(Position { x: 2, y: 1 });
+        assert!(!sight.sees(&obstacles, origin, target));
+        // the obstacle is beside, not on, the straight line
+        assert!(sight.sees(&obstacles, origin, Position { x: 4, y: 0 }));
+        // standing on an obstacle does not hide an entity
+        assert!(sight.sees(&obstacles, origin, Position { x: 2, y: 1 }));
+
+        obstacles.remove(Position { x: 2, y: 1 });
+        assert!(sight.sees(&obstacles, origin, target));
+    }
+}
@@ -0,0 +1,94 @@
+use bevy::prelude::Component;
+
+use crate::path::plugin::Position;
+
+// components
+/// How far an entity sees. Entities without one see as far as the default.
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ViewRange {
+    /// Entities closer than this become visible.
+    pub(crate) enter: u32,
+    /// How much further visible entities may go before they are lost from
+    /// view, so that entities moving along the edge do not flicker.
+    pub(crate) hysteresis: u32,
+    pub(crate) metric: DistanceMetric,
+}
+
+impl Default for ViewRange {
+    fn default() -> Self {
+        Self {
+            enter: 10,
+            hysteresis: 2,
+            metric: DistanceMetric::Taxicab,
+        }
+    }
+}
+
+impl ViewRange {
+    /// Visible entities at least this far away stop being visible.
+    pub(crate) fn leave(&self) -> u32 {
+        self.enter.saturating_add(self.hysteresis)
+    }
+
+    /// Whether an entity at `other` comes into view from `position`.
+    pub(crate) fn enters(&self, position: Position, other: Position) -> bool {
+        self.metric.closer_than(position, other, self.enter)
+    }
+
+    /// Whether an entity at `other` is still in view from `position`.
+    pub(crate) fn keeps(&self, position: Position, other: Position) -> bool {
+        self.metric.closer_than(position, other, self.leave())
+    }
+}
+
+// util
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum DistanceMetric {
+    /// Steps along the axes, seeing in a diamond.
+    #[default]
+    Taxicab,
+    /// Steps including diagonals, seeing in a square.
+    Chebyshev,
+    /// Straight lines, seeing in a circle.
+    Euclidean,
+}
+
+impl DistanceMetric {
+    /// Whether `a` and `b` are less than `distance` apart.
+    pub(crate) fn closer_than(self, a: Position, b: Position, distance: u32) -> bool {
+        let (dx, dy) = (u64::from(a.x.abs_diff(b.x)), u64::from(a.y.abs_diff(b.y)));
+        let distance = u64::from(distance);
+
+        match self {
+            Self::Taxicab => dx + dy < distance,
+            Self::Chebyshev => dx.max(dy) < distance,
+            Self::Euclidean => dx * dx + dy * dy < distance * distance,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_see_in_their_shapes() {
+        let origin = Position { x: 0, y: 0 };
+        let diagonal = Position { x: 3, y: 3 };
+        let straight = Position { x: 4, y: 0 };
+
+        assert!(!DistanceMetric::Taxicab.closer_than(origin, diagonal, 5));
+        assert!(DistanceMetric::Chebyshev.closer_than(origin, diagonal, 5));
+        assert!(DistanceMetric::Euclidean.closer_than(origin, diagonal, 5));
+        assert!(!DistanceMetric::Euclidean.closer_than(origin, Position { x: 3, y: 4 }, 5));
+
+        for metric in [
+            DistanceMetric::Taxicab,
+            DistanceMetric::Chebyshev,
+            DistanceMetric::Euclidean,
+        ] {
+            assert!(metric.closer_than(origin, straight, 5));
+            assert!(!metric.closer_than(origin, straight, 4));
+        }
+    }
+}
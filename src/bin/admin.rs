@@ -0,0 +1,79 @@
+use std::{
+    env,
+    io::{self, BufRead, Write},
+    process,
+};
+
+use animus_lib::network::admin::{AdminClient, AdminEndpoint};
+
+const USAGE: &str = "usage: admin [--connect ADDR | --connect PATH] [COMMAND...]
+
+Runs COMMAND on the admin console of a local server, or reads commands from
+standard input when there is none. Try the help command.";
+
+fn parse_args() -> Result<(AdminEndpoint, Option<String>), String> {
+    let mut endpoint = AdminEndpoint::default();
+
+    let mut args = env::args().skip(1).peekable();
+    while let Some(flag) = args.next_if(|arg| arg.starts_with("--")) {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {flag}"))?;
+
+        match flag.as_str() {
+            "--connect" => {
+                endpoint = value
+                    .parse()
+                    .map_err(|_| format!("invalid value for {flag}: {value}"))?
+            }
+            _ => return Err(format!("unknown flag {flag}")),
+        }
+    }
+
+    let command = args.collect::<Vec<_>>().join(" ");
+    Ok((endpoint, (!command.is_empty()).then_some(command)))
+}
+
+fn run(client: &mut AdminClient, command: &str) -> io::Result<bool> {
+    let reply = client.request(command)?;
+    for line in &reply {
+        println!("{line}");
+    }
+
+    Ok(!reply.iter().any(|line| line.starts_with("error:")))
+}
+
+fn main() -> io::Result<()> {
+    let (endpoint, command) = parse_args().unwrap_or_else(|e| {
+        eprintln!("{e}\n\n{USAGE}");
+        process::exit(2);
+    });
+
+    let mut client = AdminClient::connect(&endpoint).unwrap_or_else(|e| {
+        eprintln!("failed to connect to {endpoint}: {e}");
+        process::exit(1);
+    });
+
+    if let Some(command) = command {
+        if !run(&mut client, &command)? {
+            process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let stdin = io::stdin();
+    loop {
+        print!("> ");
+        io::stdout().flush()?;
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        run(&mut client, &line)?;
+    }
+}
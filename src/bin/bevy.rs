@@ -181,7 +181,7 @@ fn main() {
     let mut app = App::new();
     app.add_plugins(DefaultPlugins)
         .add_plugin(TickPlugin)
-        .add_plugin(NetworkPlugin)
+        .add_plugin(NetworkPlugin::default())
         .add_plugins(PathPlugins)
         .add_plugin(AmbitPlugin)
         .add_startup_system(setup);
@@ -0,0 +1,119 @@
+use std::{
+    env,
+    net::SocketAddr,
+    process, thread,
+    time::{Duration, Instant},
+};
+
+use animus_lib::{
+    ambit::plugin::AmbitPlugin,
+    chat::plugin::ChatPlugin,
+    client::bot::{BotPlugin, BotSettings, BotStats, BotSummary},
+    network::plugin::NetworkPlugin,
+    path::plugin::PathPlugins,
+    time::tick::TickPlugin,
+};
+use bevy::prelude::*;
+
+const USAGE: &str = "usage: bots [--bots N] [--addr ADDR] [--seconds N] [--path-interval MS] \
+                     [--chat-interval MS]
+
+An interval of 0 disables that kind of request.";
+
+const FRAME: Duration = Duration::from_millis(16);
+
+struct Args {
+    bots: usize,
+    addr: SocketAddr,
+    duration: Duration,
+    settings: BotSettings,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        bots: 10,
+        addr: NetworkPlugin::default().addr,
+        duration: Duration::from_secs(30),
+        settings: BotSettings::default(),
+    };
+
+    let mut iter = env::args().skip(1);
+    while let Some(flag) = iter.next() {
+        let value = iter
+            .next()
+            .ok_or_else(|| format!("missing value for {flag}"))?;
+        let invalid = || format!("invalid value for {flag}: {value}");
+
+        match flag.as_str() {
+            "--bots" => args.bots = value.parse().map_err(|_| invalid())?,
+            "--addr" => args.addr = value.parse().map_err(|_| invalid())?,
+            "--seconds" => {
+                args.duration = Duration::from_secs(value.parse().map_err(|_| invalid())?)
+            }
+            "--path-interval" => {
+                args.settings.path_interval = interval(value.parse().map_err(|_| invalid())?)
+            }
+            "--chat-interval" => {
+                args.settings.chat_interval = interval(value.parse().map_err(|_| invalid())?)
+            }
+            _ => return Err(format!("unknown flag {flag}")),
+        }
+    }
+
+    Ok(args)
+}
+
+fn interval(millis: u64) -> Option<Duration> {
+    (millis > 0).then(|| Duration::from_millis(millis))
+}
+
+fn bot_app(args: &Args) -> App {
+    let mut app = App::new();
+    app.add_plugins(MinimalPlugins);
+    app.add_plugin(NetworkPlugin {
+        addr: args.addr,
+        ..default()
+    });
+    app.add_plugin(TickPlugin);
+    app.add_plugins(PathPlugins);
+    app.add_plugin(AmbitPlugin);
+    app.add_plugin(ChatPlugin);
+    app.add_plugin(BotPlugin {
+        settings: args.settings.clone(),
+    });
+    app
+}
+
+fn main() {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            process::exit(2);
+        }
+    };
+
+    // a single subscriber for every bot, LogPlugin can only be added once per process
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::WARN)
+        .init();
+
+    let mut bots: Vec<App> = (0..args.bots).map(|_| bot_app(&args)).collect();
+
+    let started = Instant::now();
+    while started.elapsed() < args.duration {
+        let frame = Instant::now();
+        for bot in &mut bots {
+            bot.update();
+        }
+        if let Some(rest) = FRAME.checked_sub(frame.elapsed()) {
+            thread::sleep(rest);
+        }
+    }
+
+    let mut summary = BotSummary::default();
+    for bot in &bots {
+        summary.add(bot.world.resource::<BotStats>());
+    }
+    println!("{summary}");
+}
@@ -1,16 +1,62 @@
+use std::{env, process};
+
 use animus_lib::{
-    ambit::plugin::AmbitPlugin, client::camera::ClientPlugin, network::plugin::NetworkPlugin,
-    path::plugin::PathPlugins, time::tick::TickPlugin,
+    ambit::plugin::AmbitPlugin, chat::plugin::ChatPlugin, client::camera::ClientPlugin,
+    id::NetworkId, network::guard::Account, network::plugin::NetworkPlugin,
+    network::spectate::Viewpoint, path::plugin::PathPlugins, time::tick::TickPlugin,
 };
 use bevy::prelude::*;
 
+const USAGE: &str = "usage: client [--addr ADDR] [--account NAME] [--spectate ID | --spectate X,Y]
+
+--spectate joins as a spectator, watching the entity ID or the region around X,Y.";
+
+fn parse_args() -> Result<NetworkPlugin, String> {
+    let mut network = NetworkPlugin::default();
+
+    let mut iter = env::args().skip(1);
+    while let Some(flag) = iter.next() {
+        let value = iter
+            .next()
+            .ok_or_else(|| format!("missing value for {flag}"))?;
+        let invalid = || format!("invalid value for {flag}: {value}");
+
+        match flag.as_str() {
+            "--addr" => network.addr = value.parse().map_err(|_| invalid())?,
+            "--account" => network.account = Some(Account(value)),
+            "--spectate" => network.spectate = Some(viewpoint(&value).ok_or_else(invalid)?),
+            _ => return Err(format!("unknown flag {flag}")),
+        }
+    }
+
+    Ok(network)
+}
+
+fn viewpoint(value: &str) -> Option<Viewpoint> {
+    match value.split_once(',') {
+        Some((x, y)) => Some(Viewpoint::Region {
+            x: x.trim().parse().ok()?,
+            y: y.trim().parse().ok()?,
+        }),
+        None => Some(Viewpoint::Entity(NetworkId::from(
+            value.parse::<u64>().ok()?,
+        ))),
+    }
+}
+
 fn main() {
+    let network = parse_args().unwrap_or_else(|e| {
+        eprintln!("{e}\n\n{USAGE}");
+        process::exit(2);
+    });
+
     let mut app = App::new();
     app.add_plugins(DefaultPlugins);
-    app.add_plugin(NetworkPlugin);
+    app.add_plugin(network);
     app.add_plugin(TickPlugin);
     app.add_plugins(PathPlugins);
     app.add_plugin(AmbitPlugin);
+    app.add_plugin(ChatPlugin);
     app.add_plugin(ClientPlugin);
     app.add_startup_system(setup);
     app.run();
@@ -1,17 +1,124 @@
+use std::{env, fs, net::SocketAddr, path::PathBuf, process};
+
 use animus_lib::{
-    ambit::plugin::AmbitPlugin, network::plugin::NetworkPlugin, path::plugin::PathPlugins,
+    ambit::plugin::AmbitPlugin,
+    chat::plugin::ChatPlugin,
+    network::{
+        admin::{AdminEndpoint, AdminPlugin},
+        peer::{DialPeer, PeerIdentity, PeerPlugin},
+        plugin::NetworkPlugin,
+    },
+    path::plugin::PathPlugins,
     time::tick::TickPlugin,
 };
 use bevy::{log::LogPlugin, prelude::*};
 
+const USAGE: &str = "usage: server [--addr ADDR] [--admin ADDR | --admin PATH]
+              [--peer-listen ADDR --cluster-root PATH --peer-cert PATH
+               --peer-key PATH [--peer ADDR]...]
+
+--admin sets where the admin console listens, a loopback address or a Unix
+socket path.
+
+--peer-listen links this server to the other servers of its cluster, which
+dial it there. Its certificate and key, and the cluster root that signed it,
+are read from DER files. Every --peer is dialed on startup. Peers transfer
+players to --addr, so it must be reachable by clients.";
+
+#[derive(Default)]
+struct PeerArgs {
+    listen: Option<SocketAddr>,
+    root: Option<PathBuf>,
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+    dial: Vec<SocketAddr>,
+}
+
+impl PeerArgs {
+    fn into_plugin(self, client_addr: SocketAddr) -> Result<Option<PeerPlugin>, String> {
+        let Some(listen) = self.listen else {
+            let linking = [&self.root, &self.cert, &self.key]
+                .iter()
+                .any(|path| path.is_some());
+            if linking || !self.dial.is_empty() {
+                return Err("peer flags need --peer-listen".to_string());
+            }
+            return Ok(None);
+        };
+
+        let read = |flag: &str, path: Option<PathBuf>| {
+            let path = path.ok_or_else(|| format!("--peer-listen needs {flag}"))?;
+            fs::read(&path).map_err(|e| format!("failed to read {}: {e}", path.display()))
+        };
+        let identity = PeerIdentity::new(
+            read("--cluster-root", self.root)?,
+            vec![read("--peer-cert", self.cert)?],
+            read("--peer-key", self.key)?,
+        );
+
+        Ok(Some(PeerPlugin {
+            listen,
+            client_addr,
+            identity,
+        }))
+    }
+}
+
+fn parse_args() -> Result<(NetworkPlugin, AdminPlugin, PeerArgs), String> {
+    let mut network = NetworkPlugin::default();
+    let mut admin = AdminPlugin {
+        endpoint: AdminEndpoint::default(),
+    };
+    let mut peer = PeerArgs::default();
+
+    let mut iter = env::args().skip(1);
+    while let Some(flag) = iter.next() {
+        let value = iter
+            .next()
+            .ok_or_else(|| format!("missing value for {flag}"))?;
+        let invalid = || format!("invalid value for {flag}: {value}");
+
+        match flag.as_str() {
+            "--addr" => network.addr = value.parse().map_err(|_| invalid())?,
+            "--admin" => admin.endpoint = value.parse().map_err(|_| invalid())?,
+            "--peer-listen" => peer.listen = Some(value.parse().map_err(|_| invalid())?),
+            "--peer" => peer.dial.push(value.parse().map_err(|_| invalid())?),
+            "--cluster-root" => peer.root = Some(value.into()),
+            "--peer-cert" => peer.cert = Some(value.into()),
+            "--peer-key" => peer.key = Some(value.into()),
+            _ => return Err(format!("unknown flag {flag}")),
+        }
+    }
+
+    Ok((network, admin, peer))
+}
+
 fn main() {
+    let (network, admin, peer) = parse_args().unwrap_or_else(|e| {
+        eprintln!("{e}\n\n{USAGE}");
+        process::exit(2);
+    });
+    let dial = peer.dial.clone();
+    let peer = peer.into_plugin(network.addr).unwrap_or_else(|e| {
+        eprintln!("{e}\n\n{USAGE}");
+        process::exit(2);
+    });
+
     let mut app = App::new();
     app.add_plugins(MinimalPlugins);
     app.add_plugin(LogPlugin::default());
-    app.add_plugin(NetworkPlugin);
+    app.add_plugin(network);
+    app.add_plugin(admin);
+    if let Some(peer) = peer {
+        app.add_plugin(peer);
+        for addr in dial {
+            app.world.send_event(DialPeer { addr });
+        }
+    }
     app.add_plugin(TickPlugin);
     app.add_plugins(PathPlugins);
     app.add_plugin(AmbitPlugin);
+    app.add_plugin(ChatPlugin);
 
     app.run();
 }
@@ -1,11 +1,11 @@
 pub(crate) struct BroadcastChannel<T> {
-    pub notify: async_std::channel::Sender<T>,
-    pub notified: async_std::channel::Receiver<T>,
+    pub notify: async_channel::Sender<T>,
+    pub notified: async_channel::Receiver<T>,
 }
 
 impl<T: Clone> BroadcastChannel<T> {
     pub fn channel() -> Self {
-        let (notify, notified) = async_std::channel::bounded(1);
+        let (notify, notified) = async_channel::bounded(1);
         Self { notify, notified }
     }
 }
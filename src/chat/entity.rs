@@ -1,9 +1,10 @@
+use bevy::prelude::Resource;
 use speedy::{Readable, Writable};
 
 use super::packet::MessageReceived;
 use crate::id::NetworkId;
 
-#[derive(Default)]
+#[derive(Resource, Default)]
 pub(crate) struct Chat {
     pub(crate) messages: Vec<Message>,
 }
@@ -25,6 +26,16 @@ pub(crate) struct Message {
     kind: MessageKind,
 }
 
+impl Message {
+    pub(crate) fn sender(&self) -> NetworkId {
+        self.sender
+    }
+
+    pub(crate) fn contents(&self) -> &str {
+        &self.contents
+    }
+}
+
 impl From<MessageReceived> for Message {
     fn from(value: MessageReceived) -> Self {
         Self {
@@ -38,4 +49,6 @@ impl From<MessageReceived> for Message {
 #[derive(Readable, Writable, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 pub(crate) enum MessageKind {
     Shout,
+    /// Sent by the server itself, from [`NetworkId::SERVER`].
+    System,
 }
@@ -1,3 +1,3 @@
 pub(crate) mod entity;
 pub(crate) mod packet;
-pub(crate) mod system;
+pub mod plugin;
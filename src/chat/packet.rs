@@ -16,6 +16,12 @@ pub(crate) struct MessageReceived {
     pub(crate) contents: String,
 }
 
+/// A message shouted on another server, relayed over a peer link.
+#[derive(Readable, Writable, Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub(crate) struct ForwardMessage {
+    pub(crate) message: MessageReceived,
+}
+
 impl From<PacketWithConnId<SendMessage>> for MessageReceived {
     fn from(value: PacketWithConnId<SendMessage>) -> Self {
         Self {
@@ -0,0 +1,119 @@
+use bevy::prelude::{App, Plugin, Query, Res, ResMut, With};
+use tracing::error;
+
+use super::{
+    entity::Chat,
+    packet::{ForwardMessage, MessageReceived, SendMessage},
+};
+use crate::{
+    ambit::plugin::Player,
+    network::{
+        mediator::PacketWithConnId,
+        plugin::{Client, Network, Packets, Peer},
+    },
+};
+
+// plugins
+pub struct ChatPlugin;
+
+pub(crate) struct ServerChatPlugin;
+
+pub(crate) struct ClientChatPlugin;
+
+impl Plugin for ChatPlugin {
+    fn build(&self, app: &mut App) {
+        #[cfg(feature = "server")]
+        app.add_plugin(ServerChatPlugin);
+
+        #[cfg(feature = "client")]
+        app.add_plugin(ClientChatPlugin);
+    }
+}
+
+impl Plugin for ServerChatPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(broadcast_shouts);
+        app.add_system(relay_forwarded_messages);
+    }
+}
+
+impl Plugin for ClientChatPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Chat>();
+        app.add_system(add_messages_to_chat);
+    }
+}
+
+// systems
+fn broadcast_shouts(
+    packets: Res<Packets<PacketWithConnId<SendMessage>>>,
+    clients: Query<&Network<Client>, With<Player>>,
+    peers: Query<&Network<Peer>>,
+) {
+    for packet in packets.iter() {
+        let message = MessageReceived::from(packet);
+
+        if let Err(e) = Network::send_all(
+            peers.iter(),
+            ForwardMessage {
+                message: message.clone(),
+            },
+        ) {
+            error!("Failed to forward message to peers: {}", e);
+        }
+
+        if let Err(e) = Network::send_all(clients.iter(), message) {
+            error!("Failed to broadcast message: {}", e);
+        }
+    }
+}
+
+/// Forwarded messages are only registered when the server has peer links.
+fn relay_forwarded_messages(
+    packets: Option<Res<Packets<PacketWithConnId<ForwardMessage>>>>,
+    clients: Query<&Network<Client>, With<Player>>,
+) {
+    let Some(packets) = packets else {
+        return;
+    };
+
+    for packet in packets.iter() {
+        if let Err(e) = Network::send_all(clients.iter(), packet.packet.message) {
+            error!("Failed to relay forwarded message: {}", e);
+        }
+    }
+}
+
+fn add_messages_to_chat(packets: Res<Packets<MessageReceived>>, mut chat: ResMut<Chat>) {
+    for message in packets.iter() {
+        chat.push(message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{chat::entity::MessageKind, network::harness::Harness};
+
+    #[test]
+    fn shouts_reach_every_client() {
+        let mut harness = Harness::new(2);
+        harness.wait_for_players();
+        let sender = harness.client_id(0).unwrap();
+
+        harness.send_from_client(
+            0,
+            SendMessage {
+                kind: MessageKind::Shout,
+                contents: "message from 1".to_owned(),
+            },
+        );
+
+        harness.step_until("both clients received the shout", |harness| {
+            harness.clients.iter().all(|client| {
+                let chat = client.world.resource::<Chat>();
+                chat.messages.len() == 1 && chat.messages[0].sender() == sender
+            })
+        });
+    }
+}
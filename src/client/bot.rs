@@ -0,0 +1,350 @@
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    time::{Duration, Instant},
+};
+
+use bevy::{
+    prelude::{
+        Added, App, EventReader, Input, Local, MouseButton, Plugin, Query, Res, ResMut, Resource,
+        With, Without,
+    },
+    utils::HashMap,
+};
+use tracing::error;
+
+use super::camera::MouseWorldCoordinates;
+use crate::{
+    chat::{
+        entity::{Chat, MessageKind},
+        packet::SendMessage,
+    },
+    id::NetworkId,
+    network::{
+        event::{Connecting, Disconnected},
+        plugin::{Me, Network, Server},
+        transfer::Transferring,
+    },
+    path::{
+        packet::PathTargetRequest,
+        plugin::{Position, Target},
+    },
+};
+
+// plugins
+/// Drives a headless client with random path requests and chat, recording how
+/// long the server takes to respond.
+pub struct BotPlugin {
+    pub settings: BotSettings,
+}
+
+impl Plugin for BotPlugin {
+    fn build(&self, app: &mut App) {
+        // the client path plugin reads mouse input, which a bot never has
+        app.init_resource::<MouseWorldCoordinates>();
+        app.init_resource::<Input<MouseButton>>();
+        app.insert_resource(self.settings.clone());
+        app.init_resource::<BotStats>();
+        app.add_system(record_connect_times);
+        app.add_system(record_disconnects);
+        app.add_system(request_random_paths);
+        app.add_system(send_chat);
+        app.add_system(record_path_responses);
+        app.add_system(record_chat_responses);
+    }
+}
+
+// resources
+#[derive(Resource, Clone, Debug)]
+pub struct BotSettings {
+    /// Time between path requests, `None` disables them.
+    pub path_interval: Option<Duration>,
+    /// Time between chat messages, `None` disables them.
+    pub chat_interval: Option<Duration>,
+    /// Path targets are picked within this many tiles of the origin.
+    pub range: i32,
+}
+
+impl Default for BotSettings {
+    fn default() -> Self {
+        Self {
+            path_interval: Some(Duration::from_secs(1)),
+            chat_interval: Some(Duration::from_secs(5)),
+            range: 20,
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct BotStats {
+    connecting_since: Option<Instant>,
+    connect_time: Option<Duration>,
+    path_rtts: Vec<Duration>,
+    chat_rtts: Vec<Duration>,
+    disconnects: usize,
+    pending_paths: Vec<(Position, Instant)>,
+    pending_chat: HashMap<String, Instant>,
+}
+
+/// Aggregated [`BotStats`] of every bot in a run.
+#[derive(Default)]
+pub struct BotSummary {
+    bots: usize,
+    connect_times: Vec<Duration>,
+    path_rtts: Vec<Duration>,
+    chat_rtts: Vec<Duration>,
+    disconnects: usize,
+    unanswered: usize,
+}
+
+impl BotSummary {
+    pub fn add(&mut self, stats: &BotStats) {
+        self.bots += 1;
+        self.connect_times.extend(stats.connect_time);
+        self.path_rtts.extend(&stats.path_rtts);
+        self.chat_rtts.extend(&stats.chat_rtts);
+        self.disconnects += stats.disconnects;
+        self.unanswered += stats.pending_paths.len() + stats.pending_chat.len();
+    }
+}
+
+impl fmt::Display for BotSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "bots: {}, connected: {}, disconnects: {}, unanswered requests: {}",
+            self.bots,
+            self.connect_times.len(),
+            self.disconnects,
+            self.unanswered
+        )?;
+        write_durations(f, "connect", &self.connect_times)?;
+        write_durations(f, "path rtt", &self.path_rtts)?;
+        write_durations(f, "chat rtt", &self.chat_rtts)
+    }
+}
+
+fn write_durations(f: &mut fmt::Formatter<'_>, name: &str, durations: &[Duration]) -> fmt::Result {
+    let mut sorted = durations.to_vec();
+    sorted.sort();
+
+    let (Some(p50), Some(p90), Some(p99), Some(max)) = (
+        percentile(&sorted, 50),
+        percentile(&sorted, 90),
+        percentile(&sorted, 99),
+        sorted.last(),
+    ) else {
+        return writeln!(f, "{name}: no samples");
+    };
+
+    writeln!(
+        f,
+        "{name}: n={} p50={:?} p90={:?} p99={:?} max={:?}",
+        sorted.len(),
+        p50,
+        p90,
+        p99,
+        max
+    )
+}
+
+/// Nearest-rank percentile of an already sorted slice.
+fn percentile(sorted: &[Duration], percent: usize) -> Option<Duration> {
+    if sorted.is_empty() {
+        return None;
+    }
+
+    let rank = (percent * sorted.len()).div_ceil(100).max(1);
+    sorted.get(rank - 1).copied()
+}
+
+// util
+struct Random(u64);
+
+impl Default for Random {
+    fn default() -> Self {
+        // xorshift must not be seeded with 0
+        Self(RandomState::new().build_hasher().finish() | 1)
+    }
+}
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn range(&mut self, range: i32) -> i32 {
+        let width = range as u64 * 2 + 1;
+        (self.next() % width) as i32 - range
+    }
+}
+
+// systems
+fn record_connect_times(
+    mut stats: ResMut<BotStats>,
+    mut connecting: EventReader<Connecting>,
+    me: Query<(), Added<Me>>,
+) {
+    if connecting.iter().count() > 0 && stats.connecting_since.is_none() {
+        stats.connecting_since = Some(Instant::now());
+    }
+
+    if !me.is_empty() && stats.connect_time.is_none() {
+        stats.connect_time = stats.connecting_since.map(|since| since.elapsed());
+    }
+}
+
+fn record_disconnects(mut stats: ResMut<BotStats>, mut disconnected: EventReader<Disconnected>) {
+    stats.disconnects += disconnected.iter().count();
+}
+
+fn request_random_paths(
+    settings: Res<BotSettings>,
+    mut stats: ResMut<BotStats>,
+    server: Query<&Network<Server>, Without<Transferring>>,
+    me: Query<(), With<Me>>,
+    mut last_sent: Local<Option<Instant>>,
+    mut random: Local<Random>,
+) {
+    let Some(interval) = settings.path_interval else {
+        return;
+    };
+    let Ok(server) = server.get_single() else {
+        return;
+    };
+    if me.is_empty() || last_sent.is_some_and(|sent| sent.elapsed() < interval) {
+        return;
+    }
+
+    let target = Position {
+        x: random.range(settings.range),
+        y: random.range(settings.range),
+    };
+
+    if let Err(e) = server.send(PathTargetRequest {
+        x: target.x,
+        y: target.y,
+    }) {
+        error!("Failed to request path: {}", e);
+        return;
+    }
+
+    let now = Instant::now();
+    *last_sent = Some(now);
+    stats.pending_paths.push((target, now));
+}
+
+fn send_chat(
+    settings: Res<BotSettings>,
+    mut stats: ResMut<BotStats>,
+    server: Query<&Network<Server>, Without<Transferring>>,
+    me: Query<&NetworkId, With<Me>>,
+    mut last_sent: Local<Option<Instant>>,
+    mut sequence: Local<u64>,
+) {
+    let Some(interval) = settings.chat_interval else {
+        return;
+    };
+    let (Ok(server), Ok(id)) = (server.get_single(), me.get_single()) else {
+        return;
+    };
+    if last_sent.is_some_and(|sent| sent.elapsed() < interval) {
+        return;
+    }
+
+    *sequence += 1;
+    let contents = format!("bot {} message {}", id, *sequence);
+
+    if let Err(e) = server.send(SendMessage {
+        kind: MessageKind::Shout,
+        contents: contents.clone(),
+    }) {
+        error!("Failed to send chat: {}", e);
+        return;
+    }
+
+    let now = Instant::now();
+    *last_sent = Some(now);
+    stats.pending_chat.insert(contents, now);
+}
+
+fn record_path_responses(
+    mut stats: ResMut<BotStats>,
+    mut targets: EventReader<Target>,
+    me: Query<(), With<Me>>,
+) {
+    for target in targets.iter() {
+        if !me.contains(target.entity) {
+            continue;
+        }
+
+        let Some(index) = stats
+            .pending_paths
+            .iter()
+            .position(|(position, _)| *position == target.position)
+        else {
+            continue;
+        };
+
+        let (_, sent) = stats.pending_paths.remove(index);
+        stats.path_rtts.push(sent.elapsed());
+    }
+}
+
+fn record_chat_responses(
+    mut stats: ResMut<BotStats>,
+    chat: Res<Chat>,
+    me: Query<&NetworkId, With<Me>>,
+    mut seen: Local<usize>,
+) {
+    let Ok(&id) = me.get_single() else {
+        return;
+    };
+
+    for message in chat.messages.iter().skip(*seen) {
+        if message.sender() != id {
+            continue;
+        }
+
+        if let Some(sent) = stats.pending_chat.remove(message.contents()) {
+            stats.chat_rtts.push(sent.elapsed());
+        }
+    }
+
+    *seen = chat.messages.len();
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case(50, 5)]
+    #[case(90, 9)]
+    #[case(99, 10)]
+    #[case(100, 10)]
+    #[case(1, 1)]
+    fn nearest_rank_percentile(#[case] percent: usize, #[case] expected_ms: u64) {
+        let sorted: Vec<_> = (1..=10).map(Duration::from_millis).collect();
+
+        assert_eq!(
+            percentile(&sorted, percent),
+            Some(Duration::from_millis(expected_ms))
+        );
+    }
+
+    #[test]
+    fn random_range_is_inclusive_and_bounded() {
+        let mut random = Random::default();
+
+        assert!((0..1000)
+            .map(|_| random.range(3))
+            .all(|v| (-3..=3).contains(&v)));
+    }
+}
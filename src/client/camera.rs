@@ -3,7 +3,7 @@ use std::ops::Div;
 use bevy::{
     prelude::{
         Added, Camera, Color, Commands, Component, Entity, GlobalTransform, IntoSystemDescriptor,
-        Plugin, Query, Res, ResMut, Resource, Transform, Vec2, Vec3, With,
+        Or, Plugin, Query, Res, ResMut, Resource, Transform, Vec2, Vec3, With, Without,
     },
     render::camera::RenderTarget,
     sprite::{Sprite, SpriteBundle},
@@ -13,8 +13,12 @@ use bevy::{
 use tracing::{error, info};
 
 use crate::{
-    ambit::plugin::Player,
-    network::plugin::{Client, Network},
+    ambit::plugin::{EntityKind, Player},
+    id::NetworkToWorld,
+    network::{
+        plugin::{Client, Network},
+        spectate::{Spectating, Viewpoint},
+    },
     path::plugin::{MaybeNextPosition, Position},
     time::tick::Tick,
 };
@@ -25,8 +29,9 @@ impl Plugin for ClientPlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
         app.init_resource::<MouseWorldCoordinates>();
         app.add_system(set_cursor_world_coords);
-        app.add_system(update_transform.after("set_position"));
-        app.add_system(add_player_sprites);
+        app.add_system(update_transform.after("set_position").label("transform"));
+        app.add_system(follow_viewpoint.after("transform"));
+        app.add_system(add_entity_sprites);
         app.add_system(update_mouse_position_marker);
     }
 }
@@ -105,24 +110,68 @@ fn update_transform(
     }
 }
 
+/// Keeps a spectator's camera on what it watches.
+fn follow_viewpoint(
+    spectating: Option<Res<Spectating>>,
+    network_to_world: Res<NetworkToWorld<Client>>,
+    targets: Query<&Transform, Without<Camera>>,
+    mut cameras: Query<&mut Transform, With<Camera>>,
+) {
+    let Some(spectating) = spectating else {
+        return;
+    };
+
+    let translation = match spectating.0 {
+        Viewpoint::Entity(id) => {
+            let Some(target) = network_to_world
+                .get(&id)
+                .and_then(|&entity| targets.get(entity).ok())
+            else {
+                return;
+            };
+            target.translation
+        }
+        Viewpoint::Region { x, y } => {
+            Vec3::new(x as f32 * 30.0 + 15.0, y as f32 * 30.0 + 15.0, 0.0)
+        }
+    };
+
+    for mut camera in cameras.iter_mut() {
+        camera.translation.x = translation.x;
+        camera.translation.y = translation.y;
+    }
+}
+
 #[allow(clippy::type_complexity)]
-fn add_player_sprites(
+fn add_entity_sprites(
     mut commands: Commands,
     new_positions: Query<
-        (Entity, &Position, Option<&Network<Client>>),
-        (Added<Position>, With<Player>),
+        (
+            Entity,
+            &Position,
+            Option<&Network<Client>>,
+            Option<&EntityKind>,
+        ),
+        (Added<Position>, Or<(With<Player>, With<EntityKind>)>),
     >,
 ) {
-    for (entity, position, is_client) in new_positions.iter() {
+    for (entity, position, is_client, kind) in new_positions.iter() {
         let color = if is_client.is_some() {
             info!("spawning client");
             Color::rgb(1.0, 0.0, 0.0)
         } else {
-            Color::rgb(1.0, 1.0, 0.0)
+            match kind.copied().unwrap_or_default() {
+                EntityKind::Player => Color::rgb(1.0, 1.0, 0.0),
+                EntityKind::Npc => Color::rgb(0.0, 0.8, 0.0),
+                EntityKind::Item => Color::rgb(0.0, 0.5, 1.0),
+                EntityKind::Projectile => Color::rgb(1.0, 1.0, 1.0),
+            }
         };
 
         let custom_size = if is_client.is_some() {
             Some(Vec2::splat(35.0))
+        } else if kind == Some(&EntityKind::Projectile) {
+            Some(Vec2::splat(10.0))
         } else {
             Some(Vec2::splat(30.0))
         };
@@ -165,7 +214,7 @@ fn update_mouse_position_marker(
                 // transform: Transform::from_xyz(0.0, 0.0, -1.0),
                 ..Default::default()
             },
-            Position{ x: 0, y: 0},
+            Position { x: 0, y: 0 },
         ));
         return;
     };
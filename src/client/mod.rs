@@ -1 +1,3 @@
+#[cfg(feature = "client")]
+pub mod bot;
 pub mod camera;
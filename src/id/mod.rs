@@ -23,7 +23,13 @@ use speedy::{Readable, Writable};
     Display,
     Component,
 )]
-pub(crate) struct NetworkId(u64);
+pub struct NetworkId(u64);
+
+impl NetworkId {
+    /// Stands in for the server where a message needs a sender, never
+    /// assigned to a connection.
+    pub(crate) const SERVER: Self = Self(u64::MAX);
+}
 
 impl From<u64> for NetworkId {
     fn from(value: u64) -> Self {
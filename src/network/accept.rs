@@ -7,9 +7,18 @@ use std::{
     time::Duration,
 };
 
-use bevy::tasks::IoTaskPool;
+use bevy::tasks::{IoTaskPool, Task};
+use crossbeam_channel::Sender;
 use futures::{Future, FutureExt, StreamExt};
 use quinn::{Endpoint, ServerConfig};
+use tracing::{error, info};
+
+use super::{
+    error::{Error, Result},
+    event::ConnectionFailed,
+    guard::{ConnectionGuard, Permit},
+    runtime,
+};
 
 pub(crate) struct Accept<'a, A: ?Sized> {
     acceptor: &'a mut A,
@@ -19,7 +28,7 @@ impl<'a, A> Future for Accept<'a, A>
 where
     A: AsyncAccept + Unpin,
 {
-    type Output = std::io::Result<A::Connection>;
+    type Output = std::io::Result<Accepted<A::Connection>>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         AsyncAccept::poll_accept(Pin::new(self.acceptor), cx)
@@ -31,102 +40,170 @@ pub(crate) trait AsyncAccept {
     fn poll_accept(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
-    ) -> Poll<std::io::Result<Self::Connection>>;
+    ) -> Poll<std::io::Result<Accepted<Self::Connection>>>;
 }
 
+pub(crate) struct Accepted<C> {
+    pub(crate) connection: C,
+    /// Held by incoming connections until they close.
+    pub(crate) permit: Option<Permit>,
+}
+
+/// Accepts connections that the [`ConnectionGuard`] lets through. Handshakes
+/// run concurrently so that a slow client cannot hold up the others.
 pub(crate) struct QuicListener {
-    stream_rx: Option<futures::channel::oneshot::Receiver<std::io::Result<quinn::Connection>>>,
-    endpoint: Endpoint,
+    connections: async_channel::Receiver<Accepted<quinn::Connection>>,
+    // dropping the task stops accepting
+    _task: Task<()>,
 }
-fn generate_self_signed_cert(
-) -> Result<(rustls::Certificate, rustls::PrivateKey), Box<dyn std::error::Error>> {
+fn generate_self_signed_cert() -> Result<(rustls::Certificate, rustls::PrivateKey)> {
     let cert = rcgen::generate_simple_self_signed(vec!["test".to_string()])?;
     let key = rustls::PrivateKey(cert.serialize_private_key_der());
     Ok((rustls::Certificate(cert.serialize_der()?), key))
 }
 impl QuicListener {
-    pub(crate) fn new(addr: SocketAddr) -> Self {
-        let (cert, key) = generate_self_signed_cert().unwrap();
+    pub(crate) fn new(
+        addr: SocketAddr,
+        failures: Sender<ConnectionFailed>,
+        guard: ConnectionGuard,
+    ) -> Result<Self> {
+        let (cert, key) = generate_self_signed_cert()?;
         let server_config = rustls::ServerConfig::builder()
             .with_safe_defaults()
             .with_no_client_auth()
-            .with_single_cert(vec![cert], key)
-            .unwrap();
+            .with_single_cert(vec![cert], key)?;
 
-        Self {
-            stream_rx: None,
-            endpoint: Endpoint::server(ServerConfig::with_crypto(Arc::new(server_config)), addr)
-                .unwrap(),
-        }
+        Self::with_crypto(addr, server_config, failures, guard)
+    }
+
+    pub(crate) fn with_crypto(
+        addr: SocketAddr,
+        crypto: rustls::ServerConfig,
+        failures: Sender<ConnectionFailed>,
+        guard: ConnectionGuard,
+    ) -> Result<Self> {
+        let endpoint =
+            runtime::enter(|| Endpoint::server(ServerConfig::with_crypto(Arc::new(crypto)), addr))
+                .map_err(|source| Error::Bind { addr, source })?;
+
+        let (connections_tx, connections) = async_channel::unbounded();
+        let task = IoTaskPool::get().spawn(accept_connections(
+            endpoint,
+            guard,
+            failures,
+            connections_tx,
+        ));
+
+        Ok(Self {
+            connections,
+            _task: task,
+        })
     }
 }
+
 impl AsyncAccept for QuicListener {
     type Connection = quinn::Connection;
 
     fn poll_accept(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
-    ) -> Poll<std::io::Result<quinn::Connection>> {
-        {
-            if let Some(stream_rx) = &mut self.stream_rx {
-                // unwrap is fine here since the oneshot is known to never drop without sending
-                let stream = futures::ready!(stream_rx.poll_unpin(cx).map(|e| e.unwrap()));
-                let _ = self.stream_rx.take();
-                return Poll::Ready(stream);
-            }
+    ) -> Poll<std::io::Result<Accepted<quinn::Connection>>> {
+        match futures::ready!(self.connections.poll_next_unpin(cx)) {
+            Some(accepted) => Poll::Ready(Ok(accepted)),
+            None => Poll::Ready(Err(ErrorKind::UnexpectedEof.into())),
+        }
+    }
+}
 
-            let waker = cx.waker().clone();
-            let (tx, rx) = futures::channel::oneshot::channel();
-            self.stream_rx = Some(rx);
+async fn accept_connections(
+    endpoint: Endpoint,
+    guard: ConnectionGuard,
+    failures: Sender<ConnectionFailed>,
+    connections: async_channel::Sender<Accepted<quinn::Connection>>,
+) {
+    let pool = IoTaskPool::get();
 
-            let endpoint = self.endpoint.clone();
+    while let Some(connecting) = endpoint.accept().await {
+        let addr = connecting.remote_address();
 
-            let pool = IoTaskPool::get();
-            // TODO: Is there a better way to do this?
-            pool.spawn(async move {
-                loop {
-                    let stream = endpoint.accept().await;
-                    if stream.is_none() {
-                        async_std::task::sleep(Duration::from_secs(1)).await;
-                        continue;
-                    }
-                    let stream = stream.unwrap().await;
-                    if stream.is_err() {
-                        async_std::task::sleep(Duration::from_secs(1)).await;
-                        continue;
-                    }
-                    let _ = tx.send(stream.map_err(|err| err.into()));
-                    waker.wake();
-                    break;
+        // checked before the handshake so refused clients cost next to nothing,
+        // dropping `connecting` closes the connection
+        let permit = match guard.admit(addr.ip()) {
+            Ok(permit) => permit,
+            Err(rejection) => {
+                info!("Refused connection from {}: {}", addr, rejection);
+                let _ = failures.send(ConnectionFailed {
+                    addr,
+                    reason: rejection.to_string(),
+                });
+                continue;
+            }
+        };
+
+        let timeout = guard.handshake_timeout();
+        let failures = failures.clone();
+        let connections = connections.clone();
+        pool.spawn(async move {
+            let reason = match runtime::timeout(timeout, connecting).await {
+                Some(Ok(connection)) => {
+                    let _ = connections
+                        .send(Accepted {
+                            connection,
+                            permit: Some(permit),
+                        })
+                        .await;
+                    return;
                 }
-            })
-            .detach();
+                Some(Err(e)) => e.to_string(),
+                None => "handshake timed out".to_owned(),
+            };
 
-            Poll::Pending
-        }
+            error!("Incoming connection from {} failed: {}", addr, reason);
+            let _ = failures.send(ConnectionFailed { addr, reason });
+        })
+        .detach();
     }
 }
 
 pub(crate) struct QuicConnector {
-    connect_to: async_std::channel::Receiver<SocketAddr>,
+    connect_to: async_channel::Receiver<SocketAddr>,
     stream_rx: Option<futures::channel::oneshot::Receiver<std::io::Result<quinn::Connection>>>,
     pub(crate) endpoint: Endpoint,
+    server_name: &'static str,
+    failures: Sender<ConnectionFailed>,
 }
 
 impl QuicConnector {
-    pub(crate) fn new(connect_to: async_std::channel::Receiver<SocketAddr>) -> Self {
+    pub(crate) fn new(
+        connect_to: async_channel::Receiver<SocketAddr>,
+        failures: Sender<ConnectionFailed>,
+    ) -> Result<Self> {
         // TODO should not do this
         let crypto = rustls::ClientConfig::builder()
             .with_safe_defaults()
             .with_custom_certificate_verifier(SkipServerVerification::new())
             .with_no_client_auth();
-        let mut endpoint = Endpoint::client("0.0.0.0:0".parse().unwrap()).unwrap();
+
+        Self::with_crypto(connect_to, crypto, "test", failures)
+    }
+
+    pub(crate) fn with_crypto(
+        connect_to: async_channel::Receiver<SocketAddr>,
+        crypto: rustls::ClientConfig,
+        server_name: &'static str,
+        failures: Sender<ConnectionFailed>,
+    ) -> Result<Self> {
+        let addr = SocketAddr::from(([0, 0, 0, 0], 0));
+        let mut endpoint = runtime::enter(|| Endpoint::client(addr))
+            .map_err(|source| Error::Bind { addr, source })?;
         endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(crypto)));
-        Self {
+        Ok(Self {
             connect_to,
             stream_rx: None,
             endpoint,
-        }
+            server_name,
+            failures,
+        })
     }
 }
 
@@ -136,12 +213,18 @@ impl AsyncAccept for QuicConnector {
     fn poll_accept(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
-    ) -> Poll<std::io::Result<quinn::Connection>> {
+    ) -> Poll<std::io::Result<Accepted<quinn::Connection>>> {
         if let Some(stream_rx) = &mut self.stream_rx {
-            // unwrap is fine here since the oneshot is known to never drop without sending
-            let stream = futures::ready!(stream_rx.poll_unpin(cx).map(|e| e.unwrap()));
+            // the oneshot is dropped without sending when the attempt failed, in which case
+            // wait for the next address to connect to
+            let stream = futures::ready!(stream_rx.poll_unpin(cx));
             let _ = self.stream_rx.take();
-            return Poll::Ready(stream);
+            if let Ok(stream) = stream {
+                return Poll::Ready(stream.map(|connection| Accepted {
+                    connection,
+                    permit: None,
+                }));
+            }
         }
 
         match futures::ready!(self.connect_to.poll_next_unpin(cx)) {
@@ -151,26 +234,29 @@ impl AsyncAccept for QuicConnector {
                 self.stream_rx = Some(rx);
 
                 let endpoint = self.endpoint.clone();
+                let server_name = self.server_name;
+                let failures = self.failures.clone();
                 let pool = IoTaskPool::get();
 
-                // TODO: Is there a better way to do this?
                 pool.spawn(async move {
-                    loop {
-                        let addrx = addr;
-                        let stream = endpoint.connect(addrx, "test");
-                        if stream.is_err() {
-                            async_std::task::sleep(Duration::from_secs(1)).await;
-                            continue;
+                    let stream = match runtime::enter(|| endpoint.connect(addr, server_name)) {
+                        Ok(connecting) => connecting.await.map_err(|e| e.to_string()),
+                        Err(e) => Err(e.to_string()),
+                    };
+
+                    match stream {
+                        Ok(stream) => {
+                            let _ = tx.send(Ok(stream));
                         }
-                        let stream = stream.unwrap().await;
-                        if stream.is_err() {
-                            async_std::task::sleep(Duration::from_secs(1)).await;
-                            continue;
+                        Err(reason) => {
+                            error!("Connecting to {} failed: {}", addr, reason);
+                            // pace retries, the failure is only reported once this has elapsed
+                            runtime::sleep(Duration::from_secs(1)).await;
+                            let _ = failures.send(ConnectionFailed { addr, reason });
+                            drop(tx);
                         }
-                        let _ = tx.send(stream.map_err(|err| err.into()));
-                        waker.wake();
-                        break;
                     }
+                    waker.wake();
                 })
                 .detach();
 
@@ -206,7 +292,7 @@ impl rustls::client::ServerCertVerifier for SkipServerVerification {
         _scts: &mut dyn Iterator<Item = &[u8]>,
         _ocsp_response: &[u8],
         _now: std::time::SystemTime,
-    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+    ) -> std::result::Result<rustls::client::ServerCertVerified, rustls::Error> {
         Ok(rustls::client::ServerCertVerified::assertion())
     }
 }
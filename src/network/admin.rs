@@ -0,0 +1,595 @@
+use std::{
+    fmt,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    str::FromStr,
+    thread,
+};
+#[cfg(unix)]
+use std::{
+    fs,
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+};
+
+use bevy::{
+    app::AppExit,
+    ecs::component::ComponentId,
+    prelude::{App, Component, Entity, Or, Plugin, Resource, With, World},
+    utils::HashMap,
+};
+use crossbeam_channel::{Receiver, Sender};
+use tracing::{error, info, warn};
+
+use super::{
+    event::NetworkError,
+    plugin::{Client, ClientConnection, Network, NetworkErrors, RemoteAddr},
+    spectate::Spectator,
+    state::{ConnectionState, ConnectionStates},
+};
+use crate::{
+    ambit::plugin::Player,
+    chat::{entity::MessageKind, packet::MessageReceived},
+    id::{NetworkId, NetworkToWorld},
+    network::plugin::Server,
+    path::plugin::{teleport, Heading, MaybeNextPosition, Path, Position},
+    stat::MovementSpeed,
+};
+
+const DEFAULT_ENDPOINT: &str = "127.0.0.1:56566";
+
+const HELP: &str = "list                      connections with their address and round trip time
+kick ID [REASON]          close the connection of ID
+broadcast MESSAGE         send a system message to every client
+teleport ID X Y           move the entity ID to X,Y at once
+dump ID                   show the components of the entity ID
+shutdown                  disconnect every client and stop the server";
+
+// plugins
+/// Lets whoever is logged into the machine running the server inspect and
+/// control it. See [`AdminClient`] for the other end.
+pub struct AdminPlugin {
+    pub endpoint: AdminEndpoint,
+}
+
+impl Plugin for AdminPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(self.endpoint.clone());
+        app.add_startup_system(spawn_admin_listener);
+        app.add_system(execute_admin_commands);
+    }
+}
+
+// resources
+/// Where the admin console listens. Only the local machine may connect.
+#[derive(Resource, Clone, Debug, PartialEq, Eq)]
+pub enum AdminEndpoint {
+    /// A loopback TCP address.
+    Tcp(SocketAddr),
+    /// A Unix socket at this path, replacing whatever file is there.
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Default for AdminEndpoint {
+    fn default() -> Self {
+        Self::Tcp(DEFAULT_ENDPOINT.parse().unwrap())
+    }
+}
+
+impl FromStr for AdminEndpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse() {
+            return Ok(Self::Tcp(addr));
+        }
+
+        #[cfg(unix)]
+        return Ok(Self::Unix(PathBuf::from(s)));
+
+        #[cfg(not(unix))]
+        return Err(format!("not a socket address: {s}"));
+    }
+}
+
+impl fmt::Display for AdminEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+#[derive(Resource)]
+struct AdminConsole {
+    requests: Receiver<AdminRequest>,
+    endpoint: AdminEndpoint,
+}
+
+impl Drop for AdminConsole {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let AdminEndpoint::Unix(path) = &self.endpoint {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+// util
+/// A command line read from an admin connection, and where to send the reply.
+struct AdminRequest {
+    line: String,
+    reply: Sender<Vec<String>>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    Help,
+    List,
+    Kick { id: NetworkId, reason: String },
+    Broadcast(String),
+    Teleport { id: NetworkId, position: Position },
+    Dump(NetworkId),
+    Shutdown,
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (name, rest) = s.split_once(' ').unwrap_or((s, ""));
+        let rest = rest.trim();
+        let mut args = rest.split_whitespace();
+
+        fn arg<T: FromStr>(value: Option<&str>, what: &str) -> Result<T, String> {
+            let value = value.ok_or_else(|| format!("missing {what}"))?;
+            value
+                .parse()
+                .map_err(|_| format!("invalid {what}: {value}"))
+        }
+
+        let command = match name {
+            "help" => Self::Help,
+            "list" => Self::List,
+            "kick" => {
+                let id = NetworkId::from(arg::<u64>(args.next(), "id")?);
+                let reason = args.collect::<Vec<_>>().join(" ");
+                return Ok(Self::Kick { id, reason });
+            }
+            "broadcast" if !rest.is_empty() => return Ok(Self::Broadcast(rest.to_owned())),
+            "broadcast" => return Err("missing message".to_owned()),
+            "teleport" => Self::Teleport {
+                id: NetworkId::from(arg::<u64>(args.next(), "id")?),
+                position: Position {
+                    x: arg(args.next(), "x")?,
+                    y: arg(args.next(), "y")?,
+                },
+            },
+            "dump" => Self::Dump(NetworkId::from(arg::<u64>(args.next(), "id")?)),
+            "shutdown" => Self::Shutdown,
+            _ => return Err(format!("unknown command {name}, try help")),
+        };
+
+        match args.next() {
+            Some(extra) => Err(format!("unexpected argument {extra}")),
+            None => Ok(command),
+        }
+    }
+}
+
+impl Command {
+    fn execute(self, world: &mut World) -> Result<Vec<String>, String> {
+        match self {
+            Self::Help => Ok(HELP.lines().map(str::to_owned).collect()),
+            Self::List => Ok(list(world)),
+            Self::Kick { id, reason } => {
+                let entity = entity(world, id)?;
+                let connection = world
+                    .get::<ClientConnection>(entity)
+                    .ok_or_else(|| format!("{id} is not a client"))?;
+                connection.close(&reason);
+                world
+                    .resource::<ConnectionStates>()
+                    .set(id, ConnectionState::Disconnecting);
+                info!("Kicked {} from the admin console: {}", id, reason);
+                Ok(vec![format!("kicked {id}")])
+            }
+            Self::Broadcast(contents) => {
+                let mut clients =
+                    world.query_filtered::<&Network<Client>, Or<(With<Player>, With<Spectator>)>>();
+                let message = MessageReceived {
+                    sender: NetworkId::SERVER,
+                    kind: MessageKind::System,
+                    contents,
+                };
+                Network::send_all(clients.iter(world), message).map_err(|e| e.to_string())?;
+                Ok(vec![format!(
+                    "sent to {} clients",
+                    clients.iter(world).len()
+                )])
+            }
+            Self::Teleport { id, position } => {
+                let entity = entity(world, id)?;
+                let mut entity = world.entity_mut(entity);
+                if !entity.contains::<Position>() {
+                    return Err(format!("{id} has no position"));
+                }
+                teleport(&mut entity, position);
+                Ok(vec![format!(
+                    "teleported {id} to {},{}",
+                    position.x, position.y
+                )])
+            }
+            Self::Dump(id) => Ok(dump(world, entity(world, id)?)),
+            Self::Shutdown => {
+                info!("Shutting down from the admin console");
+                let states = world.resource::<ConnectionStates>().clone();
+                for (&id, connection) in
+                    world.query::<(&NetworkId, &ClientConnection)>().iter(world)
+                {
+                    states.set(id, ConnectionState::Disconnecting);
+                    connection.close("server shutting down");
+                }
+                world.send_event(AppExit);
+                Ok(vec!["shutting down".to_owned()])
+            }
+        }
+    }
+}
+
+fn entity(world: &World, id: NetworkId) -> Result<Entity, String> {
+    world
+        .resource::<NetworkToWorld<Server>>()
+        .get(&id)
+        .copied()
+        .ok_or_else(|| format!("no entity {id}"))
+}
+
+fn list(world: &mut World) -> Vec<String> {
+    let mut connections = world
+        .query_filtered::<(
+            &NetworkId,
+            Option<&RemoteAddr>,
+            Option<&ClientConnection>,
+            Option<&Player>,
+            Option<&Spectator>,
+        ), With<Network<Client>>>()
+        .iter(world)
+        .map(|(id, addr, connection, player, spectator)| {
+            let addr = addr.map_or("-".to_owned(), |addr| addr.0.to_string());
+            let rtt = connection.map_or("-".to_owned(), |connection| {
+                format!("{}ms", connection.rtt().as_millis())
+            });
+            let role = match (player, spectator) {
+                (Some(_), _) => "player",
+                (_, Some(_)) => "spectator",
+                _ => "queued",
+            };
+            (*id, format!("{id} {addr} rtt={rtt} {role}"))
+        })
+        .collect::<Vec<_>>();
+    connections.sort_by_key(|(id, _)| *id);
+
+    if connections.is_empty() {
+        return vec!["no connections".to_owned()];
+    }
+    connections.into_iter().map(|(_, line)| line).collect()
+}
+
+/// Names every component of `entity`, with the value of those that can be
+/// shown.
+fn dump(world: &World, entity: Entity) -> Vec<String> {
+    fn value<T: Component + fmt::Debug>(
+        world: &World,
+        entity: Entity,
+    ) -> Option<(ComponentId, String)> {
+        let id = world.components().component_id::<T>()?;
+        world
+            .get::<T>(entity)
+            .map(|component| (id, format!("{component:?}")))
+    }
+
+    let values: HashMap<_, _> = [
+        value::<NetworkId>(world, entity),
+        value::<RemoteAddr>(world, entity),
+        value::<Position>(world, entity),
+        value::<MaybeNextPosition>(world, entity),
+        value::<Path>(world, entity),
+        value::<Heading>(world, entity),
+        value::<Spectator>(world, entity),
+        value::<MovementSpeed>(world, entity),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+
+    world
+        .inspect_entity(entity)
+        .into_iter()
+        .map(|info| match values.get(&info.id()) {
+            Some(value) => format!("{}: {}", info.name(), value),
+            None => info.name().to_owned(),
+        })
+        .collect()
+}
+
+/// Reads command lines from `reader` and writes each reply to `writer`, every
+/// reply ending with an empty line.
+fn serve<R, W>(reader: R, mut writer: W, requests: Sender<AdminRequest>) -> io::Result<()>
+where
+    R: Read,
+    W: Write,
+{
+    for line in BufReader::new(reader).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let (reply, replies) = crossbeam_channel::bounded(1);
+        // the server has stopped
+        if requests.send(AdminRequest { line, reply }).is_err() {
+            break;
+        }
+        let Ok(reply) = replies.recv() else {
+            break;
+        };
+
+        for line in reply {
+            writeln!(writer, "{line}")?;
+        }
+        writeln!(writer)?;
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+fn accept<S, I>(incoming: I, requests: Sender<AdminRequest>)
+where
+    S: Read + Write + Send + 'static,
+    I: Iterator<Item = io::Result<(S, S)>>,
+{
+    for stream in incoming {
+        let (reader, writer) = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Failed to accept admin connection: {}", e);
+                continue;
+            }
+        };
+
+        let requests = requests.clone();
+        let spawned = thread::Builder::new()
+            .name("admin connection".to_owned())
+            .spawn(move || {
+                if let Err(e) = serve(reader, writer, requests) {
+                    warn!("Admin connection failed: {}", e);
+                }
+            });
+        if let Err(e) = spawned {
+            error!("Failed to serve admin connection: {}", e);
+        }
+    }
+}
+
+fn listen(endpoint: &AdminEndpoint, requests: Sender<AdminRequest>) -> io::Result<()> {
+    let spawned = match endpoint {
+        AdminEndpoint::Tcp(addr) => {
+            if !addr.ip().is_loopback() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{addr} is not a loopback address"),
+                ));
+            }
+
+            let listener = TcpListener::bind(addr)?;
+            thread::Builder::new()
+                .name("admin listener".to_owned())
+                .spawn(move || {
+                    let incoming = listener
+                        .incoming()
+                        .map(|stream| stream.and_then(|s| Ok((s.try_clone()?, s))));
+                    accept::<TcpStream, _>(incoming, requests);
+                })
+        }
+        #[cfg(unix)]
+        AdminEndpoint::Unix(path) => {
+            // a socket left behind by a server that did not shut down cleanly
+            let _ = fs::remove_file(path);
+
+            let listener = UnixListener::bind(path)?;
+            thread::Builder::new()
+                .name("admin listener".to_owned())
+                .spawn(move || {
+                    let incoming = listener
+                        .incoming()
+                        .map(|stream| stream.and_then(|s| Ok((s.try_clone()?, s))));
+                    accept::<UnixStream, _>(incoming, requests);
+                })
+        }
+    };
+
+    spawned.map(|_| ())
+}
+
+/// Runs a single command line against `world`.
+fn execute(world: &mut World, line: &str) -> Vec<String> {
+    line.parse::<Command>()
+        .and_then(|command| command.execute(world))
+        .unwrap_or_else(|e| vec![format!("error: {e}")])
+}
+
+/// A connection to the admin console of a server.
+pub struct AdminClient {
+    reader: BufReader<Box<dyn Read + Send>>,
+    writer: Box<dyn Write + Send>,
+}
+
+impl AdminClient {
+    pub fn connect(endpoint: &AdminEndpoint) -> io::Result<Self> {
+        let (reader, writer): (Box<dyn Read + Send>, Box<dyn Write + Send>) = match endpoint {
+            AdminEndpoint::Tcp(addr) => {
+                let stream = TcpStream::connect(addr)?;
+                (Box::new(stream.try_clone()?), Box::new(stream))
+            }
+            #[cfg(unix)]
+            AdminEndpoint::Unix(path) => {
+                let stream = UnixStream::connect(path)?;
+                (Box::new(stream.try_clone()?), Box::new(stream))
+            }
+        };
+
+        Ok(Self {
+            reader: BufReader::new(reader),
+            writer,
+        })
+    }
+
+    /// Runs `command` on the server and returns the lines of its reply. Failed
+    /// commands reply with a single line starting with `error:`.
+    pub fn request(&mut self, command: &str) -> io::Result<Vec<String>> {
+        writeln!(self.writer, "{}", command.trim())?;
+        self.writer.flush()?;
+
+        let mut reply = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+
+            let line = line.trim_end();
+            if line.is_empty() {
+                return Ok(reply);
+            }
+            reply.push(line.to_owned());
+        }
+    }
+}
+
+// systems
+fn spawn_admin_listener(world: &mut World) {
+    let endpoint = world.resource::<AdminEndpoint>().clone();
+    let (sender, receiver) = crossbeam_channel::unbounded();
+
+    if let Err(e) = listen(&endpoint, sender) {
+        error!("Failed to start the admin console on {}: {}", endpoint, e);
+        let _ = world.resource::<NetworkErrors>().sender.send(NetworkError {
+            id: None,
+            error: e.into(),
+        });
+        return;
+    }
+
+    info!("Admin console listening on {}", endpoint);
+    world.insert_resource(AdminConsole {
+        requests: receiver,
+        endpoint,
+    });
+}
+
+/// Commands run in the schedule like any other system, so they see and
+/// change the world between frames.
+fn execute_admin_commands(world: &mut World) {
+    let Some(requests) = world
+        .get_resource::<AdminConsole>()
+        .map(|console| console.requests.try_iter().collect::<Vec<_>>())
+    else {
+        return;
+    };
+
+    for request in requests {
+        let reply = execute(world, &request.line);
+        let _ = request.reply.send(reply);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{harness::Harness, test_utils::next_local_addr};
+
+    #[test]
+    fn commands_parse_from_lines() {
+        assert_eq!("list".parse(), Ok(Command::List));
+        assert_eq!(
+            " kick 3  too many  spaces ".parse(),
+            Ok(Command::Kick {
+                id: NetworkId::from(3),
+                reason: "too many spaces".to_owned()
+            })
+        );
+        assert_eq!(
+            "broadcast restart in 5  minutes".parse(),
+            Ok(Command::Broadcast("restart in 5  minutes".to_owned()))
+        );
+        assert_eq!(
+            "teleport 1 -4 7".parse(),
+            Ok(Command::Teleport {
+                id: NetworkId::from(1),
+                position: Position { x: -4, y: 7 }
+            })
+        );
+        assert!("teleport 1 4".parse::<Command>().is_err());
+        assert!("dump 1 2".parse::<Command>().is_err());
+        assert!("broadcast".parse::<Command>().is_err());
+        assert!("reboot".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn kick_over_the_console_disconnects_the_client() {
+        let endpoint = AdminEndpoint::Tcp(next_local_addr().parse().unwrap());
+        let plugin_endpoint = endpoint.clone();
+        let mut harness = Harness::with_server(2, |server| {
+            server.add_plugin(AdminPlugin {
+                endpoint: plugin_endpoint,
+            });
+        });
+        harness.wait_for_players();
+        let kicked = harness.client_id(0).unwrap();
+
+        let console = thread::spawn(move || {
+            let mut client = AdminClient::connect(&endpoint).unwrap();
+            let list = client.request("list").unwrap();
+            let kick = client.request(&format!("kick {kicked} testing")).unwrap();
+            (list, kick)
+        });
+        harness.step_until("the console is done", |_| console.is_finished());
+        let (list, kick) = console.join().unwrap();
+
+        assert_eq!(list.len(), 2);
+        assert!(list
+            .iter()
+            .any(|line| line.starts_with(&format!("{kicked} 127.0.0.1:"))));
+        assert!(list.iter().all(|line| line.ends_with(" player")));
+        assert_eq!(kick, vec![format!("kicked {kicked}")]);
+        // the client reconnects right away, under a new id
+        harness.step_until("the server forgot the kicked client", |harness| {
+            !harness
+                .server
+                .world
+                .resource::<NetworkToWorld<Server>>()
+                .contains_key(&kicked)
+        });
+    }
+
+    #[test]
+    fn teleported_players_jump_there_on_clients() {
+        let mut harness = Harness::new(1);
+        harness.wait_for_players();
+        let player = harness.client_id(0).unwrap();
+
+        let reply = execute(
+            &mut harness.server.world,
+            &format!("teleport {player} 30 -2"),
+        );
+
+        assert_eq!(reply, vec![format!("teleported {player} to 30,-2")]);
+        harness.wait_until_client_sees(0, player, Position { x: 30, y: -2 });
+    }
+}
@@ -0,0 +1,82 @@
+//! Packet framing exposed for `benches/allocations.rs`, which needs its own
+//! allocator and so cannot run among the tests. Not part of the API.
+
+use futures::{executor::block_on, io::Cursor};
+
+use super::{
+    packet::{ClientPacket, ClientPacketKind, EncodedPacket},
+    socket::Socket,
+};
+use crate::{
+    chat::{entity::MessageKind, packet::SendMessage},
+    path::packet::PathTargetRequest,
+};
+
+fn path_target() -> ClientPacket {
+    PathTargetRequest { x: 1, y: 2 }.into()
+}
+
+fn message() -> ClientPacket {
+    SendMessage {
+        kind: MessageKind::Shout,
+        contents: "anyone up for a raid on the northern keep?".to_owned(),
+    }
+    .into()
+}
+
+/// Packets waiting to be encoded, built up front so that building them is not
+/// counted.
+pub struct Encoder(Vec<ClientPacket>);
+
+impl Encoder {
+    pub fn path_targets(count: usize) -> Self {
+        Self((0..count).map(|_| path_target()).collect())
+    }
+
+    pub fn messages(count: usize) -> Self {
+        Self((0..count).map(|_| message()).collect())
+    }
+
+    /// Encodes the next packet the way it is sent, dropping it right away.
+    pub fn encode(&mut self) {
+        let packet = self.0.pop().expect("no packets left to encode");
+        drop(EncodedPacket::try_encode::<_, ClientPacket>(packet).unwrap());
+    }
+}
+
+/// A socket with packets from a client waiting to be read.
+pub struct Receiver {
+    socket: Socket<Cursor<Vec<u8>>>,
+    kind: ClientPacketKind,
+}
+
+impl Receiver {
+    pub fn path_targets(count: usize) -> Self {
+        Self::new(path_target(), count)
+    }
+
+    pub fn messages(count: usize) -> Self {
+        Self::new(message(), count)
+    }
+
+    fn new(packet: ClientPacket, count: usize) -> Self {
+        let kind = ClientPacketKind::from(&packet);
+        let frame = EncodedPacket::try_encode::<_, ClientPacket>(packet)
+            .unwrap()
+            .bytes()
+            .to_vec();
+        Self {
+            socket: Socket::new(Cursor::new(frame.repeat(count))),
+            kind,
+        }
+    }
+
+    /// Reads and decodes the next packet.
+    pub fn receive(&mut self) {
+        let packet = block_on(async {
+            self.socket.ready().await.unwrap();
+            self.socket.next::<ClientPacket>().await.unwrap()
+        });
+        assert_eq!(ClientPacketKind::from(&packet), self.kind);
+    }
+}
@@ -0,0 +1,364 @@
+use bevy::{
+    prelude::{Component, Local, Query, Res, ResMut, Resource},
+    utils::{HashMap, HashSet},
+};
+
+use super::{
+    error::Result,
+    metrics::ServerMetrics,
+    packet::{EncodedPacket, ServerPacket, ServerPacketKind},
+    plugin::{Client, Network, Server},
+    replicate::ComponentId,
+};
+use crate::{
+    id::{NetworkId, NetworkToWorld},
+    path::plugin::Position,
+    time::tick::Tick,
+};
+
+// resources
+/// How much entity state the server sends each client per tick.
+#[derive(Resource, Clone, Debug)]
+pub struct BandwidthSettings {
+    /// Bytes of updates a client is sent per tick. Updates that do not fit are
+    /// kept for the next tick, where newer updates replace them.
+    pub bytes_per_tick: usize,
+    /// Entities within this many tiles of a client are sent before those
+    /// further away, and their movement is updated every tick.
+    pub near_distance: u32,
+    /// Entities further away than `near_distance`, but within this many tiles,
+    /// have their movement updated every `mid_interval` ticks.
+    pub mid_distance: u32,
+    pub mid_interval: usize,
+    /// Entities further away still have their movement updated only once it
+    /// is at least this many tiles off from what their client knows.
+    pub far_change: u32,
+}
+
+impl Default for BandwidthSettings {
+    fn default() -> Self {
+        Self {
+            bytes_per_tick: 4096,
+            near_distance: 5,
+            mid_distance: 8,
+            mid_interval: 4,
+            far_change: 3,
+        }
+    }
+}
+
+// components
+/// Entity state waiting to be sent to a client, at most one update of each
+/// kind, or of each replicated component, per entity.
+#[derive(Component, Default)]
+pub(crate) struct PendingUpdates {
+    updates: HashMap<UpdateKey, Pending>,
+    next_order: u64,
+    /// Bytes that may still be sent, negative after an update larger than the
+    /// remaining budget went out.
+    allowance: isize,
+    merged: u64,
+    /// Entities whose pending spawn replaced a despawn, so the client still
+    /// knows them from an earlier spawn.
+    respawned: HashSet<NetworkId>,
+}
+
+type UpdateKey = (NetworkId, ServerPacketKind, Option<ComponentId>);
+
+struct Pending {
+    packet: EncodedPacket,
+    order: u64,
+}
+
+impl PendingUpdates {
+    /// Queues `packet` about the entity `subject`, replacing any pending update
+    /// of the same kind about it.
+    pub(crate) fn push<T>(&mut self, subject: NetworkId, packet: T) -> Result<()>
+    where
+        ServerPacket: From<T>,
+    {
+        let packet = ServerPacket::from(packet);
+        let kind = ServerPacketKind::from(&packet);
+        let component = match &packet {
+            ServerPacket::ReplicateComponent(packet) => Some(packet.component),
+            ServerPacket::RemoveComponent(packet) => Some(packet.component),
+            _ => None,
+        };
+
+        match kind {
+            ServerPacketKind::DespawnEntity => {
+                let spawned = self
+                    .updates
+                    .remove(&(subject, ServerPacketKind::SpawnEntity, None))
+                    .is_some();
+                let known = self.respawned.remove(&subject);
+                self.remove_subject(subject);
+                // the client never learned about the entity, so it need not forget it
+                if spawned && !known {
+                    return Ok(());
+                }
+            }
+            ServerPacketKind::SpawnEntity => {
+                let despawn =
+                    self.updates
+                        .remove(&(subject, ServerPacketKind::DespawnEntity, None));
+                if despawn.is_some() {
+                    self.merged += 1;
+                    self.respawned.insert(subject);
+                }
+            }
+            _ => {}
+        }
+
+        let pending = Pending {
+            packet: EncodedPacket::try_encode::<ServerPacket, ServerPacket>(packet)?,
+            order: self.next_order,
+        };
+        self.next_order += 1;
+
+        if let Some(component) = component {
+            // an addition and a removal of the same component replace each other
+            let other = match kind {
+                ServerPacketKind::ReplicateComponent => ServerPacketKind::RemoveComponent,
+                _ => ServerPacketKind::ReplicateComponent,
+            };
+            let replaced = self.updates.remove(&(subject, other, Some(component)));
+            self.merged += u64::from(replaced.is_some());
+        }
+
+        if self
+            .updates
+            .insert((subject, kind, component), pending)
+            .is_some()
+        {
+            self.merged += 1;
+        }
+
+        Ok(())
+    }
+
+    fn remove_subject(&mut self, subject: NetworkId) {
+        let before = self.updates.len();
+        self.updates.retain(|(id, _, _), _| *id != subject);
+        self.merged += (before - self.updates.len()) as u64;
+    }
+
+    /// Adds `ticks` worth of budget, at most one tick's worth is ever saved up.
+    fn refill(&mut self, ticks: usize, bytes_per_tick: usize) {
+        let bytes_per_tick = bytes_per_tick as isize;
+        self.allowance = self
+            .allowance
+            .saturating_add(bytes_per_tick.saturating_mul(ticks as isize))
+            .min(bytes_per_tick);
+    }
+
+    /// Takes the most important updates that fit into the budget. Within the
+    /// same priority spawns go first, so that clients know the entities the
+    /// other updates are about, then the oldest.
+    fn take<F>(&mut self, priority: F) -> Vec<EncodedPacket>
+    where
+        F: Fn(NetworkId) -> Priority,
+    {
+        let mut keys: Vec<_> = self
+            .updates
+            .iter()
+            .map(|(&key, pending)| (priority(key.0), pending.order, key))
+            .collect();
+        keys.sort_unstable_by_key(|&(priority, order, (_, kind, _))| {
+            (priority, kind != ServerPacketKind::SpawnEntity, order)
+        });
+
+        let mut packets = Vec::new();
+        for (_, _, key) in keys {
+            if self.allowance <= 0 {
+                break;
+            }
+
+            if let Some(pending) = self.updates.remove(&key) {
+                if key.1 == ServerPacketKind::SpawnEntity {
+                    self.respawned.remove(&key.0);
+                }
+                self.allowance -= pending.packet.bytes().len() as isize;
+                packets.push(pending.packet);
+            }
+        }
+
+        packets
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.updates.len()
+    }
+}
+
+// util
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Priority {
+    Own,
+    Near,
+    Far,
+}
+
+// systems
+pub(super) fn send_pending_updates(
+    settings: Res<BandwidthSettings>,
+    tick: Res<Tick>,
+    mut metrics: ResMut<ServerMetrics>,
+    network_to_world: Res<NetworkToWorld<Server>>,
+    mut clients: Query<(&NetworkId, &Network<Client>, &mut PendingUpdates)>,
+    positions: Query<&Position>,
+    mut last_tick: Local<Option<usize>>,
+) {
+    let ticks = match *last_tick {
+        Some(last) => tick.current().wrapping_sub(last),
+        None => 1,
+    };
+    if ticks == 0 {
+        return;
+    }
+    *last_tick = Some(tick.current());
+
+    let position = |id: &NetworkId| {
+        network_to_world
+            .get(id)
+            .and_then(|&entity| positions.get(entity).ok())
+            .copied()
+    };
+
+    let mut pending = 0;
+    for (id, client, mut updates) in clients.iter_mut() {
+        updates.refill(ticks, settings.bytes_per_tick);
+
+        let viewer = position(id);
+        let packets = updates.take(|subject| {
+            if subject == *id {
+                return Priority::Own;
+            }
+
+            match (viewer, position(&subject)) {
+                (Some(viewer), Some(subject))
+                    if viewer.taxi_distance(subject) <= settings.near_distance =>
+                {
+                    Priority::Near
+                }
+                _ => Priority::Far,
+            }
+        });
+
+        for packet in packets {
+            metrics.update_bytes_sent += packet.bytes().len() as u64;
+            client.send_encoded(packet);
+        }
+
+        metrics.updates_merged += std::mem::take(&mut updates.merged);
+        pending += updates.len();
+    }
+    metrics.updates_deferred = pending;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ambit::{
+            packet::{DespawnEntity, SpawnEntity},
+            plugin::EntityKind,
+        },
+        path::packet::PathTarget,
+    };
+
+    fn path_target(id: u64, x: i32) -> PathTarget {
+        PathTarget {
+            id: NetworkId::from(id),
+            x,
+            y: 0,
+            current_or_next_x: 0,
+            current_or_next_y: 0,
+        }
+    }
+
+    fn encoded(packet: PathTarget) -> Vec<u8> {
+        EncodedPacket::try_encode::<PathTarget, ServerPacket>(packet)
+            .unwrap()
+            .bytes()
+            .to_vec()
+    }
+
+    #[test]
+    fn newer_updates_replace_pending_ones() {
+        let mut updates = PendingUpdates::default();
+        updates.push(NetworkId::from(1), path_target(1, 1)).unwrap();
+        updates.push(NetworkId::from(1), path_target(1, 2)).unwrap();
+        updates.refill(1, 1024);
+
+        let packets = updates.take(|_| Priority::Far);
+
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].bytes(), encoded(path_target(1, 2)));
+        assert_eq!(updates.merged, 1);
+    }
+
+    #[test]
+    fn important_updates_go_first_and_the_rest_is_deferred() {
+        let mut updates = PendingUpdates::default();
+        updates.push(NetworkId::from(3), path_target(3, 0)).unwrap();
+        updates.push(NetworkId::from(2), path_target(2, 0)).unwrap();
+        updates.push(NetworkId::from(1), path_target(1, 0)).unwrap();
+        let size = encoded(path_target(1, 0)).len();
+        updates.refill(1, size * 2);
+
+        let packets = updates.take(|id| match *id {
+            1 => Priority::Own,
+            2 => Priority::Near,
+            _ => Priority::Far,
+        });
+
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].bytes(), encoded(path_target(1, 0)));
+        assert_eq!(packets[1].bytes(), encoded(path_target(2, 0)));
+        assert_eq!(updates.len(), 1);
+    }
+
+    fn spawn(id: NetworkId) -> SpawnEntity {
+        SpawnEntity {
+            id,
+            kind: EntityKind::Player,
+            x: 0,
+            y: 0,
+            current_or_next_x: 0,
+            current_or_next_y: 0,
+            speed: 3,
+            name: None,
+        }
+    }
+
+    #[test]
+    fn despawning_an_unsent_spawn_sends_nothing() {
+        let mut updates = PendingUpdates::default();
+        let id = NetworkId::from(1);
+        updates.push(id, spawn(id)).unwrap();
+        updates.push(id, path_target(1, 0)).unwrap();
+        updates.push(id, DespawnEntity { id }).unwrap();
+
+        assert_eq!(updates.len(), 0);
+    }
+
+    #[test]
+    fn despawning_a_respawn_still_despawns_the_sent_entity() {
+        let mut updates = PendingUpdates::default();
+        let id = NetworkId::from(1);
+        updates.push(id, spawn(id)).unwrap();
+        updates.refill(1, 1024);
+        assert_eq!(updates.take(|_| Priority::Far).len(), 1);
+
+        updates.push(id, DespawnEntity { id }).unwrap();
+        updates.push(id, spawn(id)).unwrap();
+        updates.push(id, DespawnEntity { id }).unwrap();
+        updates.refill(1, 1024);
+        let packets = updates.take(|_| Priority::Far);
+
+        let despawn = EncodedPacket::try_encode::<_, ServerPacket>(DespawnEntity { id }).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].bytes(), despawn.bytes());
+    }
+}
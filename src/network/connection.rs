@@ -3,6 +3,7 @@ use std::sync::atomic::{AtomicU64, Ordering};
 use derive_more::{Deref, DerefMut};
 use futures::{AsyncRead, AsyncWrite};
 
+use super::guard::Permit;
 use crate::id::NetworkId;
 
 static NEXT_ID: AtomicU64 = AtomicU64::new(0);
@@ -17,16 +18,24 @@ pub(crate) struct Connection<R> {
     #[deref_mut]
     pub(crate) value: R,
     connection_id: NetworkId,
+    permit: Option<Permit>,
 }
 
 impl<R> Connection<R> {
-    pub(crate) fn new(io: R) -> Self {
+    pub(crate) fn new(io: R, permit: Option<Permit>) -> Self {
         Self {
             value: io,
             connection_id: NetworkId::from(next_id()),
+            permit,
         }
     }
 
+    /// The guard permit of an incoming connection, which must be kept for as
+    /// long as the connection is open.
+    pub(crate) fn take_permit(&mut self) -> Option<Permit> {
+        self.permit.take()
+    }
+
     pub(crate) fn connection_id(&self) -> NetworkId {
         self.connection_id
     }
@@ -38,6 +47,7 @@ impl<R> Connection<R> {
         Connection::<T> {
             value: f(&self.value),
             connection_id: self.connection_id,
+            permit: None,
         }
     }
 }
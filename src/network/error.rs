@@ -1,7 +1,36 @@
+use std::net::SocketAddr;
+
 #[derive(thiserror::Error, Debug)]
-pub(crate) enum Error {
-    #[error("Generic {0}")]
-    Generic(String),
+pub enum Error {
+    #[error("Failed to bind endpoint to {addr}: {source}")]
+    Bind {
+        addr: SocketAddr,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to generate certificate: {0}")]
+    Certificate(#[from] rcgen::RcgenError),
+
+    #[error(transparent)]
+    Tls(#[from] rustls::Error),
+
+    #[error("Invalid certificate: {0}")]
+    InvalidCertificate(String),
+
+    #[error("Invalid ban list entry: {0}")]
+    InvalidBan(String),
+
+    #[error("Failed to open stream: {0}")]
+    Stream(#[from] quinn::ConnectionError),
+
+    #[error("{0} not added to packet sender map")]
+    UnregisteredPacket(String),
+
+    #[error("Packet rejected: {0}")]
+    Rejected(String),
+
+    #[error("{0} channel unexpectedly closed")]
+    ChannelClosed(&'static str),
 
     #[error(transparent)]
     Speedy(#[from] speedy::Error),
@@ -10,4 +39,4 @@ pub(crate) enum Error {
     IO(#[from] std::io::Error),
 }
 
-pub(crate) type Result<T> = std::result::Result<T, Error>;
+pub type Result<T> = std::result::Result<T, Error>;
@@ -1,5 +1,85 @@
+use std::net::SocketAddr;
+
+use bevy::prelude::Entity;
+
+use super::error::Error;
 use crate::id::NetworkId;
 
-pub(crate) struct NetworkIdConnected {
-    id: NetworkId,
+/// A connection to `addr` has been requested for the first time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Connecting {
+    pub addr: SocketAddr,
+}
+
+/// A connection has been established and its tasks spawned. On the server
+/// `entity` is the client connection, which becomes the player once it joins,
+/// on the client it is the server connection. Peer links are reported the
+/// same way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Connected {
+    pub id: NetworkId,
+    pub entity: Entity,
+}
+
+/// An attempt to connect to `addr` did not succeed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionFailed {
+    pub addr: SocketAddr,
+    pub reason: String,
+}
+
+/// A previously connected or failed connection to `addr` is being retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reconnecting {
+    pub addr: SocketAddr,
+}
+
+/// The server is full and has put this client in its login queue. Sent again
+/// periodically and whenever the position changes, until the client is
+/// admitted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Queued {
+    /// 1 for the next client to be admitted.
+    pub position: u32,
+    pub length: u32,
+}
+
+/// A connection was closed and its entity despawned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disconnected {
+    pub id: NetworkId,
+    pub entity: Entity,
+    pub reason: DisconnectReason,
+}
+
+/// A network operation failed. `id` is set when the failure belongs to a single
+/// connection.
+#[derive(Debug)]
+pub struct NetworkError {
+    pub id: Option<NetworkId>,
+    pub error: Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The local app is shutting down.
+    Quit,
+    /// The connection was closed by either side.
+    Closed,
+    /// The connection failed while sending or receiving.
+    Error(String),
+    /// Middleware rejected a packet received on the connection.
+    Rejected(String),
+}
+
+impl From<std::io::Error> for DisconnectReason {
+    fn from(value: std::io::Error) -> Self {
+        match value.kind() {
+            std::io::ErrorKind::UnexpectedEof
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::NotConnected => Self::Closed,
+            _ => Self::Error(value.to_string()),
+        }
+    }
 }
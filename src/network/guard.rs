@@ -0,0 +1,603 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    net::IpAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use bevy::{
+    prelude::{Component, Resource},
+    utils::HashMap,
+};
+use speedy::{Readable, Writable};
+
+use super::error::{Error, Result};
+
+// resources
+/// Limits on incoming connections, enforced by the listener before a
+/// connection's handshake is even completed.
+#[derive(Resource, Clone, Debug)]
+pub struct GuardSettings {
+    /// Connections from one address that may be open at the same time, `None`
+    /// for no limit.
+    pub max_connections_per_ip: Option<usize>,
+    /// How often one address may connect, `None` for no limit.
+    pub connect_rate: Option<RateLimit>,
+    /// Connections that have not completed their handshake by then are dropped.
+    pub handshake_timeout: Duration,
+    /// File the ban list is loaded from and saved to, one [`Ban`] per line.
+    /// Without it bans only last until the server stops.
+    pub ban_list: Option<PathBuf>,
+}
+
+impl Default for GuardSettings {
+    fn default() -> Self {
+        Self {
+            max_connections_per_ip: None,
+            connect_rate: None,
+            handshake_timeout: Duration::from_secs(10),
+            ban_list: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    pub connections: usize,
+    pub per: Duration,
+}
+
+/// Decides which incoming connections are let through, shared between the
+/// listener task and the app. Address bans are checked by the listener, account
+/// bans once the client joins or presents a transfer ticket.
+#[derive(Resource, Clone)]
+pub(crate) struct ConnectionGuard {
+    state: Arc<Mutex<GuardState>>,
+}
+
+struct GuardState {
+    max_connections_per_ip: Option<usize>,
+    connect_rate: Option<RateLimit>,
+    handshake_timeout: Duration,
+    bans: BanList,
+    addrs: HashMap<IpAddr, AddrState>,
+}
+
+#[derive(Default)]
+struct AddrState {
+    open: usize,
+    recent: VecDeque<Instant>,
+}
+
+impl Default for ConnectionGuard {
+    fn default() -> Self {
+        Self::new(&GuardSettings::default(), BanList::default())
+    }
+}
+
+impl ConnectionGuard {
+    pub(crate) fn new(settings: &GuardSettings, bans: BanList) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(GuardState {
+                max_connections_per_ip: settings.max_connections_per_ip,
+                connect_rate: settings.connect_rate,
+                handshake_timeout: settings.handshake_timeout,
+                bans,
+                addrs: HashMap::default(),
+            })),
+        }
+    }
+
+    /// Loads the ban list named in `settings`.
+    pub(crate) fn load(settings: &GuardSettings) -> Result<Self> {
+        let bans = match &settings.ban_list {
+            Some(path) => BanList::load(path.clone())?,
+            None => BanList::default(),
+        };
+
+        Ok(Self::new(settings, bans))
+    }
+
+    fn state(&self) -> MutexGuard<'_, GuardState> {
+        // the state stays consistent even if a holder panicked
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn handshake_timeout(&self) -> Duration {
+        self.state().handshake_timeout
+    }
+
+    /// Checks a new connection from `ip`. The returned permit counts towards
+    /// the concurrent connection limit until it is dropped.
+    pub(crate) fn admit(&self, ip: IpAddr) -> std::result::Result<Permit, Rejection> {
+        let ip = ip.to_canonical();
+        let now = Instant::now();
+        let mut state = self.state();
+
+        if state.bans.is_banned(ip) {
+            return Err(Rejection::Banned);
+        }
+
+        let connect_rate = state.connect_rate;
+        let max_connections = state.max_connections_per_ip;
+
+        // forget addresses that have nothing left to limit
+        state.addrs.retain(|_, addr| {
+            if let Some(rate) = connect_rate {
+                while addr
+                    .recent
+                    .front()
+                    .is_some_and(|connected| now - *connected >= rate.per)
+                {
+                    addr.recent.pop_front();
+                }
+            }
+            addr.open > 0 || !addr.recent.is_empty()
+        });
+
+        let addr = state.addrs.entry(ip).or_default();
+
+        if max_connections.is_some_and(|max| addr.open >= max) {
+            return Err(Rejection::TooManyConnections);
+        }
+
+        if let Some(rate) = connect_rate {
+            if addr.recent.len() >= rate.connections {
+                return Err(Rejection::RateLimited);
+            }
+            addr.recent.push_back(now);
+        }
+
+        addr.open += 1;
+
+        Ok(Permit {
+            guard: self.clone(),
+            ip,
+        })
+    }
+
+    pub(crate) fn ban(&self, ban: Ban) -> Result<()> {
+        self.state().bans.ban(ban)
+    }
+
+    /// Returns whether `ban` was in place.
+    pub(crate) fn unban(&self, ban: &Ban) -> Result<bool> {
+        self.state().bans.unban(ban)
+    }
+
+    pub(crate) fn is_banned(&self, ip: IpAddr) -> bool {
+        self.state().bans.is_banned(ip.to_canonical())
+    }
+
+    pub(crate) fn is_account_banned(&self, account: &Account) -> bool {
+        self.state().bans.is_account_banned(account)
+    }
+}
+
+/// An admitted connection, released when dropped.
+pub(crate) struct Permit {
+    guard: ConnectionGuard,
+    ip: IpAddr,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.guard.state();
+        if let Some(addr) = state.addrs.get_mut(&self.ip) {
+            addr.open = addr.open.saturating_sub(1);
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Rejection {
+    #[error("address is banned")]
+    Banned,
+    #[error("too many open connections from address")]
+    TooManyConnections,
+    #[error("address is connecting too often")]
+    RateLimited,
+    #[error("account is banned")]
+    AccountBanned,
+}
+
+#[derive(Default)]
+pub(crate) struct BanList {
+    bans: Vec<Ban>,
+    path: Option<PathBuf>,
+}
+
+impl BanList {
+    /// Reads the bans from `path`, which is created on the first ban if it
+    /// does not exist yet. Empty lines and lines starting with `#` are skipped.
+    pub(crate) fn load(path: PathBuf) -> Result<Self> {
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let bans = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::parse)
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            bans,
+            path: Some(path),
+        })
+    }
+
+    pub(crate) fn is_banned(&self, ip: IpAddr) -> bool {
+        self.bans.iter().any(|ban| match ban {
+            Ban::Net(net) => net.contains(ip),
+            Ban::Account(_) => false,
+        })
+    }
+
+    pub(crate) fn is_account_banned(&self, account: &Account) -> bool {
+        self.bans
+            .iter()
+            .any(|ban| matches!(ban, Ban::Account(banned) if banned == account))
+    }
+
+    fn ban(&mut self, ban: Ban) -> Result<()> {
+        if self.bans.contains(&ban) {
+            return Ok(());
+        }
+
+        if let Some(path) = &self.path {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{ban}")?;
+        }
+        self.bans.push(ban);
+
+        Ok(())
+    }
+
+    fn unban(&mut self, ban: &Ban) -> Result<bool> {
+        let Some(index) = self.bans.iter().position(|banned| banned == ban) else {
+            return Ok(false);
+        };
+        self.bans.remove(index);
+
+        if let Some(path) = &self.path {
+            let contents: String = self.bans.iter().map(|ban| format!("{ban}\n")).collect();
+            fs::write(path, contents)?;
+        }
+
+        Ok(true)
+    }
+}
+
+// components
+/// The name a client plays under, sent when it joins. Clients do not prove
+/// they own it, so an account ban only keeps out whoever keeps using the name.
+#[derive(Component, Resource, Readable, Writable, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Account(pub String);
+
+impl fmt::Display for Account {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+// util
+/// An entry of the ban list, an [`IpNet`] or an account like `account:name`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Ban {
+    Net(IpNet),
+    Account(Account),
+}
+
+impl FromStr for Ban {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.strip_prefix("account:") {
+            Some("") => Err(Error::InvalidBan(s.to_owned())),
+            Some(name) => Ok(Self::Account(Account(name.to_owned()))),
+            None => s.parse().map(Self::Net),
+        }
+    }
+}
+
+impl fmt::Display for Ban {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Net(net) => net.fmt(f),
+            Self::Account(account) => write!(f, "account:{account}"),
+        }
+    }
+}
+
+/// An address or CIDR range, like `192.0.2.1` or `2001:db8::/32`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                mask(u32::from(ip).into(), 32, self.prefix) == u128::from(u32::from(net))
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                mask(u128::from(ip), 128, self.prefix) == u128::from(net)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl From<IpAddr> for IpNet {
+    fn from(addr: IpAddr) -> Self {
+        let addr = addr.to_canonical();
+        let prefix = if addr.is_ipv4() { 32 } else { 128 };
+        Self { addr, prefix }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidBan(s.to_owned());
+
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+
+        let addr = IpAddr::from_str(addr)
+            .map_err(|_| invalid())?
+            .to_canonical();
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(invalid());
+        }
+
+        // keep only the network part so equal ranges compare equal
+        let addr = match addr {
+            IpAddr::V4(ip) => {
+                IpAddr::from((mask(u32::from(ip).into(), 32, prefix) as u32).to_be_bytes())
+            }
+            IpAddr::V6(ip) => IpAddr::from(mask(u128::from(ip), 128, prefix).to_be_bytes()),
+        };
+
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.addr, self.prefix) {
+            (IpAddr::V4(_), 32) | (IpAddr::V6(_), 128) => write!(f, "{}", self.addr),
+            _ => write!(f, "{}/{}", self.addr, self.prefix),
+        }
+    }
+}
+
+/// Clears all but the first `prefix` of the `bits` lowest bits of `value`.
+fn mask(value: u128, bits: u8, prefix: u8) -> u128 {
+    let host_bits = bits - prefix;
+    if host_bits >= 128 {
+        0
+    } else {
+        value >> host_bits << host_bits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use bevy::{ecs::event::Events, prelude::With};
+    use rstest::rstest;
+
+    use super::*;
+    use crate::{
+        ambit::plugin::Player,
+        network::{
+            event::ConnectionFailed,
+            harness::Harness,
+            plugin::{Client, Network},
+        },
+    };
+
+    #[rstest]
+    #[case("192.0.2.7", "192.0.2.7", true)]
+    #[case("192.0.2.7", "192.0.2.8", false)]
+    #[case("192.0.2.0/24", "192.0.2.200", true)]
+    #[case("192.0.2.0/24", "192.0.3.1", false)]
+    #[case("0.0.0.0/0", "198.51.100.1", true)]
+    #[case("2001:db8::/32", "2001:db8:1::1", true)]
+    #[case("2001:db8::/32", "192.0.2.1", false)]
+    #[case("192.0.2.0/24", "::ffff:192.0.2.1", true)]
+    fn ip_net_contains(#[case] net: &str, #[case] ip: &str, #[case] expected: bool) {
+        let net: IpNet = net.parse().unwrap();
+
+        assert_eq!(net.contains(ip.parse().unwrap()), expected);
+    }
+
+    #[rstest]
+    #[case("192.0.2.7/33")]
+    #[case("192.0.2/24")]
+    #[case("not an address")]
+    fn invalid_ip_nets_are_rejected(#[case] net: &str) {
+        assert!(net.parse::<IpNet>().is_err());
+    }
+
+    #[rstest]
+    #[case("192.0.2.0/24")]
+    #[case("account:griefer")]
+    #[case("account:two words")]
+    fn bans_round_trip(#[case] ban: &str) {
+        assert_eq!(ban.parse::<Ban>().unwrap().to_string(), ban);
+    }
+
+    #[test]
+    fn bans_without_an_account_name_are_rejected() {
+        assert!("account:".parse::<Ban>().is_err());
+    }
+
+    #[test]
+    fn ip_net_keeps_only_the_network_part() {
+        let net: IpNet = "192.0.2.77/24".parse().unwrap();
+
+        assert_eq!(net.to_string(), "192.0.2.0/24");
+    }
+
+    #[test]
+    fn permits_limit_concurrent_connections() {
+        let guard = ConnectionGuard::new(
+            &GuardSettings {
+                max_connections_per_ip: Some(1),
+                ..Default::default()
+            },
+            BanList::default(),
+        );
+        let ip = "192.0.2.1".parse().unwrap();
+
+        let permit = guard.admit(ip).unwrap();
+        assert_eq!(guard.admit(ip).err(), Some(Rejection::TooManyConnections));
+        assert!(guard.admit("192.0.2.2".parse().unwrap()).is_ok());
+
+        drop(permit);
+        assert!(guard.admit(ip).is_ok());
+    }
+
+    #[test]
+    fn connect_rate_is_limited_per_address() {
+        let guard = ConnectionGuard::new(
+            &GuardSettings {
+                connect_rate: Some(RateLimit {
+                    connections: 2,
+                    per: Duration::from_secs(60),
+                }),
+                ..Default::default()
+            },
+            BanList::default(),
+        );
+        let ip = "192.0.2.1".parse().unwrap();
+
+        drop(guard.admit(ip));
+        drop(guard.admit(ip));
+
+        assert_eq!(guard.admit(ip).err(), Some(Rejection::RateLimited));
+    }
+
+    #[test]
+    fn bans_are_persisted() {
+        let path = env::temp_dir().join(format!("animus-bans-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let net: Ban = "192.0.2.0/24".parse().unwrap();
+        let griefer = Account("griefer".to_owned());
+
+        let guard = ConnectionGuard::new(
+            &GuardSettings::default(),
+            BanList::load(path.clone()).unwrap(),
+        );
+        guard.ban(net.clone()).unwrap();
+        guard.ban("198.51.100.1".parse().unwrap()).unwrap();
+        guard.ban(Ban::Account(griefer.clone())).unwrap();
+        assert_eq!(
+            guard.admit("192.0.2.9".parse().unwrap()).err(),
+            Some(Rejection::Banned)
+        );
+
+        let reloaded = BanList::load(path.clone()).unwrap();
+        assert!(reloaded.is_banned("192.0.2.9".parse().unwrap()));
+        assert!(reloaded.is_account_banned(&griefer));
+        assert!(!reloaded.is_account_banned(&Account("192.0.2.9".to_owned())));
+
+        assert!(guard.unban(&net).unwrap());
+        let reloaded = BanList::load(path.clone()).unwrap();
+        assert!(!reloaded.is_banned("192.0.2.9".parse().unwrap()));
+        assert!(reloaded.is_banned("198.51.100.1".parse().unwrap()));
+        assert!(reloaded.is_account_banned(&griefer));
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn banned_clients_are_refused() {
+        let mut harness = Harness::new(1);
+        harness
+            .server
+            .world
+            .resource::<ConnectionGuard>()
+            .ban("127.0.0.0/8".parse().unwrap())
+            .unwrap();
+
+        harness.step_until("the client is refused", |harness| {
+            let events = harness.server.world.resource::<Events<ConnectionFailed>>();
+            events
+                .get_reader()
+                .iter(events)
+                .any(|failed| failed.reason == Rejection::Banned.to_string())
+        });
+
+        assert_eq!(harness.count::<(), With<Network<Client>>>(None), 0);
+    }
+
+    #[test]
+    fn banned_accounts_are_refused_on_joining() {
+        let mut harness = Harness::new(2);
+        let griefer = Account("griefer".to_owned());
+        harness
+            .server
+            .world
+            .resource::<ConnectionGuard>()
+            .ban(Ban::Account(griefer.clone()))
+            .unwrap();
+        harness.clients[0].insert_resource(griefer);
+        harness.clients[1].insert_resource(Account("visitor".to_owned()));
+
+        let accounts = |harness: &mut Harness| {
+            let world = &mut harness.server.world;
+            world
+                .query_filtered::<&Account, With<Player>>()
+                .iter(world)
+                .map(|account| account.0.clone())
+                .collect::<Vec<_>>()
+        };
+        harness.step_until("the visitor joined", |harness| {
+            accounts(harness) == ["visitor"]
+        });
+        for _ in 0..100 {
+            harness.step();
+        }
+        assert_eq!(accounts(&mut harness), ["visitor"]);
+        assert_eq!(harness.client_id(0), None);
+    }
+
+    #[test]
+    fn closed_connections_free_their_slot() {
+        let mut harness = Harness::with_server(1, |server| {
+            server.insert_resource(GuardSettings {
+                max_connections_per_ip: Some(1),
+                ..Default::default()
+            });
+        });
+        harness.wait_for_players();
+
+        drop(harness.clients.remove(0));
+        harness.add_client();
+
+        harness.wait_for_players();
+    }
+}
@@ -0,0 +1,227 @@
+use std::{
+    net::SocketAddr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use bevy::{
+    ecs::query::{ReadOnlyWorldQuery, WorldQuery},
+    prelude::{App, Input, MinimalPlugins, MouseButton, With},
+};
+
+use super::{
+    packet::ClientPacket,
+    plugin::{BaseNetworkPlugin, ClientNetworkPlugin, Me, Network, Server, ServerNetworkPlugin},
+    spectate::{Spectating, Viewpoint},
+    test_utils::next_local_addr,
+};
+use crate::{
+    ambit::plugin::{ClientAmbitPlugin, Player, ServerAmbitPlugin},
+    chat::plugin::{ClientChatPlugin, ServerChatPlugin},
+    client::camera::MouseWorldCoordinates,
+    id::{NetworkId, NetworkToWorld},
+    network::plugin::Client,
+    path::plugin::{BasePathPlugin, ClientPathPlugin, Position, ServerPathPlugin},
+    time::tick::TickPlugin,
+};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+const STEP_INTERVAL: Duration = Duration::from_millis(1);
+
+/// A server and its clients, each in their own [`App`], running in the test
+/// process over real local connections. Apps are always updated in the same
+/// order: the server first, then its peers and then the clients, each in the
+/// order they were added.
+pub(crate) struct Harness {
+    addr: SocketAddr,
+    pub(crate) server: App,
+    /// Further servers, which clients only reach by being transferred.
+    pub(crate) peers: Vec<App>,
+    pub(crate) clients: Vec<App>,
+}
+
+impl Harness {
+    pub(crate) fn new(clients: usize) -> Self {
+        Self::with_server(clients, |_| {})
+    }
+
+    /// Like [`Harness::new`], letting `configure` change the server before it
+    /// starts up.
+    pub(crate) fn with_server<F>(clients: usize, configure: F) -> Self
+    where
+        F: FnOnce(&mut App),
+    {
+        let addr = next_local_addr().parse().unwrap();
+
+        let mut server = server_app(addr);
+        configure(&mut server);
+
+        let mut harness = Self {
+            addr,
+            server,
+            peers: Vec::new(),
+            clients: Vec::new(),
+        };
+        // let the listener bind before any client tries to connect
+        harness.server.update();
+
+        for _ in 0..clients {
+            harness.add_client();
+        }
+
+        harness
+    }
+
+    /// Adds a client and returns its index.
+    pub(crate) fn add_client(&mut self) -> usize {
+        self.clients.push(client_app(self.addr));
+        self.clients.len() - 1
+    }
+
+    /// Adds another server, letting `configure` change it before it starts
+    /// up, and returns its index.
+    pub(crate) fn add_peer<F>(&mut self, configure: F) -> usize
+    where
+        F: FnOnce(&mut App),
+    {
+        let mut server = server_app(next_local_addr().parse().unwrap());
+        configure(&mut server);
+        server.update();
+        self.peers.push(server);
+        self.peers.len() - 1
+    }
+
+    /// Updates every app once.
+    pub(crate) fn step(&mut self) {
+        self.server.update();
+        for peer in &mut self.peers {
+            peer.update();
+        }
+        for client in &mut self.clients {
+            client.update();
+        }
+    }
+
+    /// Steps until `condition` holds, panicking with `what` if it does not
+    /// within a few seconds.
+    pub(crate) fn step_until<F>(&mut self, what: &str, mut condition: F)
+    where
+        F: FnMut(&mut Self) -> bool,
+    {
+        let started = Instant::now();
+        while !condition(self) {
+            if started.elapsed() > DEFAULT_TIMEOUT {
+                panic!("timed out waiting until {what}");
+            }
+            self.step();
+            thread::sleep(STEP_INTERVAL);
+        }
+    }
+
+    /// Adds a client that joins as a spectator and returns its index.
+    pub(crate) fn add_spectator(&mut self, viewpoint: Viewpoint) -> usize {
+        let mut client = client_app(self.addr);
+        client.insert_resource(Spectating(viewpoint));
+        self.clients.push(client);
+        self.clients.len() - 1
+    }
+
+    /// Steps until every client has been accepted and knows where it spawned.
+    pub(crate) fn wait_for_players(&mut self) {
+        let clients = self.clients.len();
+        self.step_until("all clients joined", |harness| {
+            harness.count::<(), With<Player>>(None) == clients
+                && (0..clients).all(|client| {
+                    harness
+                        .client_id(client)
+                        .and_then(|id| harness.client_position(client, id))
+                        .is_some()
+                })
+        });
+    }
+
+    /// Steps until `client` knows the entity `id` to be at `position`.
+    pub(crate) fn wait_until_client_sees(
+        &mut self,
+        client: usize,
+        id: NetworkId,
+        position: Position,
+    ) {
+        self.step_until(
+            &format!("client {client} sees {id} at {position:?}"),
+            |harness| harness.client_position(client, id) == Some(position),
+        );
+    }
+
+    /// The network id the server assigned to `client`, once it has joined.
+    pub(crate) fn client_id(&mut self, client: usize) -> Option<NetworkId> {
+        let world = &mut self.clients[client].world;
+        world
+            .query_filtered::<&NetworkId, With<Me>>()
+            .get_single(world)
+            .ok()
+            .copied()
+    }
+
+    /// Where `client` currently believes the entity `id` is.
+    pub(crate) fn client_position(&mut self, client: usize, id: NetworkId) -> Option<Position> {
+        let world = &mut self.clients[client].world;
+        let entity = *world.resource::<NetworkToWorld<Client>>().get(&id)?;
+        world.get::<Position>(entity).copied()
+    }
+
+    /// Sends `packet` from `client` to the server.
+    pub(crate) fn send_from_client<T>(&mut self, client: usize, packet: T)
+    where
+        ClientPacket: From<T>,
+    {
+        let world = &mut self.clients[client].world;
+        world
+            .query::<&Network<Server>>()
+            .single(world)
+            .send(packet)
+            .unwrap();
+    }
+
+    /// Counts entities matching `Q` and `F` on the server, or on `client`.
+    pub(crate) fn count<Q, F>(&mut self, client: Option<usize>) -> usize
+    where
+        Q: WorldQuery,
+        F: ReadOnlyWorldQuery,
+    {
+        let world = match client {
+            Some(client) => &mut self.clients[client].world,
+            None => &mut self.server.world,
+        };
+        world.query_filtered::<Q, F>().iter(world).count()
+    }
+}
+
+fn server_app(addr: SocketAddr) -> App {
+    let mut app = App::new();
+    app.add_plugins(MinimalPlugins);
+    app.add_plugin(BaseNetworkPlugin { addr });
+    app.add_plugin(ServerNetworkPlugin);
+    app.add_plugin(TickPlugin);
+    app.add_plugin(BasePathPlugin);
+    app.add_plugin(ServerPathPlugin);
+    app.add_plugin(ServerAmbitPlugin);
+    app.add_plugin(ServerChatPlugin);
+    app
+}
+
+fn client_app(addr: SocketAddr) -> App {
+    let mut app = App::new();
+    app.add_plugins(MinimalPlugins);
+    // the client path plugin reads mouse input, which a test never has
+    app.init_resource::<MouseWorldCoordinates>();
+    app.init_resource::<Input<MouseButton>>();
+    app.add_plugin(BaseNetworkPlugin { addr });
+    app.add_plugin(ClientNetworkPlugin);
+    app.add_plugin(TickPlugin);
+    app.add_plugin(BasePathPlugin);
+    app.add_plugin(ClientPathPlugin);
+    app.add_plugin(ClientAmbitPlugin);
+    app.add_plugin(ClientChatPlugin);
+    app
+}
@@ -1,4 +1,9 @@
-use std::{collections::HashMap, marker::PhantomData, sync::Arc};
+use std::{
+    collections::HashMap,
+    fmt,
+    marker::PhantomData,
+    sync::{Arc, RwLock},
+};
 
 use bevy::prelude::Resource;
 use crossbeam_channel::Sender;
@@ -8,8 +13,8 @@ use tracing::info;
 use super::{
     error::Result,
     packet::{
-        AnyPacketWithConnId, ClientPacket, ClientPacketKind, Heartbeat, Packet, ServerPacket,
-        ServerPacketKind,
+        AnyPacketWithConnId, ClientPacket, ClientPacketKind, Heartbeat, Packet, PeerPacket,
+        PeerPacketKind, ServerPacket, ServerPacketKind,
     },
 };
 use crate::{id::NetworkId, network::error::Error};
@@ -18,12 +23,39 @@ pub(crate) trait Mediator<T> {
     fn raise(&self, event: T) -> Result<()>;
 }
 
+/// What a [`Middleware`] decided to do with a packet.
+pub(crate) enum Verdict<P> {
+    /// Hands the packet, possibly rewritten, on to the next middleware and
+    /// finally to its sender.
+    Pass(AnyPacketWithConnId<P>),
+    /// Drops the packet.
+    Drop,
+    /// Drops the packet and disconnects whoever sent it.
+    Disconnect(String),
+}
+
+/// Sees every packet of a connection before it is dispatched. Middleware runs
+/// on the connection's receive task, in the order it was added.
+pub(crate) trait Middleware<P>: Send + Sync + 'static {
+    fn handle(&self, packet: AnyPacketWithConnId<P>) -> Verdict<P>;
+}
+
+impl<P, F> Middleware<P> for F
+where
+    F: Fn(AnyPacketWithConnId<P>) -> Verdict<P> + Send + Sync + 'static,
+{
+    fn handle(&self, packet: AnyPacketWithConnId<P>) -> Verdict<P> {
+        self(packet)
+    }
+}
+
 #[derive(Resource, Debug)]
 pub(crate) struct AnyPacketMediator<P>
 where
     P: Packet,
 {
     packet_senders: Arc<PacketSenderMap<P>>,
+    middleware: Arc<RwLock<MiddlewareChain<P>>>,
 }
 
 impl<P> Clone for AnyPacketMediator<P>
@@ -33,6 +65,7 @@ where
     fn clone(&self) -> Self {
         Self {
             packet_senders: Arc::clone(&self.packet_senders),
+            middleware: Arc::clone(&self.middleware),
         }
     }
 }
@@ -42,22 +75,69 @@ where
     P: Packet,
 {
     pub(crate) fn new(packet_senders: Arc<PacketSenderMap<P>>) -> Self {
-        Self { packet_senders }
+        Self {
+            packet_senders,
+            middleware: Default::default(),
+        }
+    }
+
+    /// Adds `middleware` to the end of the chain, for connections that are
+    /// already open too.
+    pub(crate) fn add_middleware<M>(&self, middleware: M)
+    where
+        M: Middleware<P>,
+    {
+        self.middleware
+            .write()
+            .unwrap()
+            .0
+            .push(Box::new(middleware));
     }
 
-    pub(crate) fn send<'a>(&'a self, packet: AnyPacketWithConnId<P>) -> Result<()>
+    pub(crate) fn send<'a>(&'a self, mut packet: AnyPacketWithConnId<P>) -> Result<()>
     where
         P::Kind: for<'b> From<&'b P>,
     {
+        for middleware in &self.middleware.read().unwrap().0 {
+            packet = match middleware.handle(packet) {
+                Verdict::Pass(packet) => packet,
+                Verdict::Drop => return Ok(()),
+                Verdict::Disconnect(reason) => return Err(Error::Rejected(reason)),
+            };
+        }
+
         let packet_kind = packet.packet_kind();
-        let sender = self
-            .packet_senders
-            .get(&packet_kind)
-            .unwrap_or_else(|| panic!("{:?} not added to packet sender map", packet_kind));
+        let Some(sender) = self.packet_senders.get(&packet_kind) else {
+            return Err(Error::UnregisteredPacket(format!("{:?}", packet_kind)));
+        };
         sender.handle(packet)
     }
 }
 
+struct MiddlewareChain<P>(Vec<Box<dyn Middleware<P>>>);
+
+impl<P> Default for MiddlewareChain<P> {
+    fn default() -> Self {
+        Self(Vec::new())
+    }
+}
+
+impl<P> fmt::Debug for MiddlewareChain<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MiddlewareChain({} middleware)", self.0.len())
+    }
+}
+
+/// Logs the kind of every packet received.
+pub(crate) fn log_packets<P>(packet: AnyPacketWithConnId<P>) -> Verdict<P>
+where
+    P: Packet,
+    P::Kind: for<'a> From<&'a P>,
+{
+    info!("Mediating packet kind: {:?}", packet.packet_kind());
+    Verdict::Pass(packet)
+}
+
 #[derive(Deref, DerefMut, Resource, Debug)]
 pub(crate) struct PacketSenderMap<P>(pub HashMap<P::Kind, P::Sender>)
 where
@@ -94,12 +174,8 @@ where
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
-pub(crate) enum NetworkEvent {
-    Disconnected { connection_id: NetworkId },
-}
-
 pub(crate) use client_packet_sender_enum::*;
+pub(crate) use peer_packet_sender_enum::*;
 pub(crate) use server_packet_sender_enum::*;
 
 #[proxy_enum::proxy(ClientPacketSender)]
@@ -118,6 +194,11 @@ mod client_packet_sender_enum {
         SendMessage(Sender::<PacketWithConnId<SendMessage>>),
         PathTargetRequest(Sender::<PacketWithConnId<PathTargetRequest>>),
         QueryEntity(Sender::<PacketWithConnId<QueryEntity>>),
+        Join(Sender::<PacketWithConnId<Join>>),
+        PresentTicket(Sender::<PacketWithConnId<PresentTicket>>),
+        AckSnapshot(Sender::<PacketWithConnId<AckSnapshot>>),
+        Spectate(Sender::<PacketWithConnId<Spectate>>),
+        AbortTransfer(Sender::<PacketWithConnId<AbortTransfer>>),
         Heartbeat(NullSink::<ClientPacket, Heartbeat>),
     }
 
@@ -134,6 +215,11 @@ impl From<&ClientPacketSender> for ClientPacketKind {
             ClientPacketSender::PathTargetRequest(_) => ClientPacketKind::PathTargetRequest,
             ClientPacketSender::Heartbeat(_) => ClientPacketKind::Heartbeat,
             ClientPacketSender::QueryEntity(_) => ClientPacketKind::QueryEntity,
+            ClientPacketSender::Join(_) => ClientPacketKind::Join,
+            ClientPacketSender::PresentTicket(_) => ClientPacketKind::PresentTicket,
+            ClientPacketSender::AckSnapshot(_) => ClientPacketKind::AckSnapshot,
+            ClientPacketSender::Spectate(_) => ClientPacketKind::Spectate,
+            ClientPacketSender::AbortTransfer(_) => ClientPacketKind::AbortTransfer,
         }
     }
 }
@@ -163,6 +249,11 @@ mod server_packet_sender_enum {
         PathTarget(Sender::<PathTarget>),
         SpawnEntity(Sender::<SpawnEntity>),
         DespawnEntity(Sender::<DespawnEntity>),
+        Transfer(Sender::<Transfer>),
+        QueuePosition(Sender::<QueuePosition>),
+        Snapshot(Sender::<Snapshot>),
+        ReplicateComponent(Sender::<ReplicateComponent>),
+        RemoveComponent(Sender::<RemoveComponent>),
         Heartbeat(NullSink::<ServerPacket, Heartbeat>),
     }
 
@@ -181,6 +272,11 @@ impl From<&ServerPacketSender> for ServerPacketKind {
             ServerPacketSender::PathTarget(_) => ServerPacketKind::PathTarget,
             ServerPacketSender::SpawnEntity(_) => ServerPacketKind::SpawnEntity,
             ServerPacketSender::DespawnEntity(_) => ServerPacketKind::DespawnEntity,
+            ServerPacketSender::Transfer(_) => ServerPacketKind::Transfer,
+            ServerPacketSender::QueuePosition(_) => ServerPacketKind::QueuePosition,
+            ServerPacketSender::Snapshot(_) => ServerPacketKind::Snapshot,
+            ServerPacketSender::ReplicateComponent(_) => ServerPacketKind::ReplicateComponent,
+            ServerPacketSender::RemoveComponent(_) => ServerPacketKind::RemoveComponent,
         }
     }
 }
@@ -191,6 +287,47 @@ impl AnyPacketHandler<ServerPacket> for ServerPacketSender {
     }
 }
 
+#[proxy_enum::proxy(PeerPacketSender)]
+mod peer_packet_sender_enum {
+    use derive_more::TryInto;
+
+    use super::*;
+    use crate::{chat::packet::ForwardMessage, network::packet::*};
+
+    #[rustfmt::skip]
+    #[derive(Debug, TryInto)]
+    pub(crate) enum PeerPacketSender {
+        PeerHello(Sender::<PacketWithConnId<PeerHello>>),
+        HandoffPlayer(Sender::<PacketWithConnId<HandoffPlayer>>),
+        Presence(Sender::<PacketWithConnId<Presence>>),
+        ForwardMessage(Sender::<PacketWithConnId<ForwardMessage>>),
+        Heartbeat(NullSink::<PeerPacket, Heartbeat>),
+    }
+
+    impl PeerPacketSender {
+        #[implement]
+        pub(crate) fn handle(&self, any_packet: AnyPacketWithConnId<PeerPacket>) -> Result<()> {}
+    }
+}
+
+impl From<&PeerPacketSender> for PeerPacketKind {
+    fn from(value: &PeerPacketSender) -> Self {
+        match value {
+            PeerPacketSender::PeerHello(_) => PeerPacketKind::PeerHello,
+            PeerPacketSender::HandoffPlayer(_) => PeerPacketKind::HandoffPlayer,
+            PeerPacketSender::Presence(_) => PeerPacketKind::Presence,
+            PeerPacketSender::ForwardMessage(_) => PeerPacketKind::ForwardMessage,
+            PeerPacketSender::Heartbeat(_) => PeerPacketKind::Heartbeat,
+        }
+    }
+}
+
+impl AnyPacketHandler<PeerPacket> for PeerPacketSender {
+    fn handle(&self, any_packet: AnyPacketWithConnId<PeerPacket>) -> Result<()> {
+        PeerPacketSender::handle(self, any_packet)
+    }
+}
+
 pub(crate) trait AnyPacketHandler<P> {
     fn handle(&self, any_packet: AnyPacketWithConnId<P>) -> Result<()>;
 }
@@ -201,34 +338,52 @@ pub(crate) struct PacketWithConnId<T> {
     pub(crate) connection_id: NetworkId,
 }
 
-trait PacketHandler<T>
+trait PacketHandler<T, P>
 where
-    T: Into<Self::Kind> + TryFrom<Self::Kind>,
+    T: Into<P> + TryFrom<P>,
 {
-    type Kind;
-    fn handle(&self, any_packet: AnyPacketWithConnId<Self::Kind>) -> Result<()>;
+    fn handle(&self, any_packet: AnyPacketWithConnId<P>) -> Result<()>;
 }
 
-impl<T> PacketHandler<T> for Sender<PacketWithConnId<T>>
+impl<T> PacketHandler<T, ClientPacket> for Sender<PacketWithConnId<T>>
 where
     T: Into<ClientPacket> + TryFrom<ClientPacket>,
     <T as TryFrom<ClientPacket>>::Error: std::fmt::Debug,
 {
-    type Kind = ClientPacket;
-
     fn handle(&self, any_packet: AnyPacketWithConnId<ClientPacket>) -> Result<()> {
-        let message_name = any_packet.packet_kind();
-        let packet = TryInto::<T>::try_into(any_packet.packet)
-            .expect("Packet kind must be in both sender and any packet enum");
+        forward_with_conn_id(self, any_packet)
+    }
+}
 
-        info!("Mediating packet kind: {:?}", message_name);
+impl<T> PacketHandler<T, PeerPacket> for Sender<PacketWithConnId<T>>
+where
+    T: Into<PeerPacket> + TryFrom<PeerPacket>,
+    <T as TryFrom<PeerPacket>>::Error: std::fmt::Debug,
+{
+    fn handle(&self, any_packet: AnyPacketWithConnId<PeerPacket>) -> Result<()> {
+        forward_with_conn_id(self, any_packet)
+    }
+}
+
+fn forward_with_conn_id<T, P>(
+    sender: &Sender<PacketWithConnId<T>>,
+    any_packet: AnyPacketWithConnId<P>,
+) -> Result<()>
+where
+    P: Packet,
+    P::Kind: for<'a> From<&'a P>,
+    T: TryFrom<P>,
+    <T as TryFrom<P>>::Error: std::fmt::Debug,
+{
+    let packet = TryInto::<T>::try_into(any_packet.packet)
+        .expect("Packet kind must be in both sender and any packet enum");
 
-        self.send(PacketWithConnId {
+    sender
+        .send(PacketWithConnId {
             packet,
             connection_id: any_packet.connection_id,
         })
-        .map_err(|_| Error::Generic("Sender unexpectedly closed".to_owned()))
-    }
+        .map_err(|_| Error::ChannelClosed(std::any::type_name::<T>()))
 }
 
 #[derive(Debug)]
@@ -240,33 +395,89 @@ impl<T, P> Default for NullSink<P, T> {
     }
 }
 
-impl<T, P> PacketHandler<T> for NullSink<P, T>
+impl<T, P> PacketHandler<T, P> for NullSink<P, T>
 where
     T: Into<P> + TryFrom<P>,
 {
-    type Kind = P;
-
     fn handle(&self, _any_packet: AnyPacketWithConnId<P>) -> Result<()> {
         Ok(())
     }
 }
 
-impl<T> PacketHandler<T> for Sender<T>
+impl<T> PacketHandler<T, ServerPacket> for Sender<T>
 where
     T: Into<ServerPacket> + TryFrom<ServerPacket>,
     <T as TryFrom<ServerPacket>>::Error: std::fmt::Debug,
 {
-    type Kind = ServerPacket;
-
     fn handle(&self, any_packet: AnyPacketWithConnId<ServerPacket>) -> Result<()> {
-        let message_name = any_packet.packet_kind();
         // Cannot happen since packet type must be in both sender and anypacket enum
         let packet = TryInto::<T>::try_into(any_packet.packet)
             .expect("Packet kind must be in both sender and any packet enum");
 
-        info!("Mediating packet kind: {:?}", message_name);
-
         self.send(packet)
-            .map_err(|_| Error::Generic("Sender unexpectedly closed".to_owned()))
+            .map_err(|_| Error::ChannelClosed(std::any::type_name::<T>()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+    use crate::chat::{entity::MessageKind, packet::SendMessage};
+
+    #[rstest]
+    fn unregistered_packet_is_an_error() {
+        let mediator =
+            AnyPacketMediator::new(Arc::new(PacketSenderMap::<ClientPacket>(HashMap::new())));
+
+        let result = mediator.send(AnyPacketWithConnId {
+            connection_id: NetworkId::from(0),
+            packet: ClientPacket::from(SendMessage {
+                kind: MessageKind::Shout,
+                contents: "message".to_owned(),
+            }),
+        });
+
+        assert!(matches!(result, Err(Error::UnregisteredPacket(_))));
+    }
+
+    #[rstest]
+    fn middleware_runs_in_order_before_dispatch() {
+        let (tx, rx) = crossbeam_channel::unbounded::<PacketWithConnId<SendMessage>>();
+        let mut senders = PacketSenderMap::<ClientPacket>::default();
+        senders.add(tx);
+        let mediator = AnyPacketMediator::new(Arc::new(senders));
+
+        let shout = |contents: &str| AnyPacketWithConnId {
+            connection_id: NetworkId::from(0),
+            packet: ClientPacket::from(SendMessage {
+                kind: MessageKind::Shout,
+                contents: contents.to_owned(),
+            }),
+        };
+        mediator.add_middleware(|mut packet: AnyPacketWithConnId<ClientPacket>| {
+            if let ClientPacket::SendMessage(message) = &mut packet.packet {
+                message.contents = message.contents.to_uppercase();
+            }
+            Verdict::Pass(packet)
+        });
+        mediator.add_middleware(
+            |packet: AnyPacketWithConnId<ClientPacket>| match &packet.packet {
+                ClientPacket::SendMessage(message) if message.contents == "SPAM" => Verdict::Drop,
+                ClientPacket::SendMessage(message) if message.contents == "CHEAT" => {
+                    Verdict::Disconnect("cheating".to_owned())
+                }
+                _ => Verdict::Pass(packet),
+            },
+        );
+
+        mediator.send(shout("hello")).unwrap();
+        mediator.send(shout("spam")).unwrap();
+        let result = mediator.send(shout("cheat"));
+
+        assert!(matches!(result, Err(Error::Rejected(reason)) if reason == "cheating"));
+        let received = rx.try_iter().map(|p| p.packet.contents).collect::<Vec<_>>();
+        assert_eq!(received, vec!["HELLO".to_owned()]);
     }
 }
@@ -0,0 +1,34 @@
+use std::time::Duration;
+
+use bevy::prelude::Resource;
+
+// resources
+/// Server state for monitoring, refreshed every update.
+#[derive(Resource, Default, Debug, Clone, PartialEq, Eq)]
+pub struct ServerMetrics {
+    /// Clients that have joined as players.
+    pub players: usize,
+    /// The configured player cap, `None` when unlimited.
+    pub max_players: Option<usize>,
+    /// Clients watching without playing, they do not count towards the cap.
+    pub spectators: usize,
+    /// Clients waiting in the login queue.
+    pub queued: usize,
+    /// Clients admitted from the login queue since startup.
+    pub admitted_from_queue: u64,
+    /// How long the client at the front of the login queue has been waiting.
+    pub longest_queue_wait: Duration,
+    /// Bytes of entity updates sent since startup.
+    pub update_bytes_sent: u64,
+    /// Entity updates held back because their client's budget was used up.
+    pub updates_deferred: usize,
+    /// Entity updates dropped since startup because a newer one replaced them
+    /// before they were sent.
+    pub updates_merged: u64,
+    /// Movements left out of snapshots since startup because the entity was
+    /// too far from the client to be updated yet.
+    pub updates_coalesced: u64,
+    /// Snapshots sent without a baseline because the client had not
+    /// acknowledged a recent enough one.
+    pub full_snapshots_sent: u64,
+}
@@ -1,12 +1,32 @@
 pub(crate) mod accept;
+#[cfg(feature = "server")]
+pub mod admin;
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench;
+pub mod budget;
 pub(crate) mod connection;
-pub(crate) mod error;
-pub(crate) mod event;
+pub mod error;
+pub mod event;
+pub mod guard;
+#[cfg(test)]
+pub(crate) mod harness;
 pub(crate) mod mediator;
+pub mod metrics;
 pub(crate) mod packet;
+#[cfg(feature = "server")]
+pub mod peer;
 pub mod plugin;
+pub(crate) mod pool;
+pub mod queue;
+pub(crate) mod replicate;
+pub(crate) mod runtime;
+pub(crate) mod snapshot;
 pub(crate) mod socket;
+pub mod spectate;
+pub(crate) mod state;
 pub(crate) mod task;
+pub(crate) mod transfer;
 
 #[cfg(test)]
 pub(crate) mod test_utils {
@@ -25,155 +45,44 @@ pub(crate) mod test_utils {
 
 #[cfg(test)]
 mod tests {
-    // use std::{
-    //     collections::HashMap,
-    //     time::{Duration, Instant},
-    // };
-    //
-    // use rstest::rstest;
-    // use tracing_test::traced_test;
-    //
-    // use crate::{
-    //     chat::{
-    //         entity::MessageKind,
-    //         packet::{MessageReceived, SendMessage},
-    //     },
-    //     id::NetworkId,
-    //     network::{
-    //         client::NetworkClient,
-    //         mediator::{NullSink, PacketSenderMap, PacketWithConnId},
-    //         packet::{
-    //             AcceptConnection, ClientPacket, ClientPacketKind, Heartbeat,
-    // ServerPacket,             ServerPacketKind,
-    //         },
-    //         server::NetworkServer,
-    //         test_utils::next_local_addr,
-    //     },
-    // };
-    //
-    // #[rstest]
-    // #[tokio::test]
-    // #[traced_test]
-    // async fn run_client_and_server() {
-    //     tokio::task::spawn_blocking(move || {
-    //         let mut client_map =
-    // PacketSenderMap::<ServerPacket>(HashMap::new());         client_map.
-    // 0.insert(             ServerPacketKind::Heartbeat,
-    //             NullSink::<ServerPacket,
-    // Heartbeat>(Default::default()).into(),         );
-    //         let (client_tx, _client_rx) =
-    // crossbeam_channel::unbounded::<AcceptConnection>();
-    //         client_map.add(client_tx);
-    //         let (client_tx, client_rx) =
-    // crossbeam_channel::unbounded::<MessageReceived>();
-    //         client_map.add(client_tx);
-    //         let mut server_map =
-    // PacketSenderMap::<ClientPacket>(HashMap::new());         server_map.
-    // 0.insert(             ClientPacketKind::Heartbeat,
-    //             NullSink::<ClientPacket,
-    // Heartbeat>(Default::default()).into(),         );
-    //         let (server_tx, server_rx) =
-    //
-    // crossbeam_channel::unbounded::<PacketWithConnId<SendMessage>>();
-    //         server_map.add(server_tx);
-    //         let addr = next_local_addr();
-    //
-    //         let mut server = NetworkServer::new(addr.parse().unwrap(),
-    // server_map);
-    //
-    //         let mut client = NetworkClient::new(client_map);
-    //
-    //         client.connect(addr.parse().unwrap()).unwrap();
-    //
-    //         let mut client_connected = false;
-    //         let mut server_connected = false;
-    //         let now = Instant::now();
-    //         while !server_connected || !client_connected {
-    //             server.spawn_tasks_for_new_connections(|_| {
-    //                 server_connected = true;
-    //             });
-    //             client.spawn_tasks_for_new_connections(|_| client_connected =
-    // true);
-    //
-    //             if now.elapsed() > Duration::from_secs(1) {
-    //                 panic!("waited for connections for too long")
-    //             }
-    //         }
-    //         let sent_packet = SendMessage {
-    //             contents: "message".to_owned(),
-    //             kind: MessageKind::Shout,
-    //         };
-    //
-    //         client.send(sent_packet.clone()).unwrap();
-    //         let packet =
-    // server_rx.recv_timeout(Duration::from_secs(1)).unwrap();
-    //         assert_eq!(packet.packet, sent_packet);
-    //
-    //         server
-    //             .send(
-    //                 MessageReceived {
-    //                     contents: "message".to_owned(),
-    //                     kind: MessageKind::Shout,
-    //                     sender: NetworkId::from(0),
-    //                 },
-    //                 packet.connection_id,
-    //             )
-    //             .unwrap();
-    //         assert!(client_rx.recv_timeout(Duration::from_secs(1)).is_ok());
-    //
-    //         std::mem::drop(client);
-    //         let mut client_map =
-    // PacketSenderMap::<ServerPacket>(HashMap::new());         let
-    // (client_tx, _client_rx) =
-    // crossbeam_channel::unbounded::<AcceptConnection>();
-    //         client_map.add(client_tx);
-    //         let (client_tx, client_rx) =
-    // crossbeam_channel::unbounded::<MessageReceived>();
-    //         client_map.add(client_tx);
-    //         let mut client = NetworkClient::new(client_map);
-    //         client.connect(addr.parse().unwrap()).unwrap();
-    //
-    //         let mut client_connected = false;
-    //         let mut server_connected = false;
-    //         let now = Instant::now();
-    //         while !server_connected || !client_connected {
-    //             server.spawn_tasks_for_new_connections(|_| {
-    //                 server_connected = true;
-    //             });
-    //             client.spawn_tasks_for_new_connections(|_| client_connected =
-    // true);
-    //
-    //             if now.elapsed() > Duration::from_secs(1) {
-    //                 panic!("waited for connections for too long")
-    //             }
-    //         }
-    //
-    //         let sent_packet = SendMessage {
-    //             contents: "message".to_owned(),
-    //             kind: MessageKind::Shout,
-    //         };
-    //
-    //         client.send(sent_packet.clone()).unwrap();
-    //         let packet =
-    // server_rx.recv_timeout(Duration::from_secs(1)).unwrap();
-    //         assert_eq!(packet.packet, sent_packet);
-    //
-    //         server
-    //             .send(
-    //                 MessageReceived {
-    //                     contents: "message".to_owned(),
-    //                     kind: MessageKind::Shout,
-    //                     sender: NetworkId::from(0),
-    //                 },
-    //                 packet.connection_id,
-    //             )
-    //             .unwrap();
-    //         assert!(client_rx.recv_timeout(Duration::from_secs(1)).is_ok());
-    //
-    //         std::mem::drop(client);
-    //         std::mem::drop(server);
-    //     })
-    //     .await
-    //     .unwrap();
-    // }
+    use bevy::prelude::With;
+
+    use crate::{
+        ambit::plugin::Player,
+        id::NetworkToWorld,
+        network::{harness::Harness, plugin::Client},
+        path::{packet::PathTargetRequest, plugin::Position},
+    };
+
+    #[test]
+    fn player_walking_into_view_is_spawned_on_other_clients() {
+        let mut harness = Harness::new(2);
+        harness.wait_for_players();
+        let walker = harness.client_id(0).unwrap();
+        assert_ne!(Some(walker), harness.client_id(1));
+
+        harness.send_from_client(0, PathTargetRequest { x: 12, y: 0 });
+        harness.wait_until_client_sees(0, walker, Position { x: 12, y: 0 });
+        harness.send_from_client(0, PathTargetRequest { x: 0, y: 0 });
+
+        harness.step_until("the walker is spawned on the other client", |harness| {
+            harness.clients[1]
+                .world
+                .resource::<NetworkToWorld<Client>>()
+                .contains_key(&walker)
+        });
+        harness.wait_until_client_sees(1, walker, Position { x: 0, y: 0 });
+    }
+
+    #[test]
+    fn server_despawns_disconnected_client() {
+        let mut harness = Harness::new(2);
+        harness.wait_for_players();
+
+        drop(harness.clients.remove(1));
+
+        harness.step_until("the server despawned the client", |harness| {
+            harness.count::<(), With<Player>>(None) == 1
+        });
+    }
 }
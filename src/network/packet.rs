@@ -1,9 +1,22 @@
-use std::{hash::Hash, io::Write, sync::Arc};
+use std::{
+    hash::Hash,
+    io::Write,
+    net::{IpAddr, SocketAddr},
+};
 
 use speedy::{Readable, Writable};
 use tracing::trace;
 
-use super::{error::Result, mediator::AnyPacketHandler};
+use super::{
+    error::Result,
+    guard::Account,
+    mediator::AnyPacketHandler,
+    pool::{SharedBuffer, FRAMES},
+    replicate::ComponentId,
+    spectate::Viewpoint,
+    state::ConnectionState,
+    transfer::Ticket,
+};
 use crate::id::NetworkId;
 
 pub(crate) struct AnyPacketWithConnId<T> {
@@ -32,6 +45,7 @@ pub(crate) trait Packet:
 }
 
 pub(crate) use client_packet_enum::*;
+pub(crate) use peer_packet_enum::*;
 pub(crate) use server_packet_enum::*;
 
 #[proxy_enum::proxy(ClientPacket)]
@@ -51,6 +65,11 @@ mod client_packet_enum {
         SendMessage(SendMessage),
         QueryEntity(QueryEntity),
         PathTargetRequest(PathTargetRequest),
+        Join(Join),
+        PresentTicket(PresentTicket),
+        AckSnapshot(AckSnapshot),
+        Spectate(Spectate),
+        AbortTransfer(AbortTransfer),
         Heartbeat(Heartbeat),
     }
 
@@ -61,6 +80,42 @@ mod client_packet_enum {
     }
 }
 
+impl ClientPacketKind {
+    /// The connection states a client may send the packet in.
+    pub(crate) fn valid_states(self) -> &'static [ConnectionState] {
+        use ConnectionState::*;
+
+        match self {
+            Self::Join | Self::PresentTicket => &[Handshaking],
+            Self::SendMessage
+            | Self::QueryEntity
+            | Self::PathTargetRequest
+            | Self::AckSnapshot
+            | Self::Spectate => &[InGame],
+            Self::AbortTransfer => &[Transferring],
+            Self::Heartbeat => &[
+                Handshaking,
+                Authenticating,
+                Loading,
+                InGame,
+                Transferring,
+                Disconnecting,
+            ],
+        }
+    }
+
+    /// The state the connection is in once the server accepted the packet,
+    /// for packets that end the current one.
+    pub(crate) fn next_state(self) -> Option<ConnectionState> {
+        match self {
+            Self::Join => Some(ConnectionState::Loading),
+            Self::PresentTicket => Some(ConnectionState::Authenticating),
+            Self::AbortTransfer => Some(ConnectionState::InGame),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Readable, Writable, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Default)]
 pub(crate) struct Heartbeat;
 
@@ -85,6 +140,11 @@ mod server_packet_enum {
         PathTarget(PathTarget),
         SpawnEntity(SpawnEntity),
         DespawnEntity(DespawnEntity),
+        Transfer(Transfer),
+        QueuePosition(QueuePosition),
+        Snapshot(Snapshot),
+        ReplicateComponent(ReplicateComponent),
+        RemoveComponent(RemoveComponent),
         Heartbeat(Heartbeat),
     }
     impl Packet for ServerPacket {
@@ -94,14 +154,161 @@ mod server_packet_enum {
     }
 }
 
+#[proxy_enum::proxy(PeerPacket)]
+mod peer_packet_enum {
+    use derive_more::TryInto;
+    use enum_kinds::EnumKind;
+
+    use super::*;
+    use crate::{chat::packet::ForwardMessage, network::mediator::PeerPacketSender};
+
+    #[derive(Readable, Writable, TryInto, Debug, EnumKind)]
+    #[enum_kind(PeerPacketKind, derive(Hash))]
+    pub(crate) enum PeerPacket {
+        PeerHello(PeerHello),
+        HandoffPlayer(HandoffPlayer),
+        Presence(Presence),
+        ForwardMessage(ForwardMessage),
+        Heartbeat(Heartbeat),
+    }
+
+    impl Packet for PeerPacket {
+        type Kind = PeerPacketKind;
+        type OtherPacket = PeerPacket;
+        type Sender = PeerPacketSender;
+    }
+}
+
 #[derive(Readable, Writable, Debug, PartialEq, Eq, Clone, Copy)]
 pub(crate) struct AcceptConnection {
     pub(crate) connection_id: NetworkId,
 }
 
+#[derive(Readable, Writable, Debug, PartialEq, Eq, Clone)]
+pub(crate) struct Join {
+    pub(crate) role: Role,
+    pub(crate) account: Option<Account>,
+}
+
+#[derive(Readable, Writable, Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum Role {
+    Player,
+    /// Watches without taking a player slot or being seen.
+    Spectator(Viewpoint),
+}
+
+/// Moves a spectator to another viewpoint.
+#[derive(Readable, Writable, Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) struct Spectate {
+    pub(crate) viewpoint: Viewpoint,
+}
+
+/// Sent to a client waiting for a free player slot. `position` is 1 for the
+/// next client to be admitted.
+#[derive(Readable, Writable, Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) struct QueuePosition {
+    pub(crate) position: u32,
+    pub(crate) length: u32,
+}
+
+/// The state of the entities a client can see, relative to the snapshot
+/// `baseline` the client acknowledged, or to nothing without one. Changes that
+/// do not fit in one packet are left for later snapshots.
+#[derive(Readable, Writable, Debug, PartialEq, Eq, Clone)]
+pub(crate) struct Snapshot {
+    pub(crate) sequence: u64,
+    pub(crate) baseline: Option<u64>,
+    pub(crate) entities: Vec<EntityDelta>,
+    /// Entities in the baseline that are no longer visible.
+    pub(crate) removed: Vec<NetworkId>,
+}
+
+/// The fields of an entity that changed since the baseline, all of them for
+/// entities that are not in it.
+#[derive(Readable, Writable, Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) struct EntityDelta {
+    pub(crate) id: NetworkId,
+    pub(crate) target_x: Option<i32>,
+    pub(crate) target_y: Option<i32>,
+    pub(crate) origin_x: Option<i32>,
+    pub(crate) origin_y: Option<i32>,
+}
+
+/// A replicated component that was added to or changed on an entity.
+#[derive(Readable, Writable, Debug, PartialEq, Eq, Clone)]
+pub(crate) struct ReplicateComponent {
+    pub(crate) id: NetworkId,
+    pub(crate) component: ComponentId,
+    /// The component, encoded on its own.
+    pub(crate) bytes: Vec<u8>,
+}
+
+#[derive(Readable, Writable, Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) struct RemoveComponent {
+    pub(crate) id: NetworkId,
+    pub(crate) component: ComponentId,
+}
+
+#[derive(Readable, Writable, Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) struct AckSnapshot {
+    pub(crate) sequence: u64,
+}
+
+#[derive(Readable, Writable, Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) struct Transfer {
+    pub(crate) ip: IpAddr,
+    pub(crate) port: u16,
+    pub(crate) ticket: Ticket,
+}
+
+impl Transfer {
+    pub(crate) fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.ip, self.port)
+    }
+}
+
+#[derive(Readable, Writable, Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) struct PresentTicket {
+    pub(crate) ticket: Ticket,
+}
+
+/// Tells the server that the transfer it started failed, so that the client
+/// keeps playing there.
+#[derive(Readable, Writable, Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) struct AbortTransfer;
+
+/// First packet on a peer link, telling the other server where its clients
+/// should be transferred to.
+#[derive(Readable, Writable, Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) struct PeerHello {
+    pub(crate) client_ip: IpAddr,
+    pub(crate) client_port: u16,
+}
+
+impl PeerHello {
+    pub(crate) fn client_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.client_ip, self.client_port)
+    }
+}
+
+#[derive(Readable, Writable, Debug, PartialEq, Eq, Clone)]
+pub(crate) struct HandoffPlayer {
+    pub(crate) ticket: Ticket,
+    pub(crate) x: i32,
+    pub(crate) y: i32,
+    pub(crate) account: Option<Account>,
+}
+
+#[derive(Readable, Writable, Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) struct Presence {
+    pub(crate) players: u32,
+}
+
+/// A packet framed with its length, cheap to clone for sending to several
+/// connections. The frame goes back to the pool once every clone is dropped.
 #[derive(Clone, Debug)]
 pub(crate) struct EncodedPacket {
-    bytes: Arc<[u8]>,
+    bytes: SharedBuffer,
 }
 
 impl EncodedPacket {
@@ -112,13 +319,14 @@ impl EncodedPacket {
         let p = P::from(packet);
         let length = Writable::<speedy::LittleEndian>::bytes_needed(&p)?;
         trace!("Writing packet requiring length: {}", length);
-        let mut bytes: Vec<u8> = vec![0; length + 4];
+        let mut bytes = FRAMES.take();
+        bytes.resize(length + 4, 0);
         (&mut bytes[..4]).write_all(&u32::to_le_bytes(length as u32))?;
 
         p.write_to_buffer(&mut bytes[4..])?;
 
         Ok(Self {
-            bytes: bytes.into(),
+            bytes: bytes.share(),
         })
     }
 
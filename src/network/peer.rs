@@ -0,0 +1,590 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use bevy::{
+    prelude::{
+        Added, App, Commands, Component, CoreStage, EventReader, EventWriter, IntoSystemDescriptor,
+        Local, Plugin, Query, Res, ResMut, Resource, With,
+    },
+    tasks::{IoTaskPool, Task},
+};
+use rustls::{server::AllowAnyAuthenticatedClient, RootCertStore};
+use tracing::{error, info};
+
+use super::{
+    accept::{QuicConnector, QuicListener},
+    error::{Error, Result},
+    event::{Connected, NetworkError},
+    guard::ConnectionGuard,
+    mediator::{log_packets, AnyPacketMediator, PacketSenderMap, PacketWithConnId},
+    packet::{HandoffPlayer, PeerHello, PeerPacket, Presence},
+    plugin::{
+        despawn_disconnections, raise_connection_failures, spawn_connection_tasks, AcceptTask,
+        AddPacketAppExt, ConnectionFailures, ConnectionReceiver, Disconnections, Network,
+        NetworkErrors, Packets, Peer, Quit,
+    },
+    task::accept::AcceptConnectionsTask,
+    transfer::{TicketIssued, TransferDestinations, TransferTickets},
+};
+use crate::{
+    ambit::plugin::Player, chat::packet::ForwardMessage, id::NetworkToWorld, path::plugin::Position,
+};
+
+const PEER_SERVER_NAME: &str = "peer";
+
+// plugins
+/// Links this server to other servers of the same cluster. Peers authenticate
+/// each other with certificates signed by the cluster root.
+pub struct PeerPlugin {
+    /// Address other servers dial to reach this one.
+    pub listen: SocketAddr,
+    /// Address clients connect to, handed to peers so they know where to send
+    /// the players they transfer here.
+    pub client_addr: SocketAddr,
+    pub identity: PeerIdentity,
+}
+
+impl Plugin for PeerPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(PeerSettings {
+            listen: self.listen,
+            client_addr: self.client_addr,
+            identity: self.identity.clone(),
+        });
+        app.add_event::<DialPeer>();
+        app.init_resource::<Disconnections<Peer>>();
+        app.init_resource::<NetworkToWorld<Peer>>();
+        app.add_startup_system(spawn_peer_tasks);
+        // so that links exist by the time their first packets are read
+        app.add_system_to_stage(CoreStage::PreUpdate, spawn_peer_links);
+        app.add_system(despawn_disconnections::<Peer>);
+        app.add_system(raise_connection_failures::<Peer>);
+        app.add_system(dial_peers);
+        app.add_system(receive_peer_hellos);
+        app.add_system(list_transfer_destinations.before("issue_tickets"));
+        app.add_system(hand_off_players);
+        app.add_system(expect_handoffs);
+        app.add_system(share_presence);
+        app.add_system(receive_presence);
+
+        app.init_resource::<PacketSenderMap<PeerPacket>>();
+
+        app.add_packet::<PacketWithConnId<PeerHello>, PeerPacket>();
+        app.add_packet::<PacketWithConnId<HandoffPlayer>, PeerPacket>();
+        app.add_packet::<PacketWithConnId<Presence>, PeerPacket>();
+        app.add_packet::<PacketWithConnId<ForwardMessage>, PeerPacket>();
+
+        let packet_map = app
+            .world
+            .remove_resource::<PacketSenderMap<PeerPacket>>()
+            .unwrap();
+
+        app.insert_resource(AnyPacketMediator::new(Arc::new(packet_map)));
+        app.add_middleware(log_packets::<PeerPacket>);
+    }
+}
+
+// resources
+/// The certificate a server presents to its peers, along with the cluster root
+/// it expects theirs to be signed by.
+#[derive(Clone)]
+pub struct PeerIdentity {
+    root: rustls::Certificate,
+    chain: Vec<rustls::Certificate>,
+    key: rustls::PrivateKey,
+}
+
+impl PeerIdentity {
+    /// Creates an identity from DER encoded certificates and private key.
+    pub fn new(root: Vec<u8>, chain: Vec<Vec<u8>>, key: Vec<u8>) -> Self {
+        Self {
+            root: rustls::Certificate(root),
+            chain: chain.into_iter().map(rustls::Certificate).collect(),
+            key: rustls::PrivateKey(key),
+        }
+    }
+
+    /// Generates a fresh certificate signed by `root`.
+    pub fn generate(root: &rcgen::Certificate) -> Result<Self> {
+        let cert = rcgen::generate_simple_self_signed(vec![PEER_SERVER_NAME.to_string()])?;
+
+        Ok(Self::new(
+            root.serialize_der()?,
+            vec![cert.serialize_der_with_signer(root)?],
+            cert.serialize_private_key_der(),
+        ))
+    }
+
+    fn roots(&self) -> Result<RootCertStore> {
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(&self.root)
+            .map_err(|e| Error::InvalidCertificate(e.to_string()))?;
+        Ok(roots)
+    }
+
+    fn server_crypto(&self) -> Result<rustls::ServerConfig> {
+        Ok(rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(self.roots()?))
+            .with_single_cert(self.chain.clone(), self.key.clone())?)
+    }
+
+    fn client_crypto(&self) -> Result<rustls::ClientConfig> {
+        Ok(rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(self.roots()?)
+            .with_single_cert(self.chain.clone(), self.key.clone())?)
+    }
+}
+
+/// Generates a root every server of a cluster can sign its peer certificate
+/// with.
+pub fn generate_cluster_root() -> Result<rcgen::Certificate> {
+    let mut params = rcgen::CertificateParams::new(vec![]);
+    params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+    Ok(rcgen::Certificate::from_params(params)?)
+}
+
+#[derive(Resource)]
+struct PeerSettings {
+    listen: SocketAddr,
+    client_addr: SocketAddr,
+    identity: PeerIdentity,
+}
+
+#[derive(Resource)]
+struct PeerDialer {
+    sender: async_channel::Sender<SocketAddr>,
+    task: Task<()>,
+}
+
+// components
+/// A link to another server.
+#[derive(Component)]
+pub(crate) struct PeerLink {
+    addr: SocketAddr,
+    client_addr: Option<SocketAddr>,
+    players: u32,
+}
+
+impl PeerLink {
+    pub(crate) fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The address clients of the peer connect to, known once it said hello.
+    pub(crate) fn client_addr(&self) -> Option<SocketAddr> {
+        self.client_addr
+    }
+
+    pub(crate) fn players(&self) -> u32 {
+        self.players
+    }
+}
+
+// events
+/// Opens a link to the server whose peer listener is on `addr`.
+pub struct DialPeer {
+    pub addr: SocketAddr,
+}
+
+// systems
+fn spawn_peer_tasks(
+    mut commands: Commands,
+    settings: Res<PeerSettings>,
+    quit: Res<Quit>,
+    errors: Res<NetworkErrors>,
+) {
+    let io_pool = IoTaskPool::get();
+
+    let (server_crypto, client_crypto) = match settings
+        .identity
+        .server_crypto()
+        .and_then(|server| Ok((server, settings.identity.client_crypto()?)))
+    {
+        Ok(crypto) => crypto,
+        Err(error) => {
+            error!("Invalid peer identity: {}", error);
+            let _ = errors.sender.send(NetworkError { id: None, error });
+            return;
+        }
+    };
+
+    let (new_connections_tx, new_connections_rx) = crossbeam_channel::unbounded();
+    commands.insert_resource(ConnectionReceiver::<Peer>::new(new_connections_rx));
+    let (failures_tx, failures_rx) = crossbeam_channel::unbounded();
+    commands.insert_resource(ConnectionFailures::<Peer>::new(failures_rx));
+
+    let listen = settings.listen;
+    let stop = quit.receiver.clone();
+    let listener_errors = errors.sender.clone();
+    let listener_failures = failures_tx.clone();
+    let listener_connections = new_connections_tx.clone();
+    let task = io_pool.spawn(async move {
+        let listener = match QuicListener::with_crypto(
+            listen,
+            server_crypto,
+            listener_failures,
+            ConnectionGuard::default(),
+        ) {
+            Ok(listener) => listener,
+            Err(error) => {
+                error!("Failed to create peer listener: {}", error);
+                let _ = listener_errors.send(NetworkError { id: None, error });
+                return;
+            }
+        };
+        AcceptConnectionsTask::new(listener, listener_connections)
+            ._run(stop)
+            .await;
+    });
+    commands.insert_resource(AcceptTask::<Peer>::new(task));
+
+    let (dial_tx, dial_rx) = async_channel::unbounded();
+    let stop = quit.receiver.clone();
+    let errors = errors.sender.clone();
+    let task = io_pool.spawn(async move {
+        let connector =
+            match QuicConnector::with_crypto(dial_rx, client_crypto, PEER_SERVER_NAME, failures_tx)
+            {
+                Ok(connector) => connector,
+                Err(error) => {
+                    error!("Failed to create peer connector: {}", error);
+                    let _ = errors.send(NetworkError { id: None, error });
+                    return;
+                }
+            };
+        AcceptConnectionsTask::new(connector, new_connections_tx)
+            ._run(stop)
+            .await;
+    });
+    commands.insert_resource(PeerDialer {
+        sender: dial_tx,
+        task,
+    });
+}
+
+fn dial_peers(mut dials: EventReader<DialPeer>, dialer: Option<Res<PeerDialer>>) {
+    let Some(dialer) = dialer else {
+        dials.clear();
+        return;
+    };
+
+    for dial in dials.iter() {
+        info!("Dialing peer {}", dial.addr);
+        let _ = dialer.sender.try_send(dial.addr);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn spawn_peer_links(
+    mut commands: Commands,
+    mut network_to_world: ResMut<NetworkToWorld<Peer>>,
+    mut connected: EventWriter<Connected>,
+    conn_receiver: Option<Res<ConnectionReceiver<Peer>>>,
+    disconnections: Res<Disconnections<Peer>>,
+    errors: Res<NetworkErrors>,
+    packet_mediator: Res<AnyPacketMediator<PeerPacket>>,
+    quit: Res<Quit>,
+    settings: Res<PeerSettings>,
+) {
+    let Some(conn_receiver) = conn_receiver else {
+        return;
+    };
+
+    if conn_receiver.receiver.is_empty() {
+        return;
+    }
+
+    let pool = IoTaskPool::get();
+
+    for connection in conn_receiver.receiver.try_iter() {
+        let conn_id = connection.connection_id();
+        let addr = connection.value.remote_address();
+
+        let sender = spawn_connection_tasks(
+            &disconnections,
+            &errors,
+            pool,
+            &packet_mediator,
+            &quit,
+            connection,
+        );
+        let network = Network::<Peer>::new(conn_id, sender);
+
+        if let Err(e) = network.send(PeerHello {
+            client_ip: settings.client_addr.ip(),
+            client_port: settings.client_addr.port(),
+        }) {
+            error!("Failed to greet peer {}: {}", addr, e);
+        }
+
+        let entity = commands
+            .spawn((
+                network,
+                PeerLink {
+                    addr,
+                    client_addr: None,
+                    players: 0,
+                },
+            ))
+            .id();
+
+        info!("linked to peer {} as {}", addr, conn_id);
+        network_to_world.insert(conn_id, entity);
+        connected.send(Connected {
+            id: conn_id,
+            entity,
+        });
+    }
+}
+
+fn receive_peer_hellos(
+    packets: Res<Packets<PacketWithConnId<PeerHello>>>,
+    network_to_world: Res<NetworkToWorld<Peer>>,
+    mut links: Query<&mut PeerLink>,
+) {
+    for hello in packets.iter() {
+        let Some(mut link) = network_to_world
+            .get(&hello.connection_id)
+            .and_then(|&entity| links.get_mut(entity).ok())
+        else {
+            continue;
+        };
+
+        link.client_addr = Some(hello.packet.client_addr());
+    }
+}
+
+fn list_transfer_destinations(
+    links: Query<&PeerLink>,
+    mut destinations: ResMut<TransferDestinations>,
+) {
+    destinations.0.clear();
+    destinations
+        .0
+        .extend(links.iter().filter_map(PeerLink::client_addr));
+}
+
+fn hand_off_players(
+    mut issued: EventReader<TicketIssued>,
+    links: Query<(&Network<Peer>, &PeerLink)>,
+) {
+    for ticket in issued.iter() {
+        let Some((peer, _)) = links
+            .iter()
+            .find(|(_, link)| link.client_addr == Some(ticket.addr))
+        else {
+            error!("No peer link to {} to hand off ticket to", ticket.addr);
+            continue;
+        };
+
+        if let Err(e) = peer.send(HandoffPlayer {
+            ticket: ticket.ticket,
+            x: ticket.position.x,
+            y: ticket.position.y,
+            account: ticket.account.clone(),
+        }) {
+            error!("Failed to hand off player to {}: {}", ticket.addr, e);
+        }
+    }
+}
+
+fn expect_handoffs(
+    packets: Res<Packets<PacketWithConnId<HandoffPlayer>>>,
+    mut tickets: ResMut<TransferTickets>,
+) {
+    for handoff in packets.iter() {
+        let HandoffPlayer {
+            ticket,
+            x,
+            y,
+            ref account,
+        } = handoff.packet;
+        tickets.expect(ticket, Position { x, y }, account.clone());
+    }
+}
+
+fn share_presence(
+    players: Query<(), With<Player>>,
+    links: Query<&Network<Peer>>,
+    new_links: Query<&Network<Peer>, Added<PeerLink>>,
+    mut shared: Local<Option<u32>>,
+) {
+    let presence = Presence {
+        players: players.iter().count() as u32,
+    };
+
+    let result = if *shared == Some(presence.players) {
+        Network::send_all(new_links.iter(), presence)
+    } else {
+        *shared = Some(presence.players);
+        Network::send_all(links.iter(), presence)
+    };
+
+    if let Err(e) = result {
+        error!("Failed to share presence: {}", e);
+    }
+}
+
+fn receive_presence(
+    packets: Res<Packets<PacketWithConnId<Presence>>>,
+    network_to_world: Res<NetworkToWorld<Peer>>,
+    mut links: Query<&mut PeerLink>,
+) {
+    for presence in packets.iter() {
+        let Some(mut link) = network_to_world
+            .get(&presence.connection_id)
+            .and_then(|&entity| links.get_mut(entity).ok())
+        else {
+            continue;
+        };
+
+        link.players = presence.packet.players;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::{Entity, With};
+
+    use super::*;
+    use crate::{
+        network::{
+            harness::Harness,
+            plugin::{NetworkSettings, Server},
+            test_utils::next_local_addr,
+            transfer::{PendingTransfer, TransferClient},
+        },
+        path::packet::PathTargetRequest,
+    };
+
+    fn link(server: &mut App, listen: SocketAddr, root: &rcgen::Certificate) {
+        let client_addr = server.world.resource::<NetworkSettings>().addr;
+        server.add_plugin(PeerPlugin {
+            listen,
+            client_addr,
+            identity: PeerIdentity::generate(root).unwrap(),
+        });
+    }
+
+    /// Steps until the first server knows where the clients of the peer with
+    /// `client_addr` connect to.
+    fn wait_for_link(harness: &mut Harness, client_addr: SocketAddr) {
+        harness.step_until("the servers greeted each other", |harness| {
+            let world = &mut harness.server.world;
+            world
+                .query::<&PeerLink>()
+                .iter(world)
+                .any(|link| link.client_addr() == Some(client_addr))
+        });
+    }
+
+    fn players(server: &mut App) -> Vec<Position> {
+        let world = &mut server.world;
+        world
+            .query_filtered::<&Position, With<Player>>()
+            .iter(world)
+            .copied()
+            .collect()
+    }
+
+    #[test]
+    fn generated_identity_builds_mutual_tls_configs() {
+        let root = generate_cluster_root().unwrap();
+        let identity = PeerIdentity::generate(&root).unwrap();
+
+        assert!(identity.server_crypto().is_ok());
+        assert!(identity.client_crypto().is_ok());
+    }
+
+    #[test]
+    fn invalid_root_is_rejected() {
+        let identity = PeerIdentity::new(vec![0; 8], vec![], vec![]);
+
+        assert!(matches!(
+            identity.roots(),
+            Err(Error::InvalidCertificate(_))
+        ));
+    }
+
+    #[test]
+    fn players_are_handed_off_over_a_mutual_tls_link() {
+        let root = generate_cluster_root().unwrap();
+        let origin_listen = next_local_addr().parse().unwrap();
+        let mut harness = Harness::with_server(1, |server| link(server, origin_listen, &root));
+        let peer = harness.add_peer(|server| {
+            link(server, next_local_addr().parse().unwrap(), &root);
+            server.world.send_event(DialPeer {
+                addr: origin_listen,
+            });
+        });
+        harness.wait_for_players();
+        let destination = harness.peers[peer].world.resource::<NetworkSettings>().addr;
+        wait_for_link(&mut harness, destination);
+
+        let id = harness.client_id(0).unwrap();
+        let entity = harness.server.world.resource::<NetworkToWorld<Server>>()[&id];
+        let position = Position { x: 3, y: -2 };
+        harness.server.world.entity_mut(entity).insert(position);
+        harness.server.world.send_event(TransferClient {
+            entity,
+            addr: destination,
+        });
+
+        harness.step_until("the player moved to the peer", |harness| {
+            players(&mut harness.server).is_empty()
+                && players(&mut harness.peers[peer]) == [position]
+                && !harness.clients[0]
+                    .world
+                    .contains_resource::<PendingTransfer>()
+        });
+        let client = &mut harness.clients[0].world;
+        let servers = client
+            .query_filtered::<Entity, With<Network<Server>>>()
+            .iter(client)
+            .count();
+        assert_eq!(servers, 1);
+    }
+
+    #[test]
+    fn clients_keep_playing_when_the_peer_cannot_be_reached() {
+        let root = generate_cluster_root().unwrap();
+        let origin_listen = next_local_addr().parse().unwrap();
+        let mut harness = Harness::with_server(1, |server| link(server, origin_listen, &root));
+        // nothing can be connected to on port 0
+        let unreachable: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        harness.add_peer(|server| {
+            server.add_plugin(PeerPlugin {
+                listen: next_local_addr().parse().unwrap(),
+                client_addr: unreachable,
+                identity: PeerIdentity::generate(&root).unwrap(),
+            });
+            server.world.send_event(DialPeer {
+                addr: origin_listen,
+            });
+        });
+        harness.wait_for_players();
+        wait_for_link(&mut harness, unreachable);
+
+        let id = harness.client_id(0).unwrap();
+        let entity = harness.server.world.resource::<NetworkToWorld<Server>>()[&id];
+        harness.server.world.send_event(TransferClient {
+            entity,
+            addr: unreachable,
+        });
+        harness.step_until("the client set off", |harness| {
+            harness.clients[0]
+                .world
+                .contains_resource::<PendingTransfer>()
+        });
+        harness.step_until("the transfer failed", |harness| {
+            !harness.clients[0]
+                .world
+                .contains_resource::<PendingTransfer>()
+        });
+
+        harness.send_from_client(0, PathTargetRequest { x: 2, y: 0 });
+        harness.wait_until_client_sees(0, id, Position { x: 2, y: 0 });
+    }
+}
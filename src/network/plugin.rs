@@ -1,30 +1,58 @@
-use std::{marker::PhantomData, net::SocketAddr, sync::Arc};
+use std::{marker::PhantomData, net::SocketAddr, sync::Arc, time::Duration};
 
 use bevy::{
     prelude::{
-        App, Commands, Component, Entity, EventWriter, Local, Plugin, Query, Res, ResMut, Resource,
-        With,
+        App, Commands, Component, CoreStage, Entity, EventReader, EventWriter,
+        IntoSystemDescriptor, Local, Plugin, Query, Res, ResMut, Resource, With, Without,
     },
     tasks::{IoTaskPool, Task},
 };
 use crossbeam_channel::{Receiver, Sender};
-use tracing::info;
+use tracing::{error, info};
 
 use super::{
     accept::{QuicConnector, QuicListener},
+    budget::{send_pending_updates, BandwidthSettings, PendingUpdates},
     connection::Connection,
-    error::Result,
-    mediator::{AnyPacketMediator, PacketSenderMap, PacketWithConnId},
-    packet::{AcceptConnection, ClientPacket, EncodedPacket, Packet, ServerPacket},
+    error::{Error, Result},
+    event::{
+        Connected, Connecting, ConnectionFailed, DisconnectReason, Disconnected, NetworkError,
+        Queued, Reconnecting,
+    },
+    guard::{Account, ConnectionGuard, GuardSettings},
+    mediator::{log_packets, AnyPacketMediator, Middleware, PacketSenderMap, PacketWithConnId},
+    metrics::ServerMetrics,
+    packet::{
+        AbortTransfer, AcceptConnection, AckSnapshot, ClientPacket, EncodedPacket, Join, Packet,
+        PeerPacket, PresentTicket, QueuePosition, RemoveComponent, ReplicateComponent, Role,
+        ServerPacket, Snapshot, Spectate, Transfer,
+    },
+    queue::{admit_players, raise_queue_positions, CapacitySettings, LoginQueue},
+    replicate::{receive_replicated_components, ReplicationRegistry},
+    snapshot::{
+        apply_snapshots, queue_snapshots, receive_snapshot_acks, ReceivedSnapshots, SnapshotHistory,
+    },
+    spectate::{
+        follow_viewpoints, move_spectators, reject_gameplay_packets, track_spectators, Spectating,
+        SpectatorIds, Viewpoint,
+    },
+    state::{
+        forget_connection_states, reject_out_of_state_packets, ConnectionState, ConnectionStates,
+    },
     task::{accept::AcceptConnectionsTask, recv::ReceivePacketsTask, send::SendPacketsTask},
+    transfer::{
+        abort_transfer, begin_transfer, issue_tickets, receive_aborted_transfers, redeem_tickets,
+        PendingTransfer, TicketIssued, TransferClient, TransferDestinations, TransferFailed,
+        TransferTickets, Transferring,
+    },
 };
 use crate::{
     ambit::{
         packet::{DespawnEntity, QueryEntity, SpawnEntity},
-        plugin::Player,
+        plugin::{Player, VisibleEntities},
     },
     channel::BroadcastChannel,
-    chat::packet::SendMessage,
+    chat::packet::{MessageReceived, SendMessage},
     id::{NetworkId, NetworkToWorld},
     path::{
         packet::{PathTarget, PathTargetRequest},
@@ -33,70 +61,210 @@ use crate::{
     stat::MovementSpeed,
 };
 
+const SERVER_ADDR: &str = "127.0.0.1:56565";
+
 // plugins
-pub struct NetworkPlugin;
+pub struct NetworkPlugin {
+    /// Address the server listens on and the client connects to.
+    pub addr: SocketAddr,
+    /// Player limit of the server, unused by the client.
+    pub capacity: CapacitySettings,
+    /// Limits on incoming connections to the server, unused by the client.
+    pub guard: GuardSettings,
+    /// Entity updates the server sends each client, unused by the client.
+    pub bandwidth: BandwidthSettings,
+    /// Joins as a spectator of this viewpoint instead of as a player, unused
+    /// by the server.
+    pub spectate: Option<Viewpoint>,
+    /// Account to join under, unused by the server.
+    pub account: Option<Account>,
+}
+
+impl Default for NetworkPlugin {
+    fn default() -> Self {
+        Self {
+            addr: SERVER_ADDR.parse().unwrap(),
+            capacity: CapacitySettings::default(),
+            guard: GuardSettings::default(),
+            bandwidth: BandwidthSettings::default(),
+            spectate: None,
+            account: None,
+        }
+    }
+}
 
 impl Plugin for NetworkPlugin {
     fn build(&self, app: &mut App) {
-        app.add_startup_system(spawn_accept_task);
-        app.add_event::<NewConnection>();
-        app.init_resource::<Quit>();
+        app.add_plugin(BaseNetworkPlugin { addr: self.addr });
 
         #[cfg(feature = "server")]
         {
-            app.init_resource::<Disconnections<Server>>();
-            app.init_resource::<NetworkToWorld<Server>>();
-            app.add_system(spawn_new_client_connections);
-            app.add_system(despawn_disconnections::<Server>);
-            app.add_system(raise_query_entity_events);
-
-            app.init_resource::<PacketSenderMap<ClientPacket>>();
-
-            app.add_packet::<PacketWithConnId<SendMessage>, ClientPacket>();
-            app.add_packet::<PacketWithConnId<PathTargetRequest>, ClientPacket>();
-            app.add_packet::<PacketWithConnId<QueryEntity>, ClientPacket>();
-            app.add_event::<EntityQuery>();
-
-            let packet_map = app
-                .world
-                .remove_resource::<PacketSenderMap<ClientPacket>>()
-                .unwrap();
-
-            app.insert_resource(AnyPacketMediator::new(Arc::new(packet_map)));
+            app.insert_resource(self.capacity.clone());
+            app.insert_resource(self.guard.clone());
+            app.insert_resource(self.bandwidth.clone());
+            app.add_plugin(ServerNetworkPlugin);
         }
 
         #[cfg(feature = "client")]
         {
-            app.init_resource::<Disconnections<Client>>();
-            app.init_resource::<NetworkToWorld<Client>>();
-            app.add_system(spawn_server);
-            app.add_system(despawn_disconnections::<Client>);
-            app.add_system(connect_to_server);
-            app.add_system(spawn_self);
-            app.init_resource::<PacketSenderMap<ServerPacket>>();
-
-            app.add_packet::<SpawnEntity, ServerPacket>();
-            app.add_packet::<DespawnEntity, ServerPacket>();
-            app.add_packet::<PathTarget, ServerPacket>();
-            app.add_packet::<AcceptConnection, ServerPacket>();
-
-            let packet_map = app
-                .world
-                .remove_resource::<PacketSenderMap<ServerPacket>>()
-                .unwrap();
-
-            app.insert_resource(AnyPacketMediator::new(Arc::new(packet_map)));
+            if let Some(viewpoint) = self.spectate {
+                app.insert_resource(Spectating(viewpoint));
+            }
+            if let Some(account) = &self.account {
+                app.insert_resource(account.clone());
+            }
+            app.add_plugin(ClientNetworkPlugin);
         }
     }
 }
 
-trait AddPacketAppExt {
+pub(crate) struct BaseNetworkPlugin {
+    pub(crate) addr: SocketAddr,
+}
+
+pub(crate) struct ServerNetworkPlugin;
+
+pub(crate) struct ClientNetworkPlugin;
+
+impl Plugin for BaseNetworkPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(NetworkSettings { addr: self.addr });
+        app.add_event::<Connecting>();
+        app.add_event::<Connected>();
+        app.add_event::<ConnectionFailed>();
+        app.add_event::<Reconnecting>();
+        app.add_event::<Disconnected>();
+        app.add_event::<NetworkError>();
+        app.init_resource::<Quit>();
+        app.init_resource::<NetworkErrors>();
+        app.add_system(raise_network_errors);
+    }
+}
+
+impl Plugin for ServerNetworkPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_startup_system(spawn_listener_task);
+        app.init_resource::<Disconnections<Server>>();
+        app.init_resource::<NetworkToWorld<Server>>();
+        app.init_resource::<TransferTickets>();
+        app.init_resource::<TransferDestinations>();
+        app.init_resource::<CapacitySettings>();
+        app.init_resource::<GuardSettings>();
+        app.init_resource::<BandwidthSettings>();
+        app.init_resource::<LoginQueue>();
+        app.init_resource::<ServerMetrics>();
+        app.add_system(spawn_new_client_connections.label("spawn_clients"));
+        // packets are only handled once the connection entity has been spawned
+        app.add_system(admit_players.before("spawn_clients"));
+        app.add_system(despawn_disconnections::<Server>);
+        app.add_system(raise_connection_failures::<Server>);
+        app.add_system(raise_query_entity_events);
+        app.add_system(issue_tickets.label("issue_tickets"));
+        app.add_system(redeem_tickets.before("spawn_clients"));
+        app.add_system(receive_aborted_transfers);
+        app.add_system(receive_snapshot_acks);
+        app.add_system(move_spectators);
+        app.add_system(track_spectators);
+        app.add_system(forget_connection_states);
+        app.add_system(follow_viewpoints.after("set_position").label("viewpoints"));
+        // after every system that queues updates
+        app.add_system_to_stage(
+            CoreStage::PostUpdate,
+            queue_snapshots.before("send_updates"),
+        );
+        app.add_system_to_stage(
+            CoreStage::PostUpdate,
+            send_pending_updates.label("send_updates"),
+        );
+
+        app.init_resource::<PacketSenderMap<ClientPacket>>();
+
+        app.add_packet::<PacketWithConnId<SendMessage>, ClientPacket>();
+        app.add_packet::<PacketWithConnId<PathTargetRequest>, ClientPacket>();
+        app.add_packet::<PacketWithConnId<QueryEntity>, ClientPacket>();
+        app.add_packet::<PacketWithConnId<Join>, ClientPacket>();
+        app.add_packet::<PacketWithConnId<PresentTicket>, ClientPacket>();
+        app.add_packet::<PacketWithConnId<AckSnapshot>, ClientPacket>();
+        app.add_packet::<PacketWithConnId<Spectate>, ClientPacket>();
+        app.add_packet::<PacketWithConnId<AbortTransfer>, ClientPacket>();
+        app.add_event::<EntityQuery>();
+        app.add_event::<TransferClient>();
+        app.add_event::<TicketIssued>();
+        app.add_event::<TransferFailed>();
+
+        let packet_map = app
+            .world
+            .remove_resource::<PacketSenderMap<ClientPacket>>()
+            .unwrap();
+
+        app.insert_resource(AnyPacketMediator::new(Arc::new(packet_map)));
+        app.add_middleware(log_packets::<ClientPacket>);
+        let states = ConnectionStates::default();
+        app.add_middleware(reject_out_of_state_packets(states.clone()));
+        app.insert_resource(states);
+        let spectators = SpectatorIds::default();
+        app.add_middleware(reject_gameplay_packets(spectators.clone()));
+        app.insert_resource(spectators);
+    }
+}
+
+impl Plugin for ClientNetworkPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_startup_system(spawn_connector_task);
+        app.init_resource::<Disconnections<Client>>();
+        app.init_resource::<NetworkToWorld<Client>>();
+        app.add_system(spawn_server);
+        app.add_system(despawn_disconnections::<Client>);
+        app.add_system(raise_connection_failures::<Client>);
+        app.add_system(connect_to_server);
+        app.add_system(spawn_self.label("spawn_self"));
+        app.add_system(begin_transfer);
+        app.add_system(abort_transfer);
+        app.add_system(raise_queue_positions);
+        app.init_resource::<ReceivedSnapshots>();
+        // snapshots are newer than any path target received along with them
+        app.add_system(apply_snapshots.after("path_targets"));
+        app.add_event::<Queued>();
+        app.init_resource::<PacketSenderMap<ServerPacket>>();
+
+        app.add_packet::<SpawnEntity, ServerPacket>();
+        app.add_packet::<DespawnEntity, ServerPacket>();
+        app.add_packet::<PathTarget, ServerPacket>();
+        app.add_packet::<AcceptConnection, ServerPacket>();
+        app.add_packet::<Transfer, ServerPacket>();
+        app.add_packet::<QueuePosition, ServerPacket>();
+        app.add_packet::<Snapshot, ServerPacket>();
+        app.add_packet::<MessageReceived, ServerPacket>();
+        app.add_packet::<ReplicateComponent, ServerPacket>();
+        app.add_packet::<RemoveComponent, ServerPacket>();
+        app.init_resource::<ReplicationRegistry>();
+        app.add_system(receive_replicated_components.after("spawn_entities"));
+
+        let packet_map = app
+            .world
+            .remove_resource::<PacketSenderMap<ServerPacket>>()
+            .unwrap();
+
+        app.insert_resource(AnyPacketMediator::new(Arc::new(packet_map)));
+        app.add_middleware(log_packets::<ServerPacket>);
+    }
+}
+
+pub(crate) trait AddPacketAppExt {
     fn add_packet<T, P>(&mut self)
     where
         T: Send + Sync + 'static,
         P: Packet,
         P::Sender: TryFrom<Sender<T>>,
         P::Kind: for<'a> From<&'a P::Sender>;
+
+    /// Runs `middleware` on every `P` received, after the middleware added
+    /// before it. Only possible once the network plugin that receives `P` has
+    /// been added.
+    fn add_middleware<P, M>(&mut self, middleware: M)
+    where
+        P: Packet,
+        M: Middleware<P>;
 }
 
 impl AddPacketAppExt for App {
@@ -112,6 +280,17 @@ impl AddPacketAppExt for App {
         packet_map.add(tx);
         self.insert_resource(Packets { receiver: rx });
     }
+
+    fn add_middleware<P, M>(&mut self, middleware: M)
+    where
+        P: Packet,
+        M: Middleware<P>,
+    {
+        self.world
+            .get_resource::<AnyPacketMediator<P>>()
+            .unwrap()
+            .add_middleware(middleware);
+    }
 }
 
 // resources
@@ -130,24 +309,29 @@ impl<P> Packets<P> {
     }
 }
 
+#[derive(Resource)]
+pub(crate) struct NetworkSettings {
+    pub(crate) addr: SocketAddr,
+}
+
 #[derive(Resource)]
 pub(crate) struct Quit {
-    sender: async_std::channel::Sender<()>,
-    receiver: async_std::channel::Receiver<()>,
+    sender: async_channel::Sender<()>,
+    pub(super) receiver: async_channel::Receiver<()>,
 }
 impl Default for Quit {
     fn default() -> Self {
-        let (sender, receiver) = async_std::channel::bounded(1);
+        let (sender, receiver) = async_channel::bounded(1);
         Self { receiver, sender }
     }
 }
 
 #[derive(Resource)]
-struct ConnectionReceiver<S>
+pub(super) struct ConnectionReceiver<S>
 where
     S: Send + Sync + 'static,
 {
-    receiver: Receiver<Connection<quinn::Connection>>,
+    pub(super) receiver: Receiver<Connection<quinn::Connection>>,
     marker: PhantomData<S>,
 }
 
@@ -155,21 +339,42 @@ impl<S> ConnectionReceiver<S>
 where
     S: Send + Sync + 'static,
 {
-    fn new(receiver: Receiver<Connection<quinn::Connection>>) -> Self {
+    pub(super) fn new(receiver: Receiver<Connection<quinn::Connection>>) -> Self {
         Self {
             receiver,
-            marker: PhantomData::default(),
+            marker: PhantomData,
+        }
+    }
+}
+
+#[derive(Resource)]
+pub(super) struct ConnectionFailures<S>
+where
+    S: Send + Sync + 'static,
+{
+    receiver: Receiver<ConnectionFailed>,
+    marker: PhantomData<S>,
+}
+
+impl<S> ConnectionFailures<S>
+where
+    S: Send + Sync + 'static,
+{
+    pub(super) fn new(receiver: Receiver<ConnectionFailed>) -> Self {
+        Self {
+            receiver,
+            marker: PhantomData,
         }
     }
 }
 
 #[derive(Resource)]
-struct Disconnections<S>
+pub(super) struct Disconnections<S>
 where
     S: Send + Sync + 'static,
 {
-    receiver: Receiver<NetworkId>,
-    sender: Sender<NetworkId>,
+    receiver: Receiver<(NetworkId, DisconnectReason)>,
+    sender: Sender<(NetworkId, DisconnectReason)>,
     marker: PhantomData<S>,
 }
 
@@ -188,10 +393,23 @@ where
 }
 
 #[derive(Resource)]
-struct ConnectionRequester(async_std::channel::Sender<SocketAddr>);
+pub(super) struct NetworkErrors {
+    receiver: Receiver<NetworkError>,
+    pub(super) sender: Sender<NetworkError>,
+}
+
+impl Default for NetworkErrors {
+    fn default() -> Self {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        Self { receiver, sender }
+    }
+}
 
 #[derive(Resource)]
-struct AcceptTask<S>
+pub(super) struct ConnectionRequester(pub(super) async_channel::Sender<SocketAddr>);
+
+#[derive(Resource)]
+pub(super) struct AcceptTask<S>
 where
     S: Send + Sync + 'static,
 {
@@ -203,7 +421,7 @@ impl<S> AcceptTask<S>
 where
     S: Send + Sync + 'static,
 {
-    fn new(task: Task<()>) -> Self {
+    pub(super) fn new(task: Task<()>) -> Self {
         Self {
             task,
             marker: PhantomData::default(),
@@ -217,7 +435,8 @@ pub(crate) struct Network<S>
 where
     S: Service,
 {
-    sender: async_std::channel::Sender<EncodedPacket>,
+    id: NetworkId,
+    sender: async_channel::Sender<EncodedPacket>,
     marker: PhantomData<S>,
 }
 
@@ -225,13 +444,18 @@ impl<S> Network<S>
 where
     S: Service,
 {
-    fn new(sender: async_std::channel::Sender<EncodedPacket>) -> Self {
+    pub(super) fn new(id: NetworkId, sender: async_channel::Sender<EncodedPacket>) -> Self {
         Self {
+            id,
             sender,
-            marker: PhantomData::default(),
+            marker: PhantomData,
         }
     }
 
+    pub(crate) fn id(&self) -> NetworkId {
+        self.id
+    }
+
     pub(crate) fn send<T>(&self, packet: T) -> Result<()>
     where
         S::Packet: From<T>,
@@ -257,27 +481,50 @@ where
         Ok(())
     }
 
-    fn send_encoded(&self, packet: EncodedPacket) {
-        let sender = self.sender.clone();
-
-        IoTaskPool::get()
-            .spawn(async move {
-                #[cfg(feature = "lag")]
-                async_std::task::sleep(std::time::Duration::from_millis(100)).await;
+    pub(super) fn send_encoded(&self, packet: EncodedPacket) {
+        #[cfg(feature = "lag")]
+        {
+            let sender = self.sender.clone();
+            IoTaskPool::get()
+                .spawn(async move {
+                    super::runtime::sleep(std::time::Duration::from_millis(100)).await;
+                    let _ = sender.send(packet).await;
+                })
+                .detach();
+        }
 
-                let _ = sender.send(packet).await;
-            })
-            .detach();
+        // the channel is unbounded, so this only fails once the connection closed
+        #[cfg(not(feature = "lag"))]
+        let _ = self.sender.try_send(packet);
     }
 }
 
-// events
-struct NewConnection {
-    id: NetworkId,
+/// Where a client connected from.
+#[derive(Component, Clone, Copy, Debug)]
+pub(crate) struct RemoteAddr(pub(crate) SocketAddr);
+
+/// The QUIC connection of a client, for what does not go through its packet
+/// tasks.
+#[derive(Component, Clone)]
+pub(crate) struct ClientConnection(quinn::Connection);
+
+impl ClientConnection {
+    pub(crate) fn rtt(&self) -> Duration {
+        self.0.rtt()
+    }
+
+    /// Closes the connection, telling the client why. The entity is despawned
+    /// once the packet tasks notice.
+    pub(crate) fn close(&self, reason: &str) {
+        self.0.close(0u32.into(), reason.as_bytes());
+    }
 }
 
+// events
+/// A client asking for the state of an entity.
 pub(crate) struct EntityQuery {
     pub(crate) entity: Entity,
+    /// The client that asked.
     pub(crate) querier: Entity,
 }
 
@@ -301,75 +548,146 @@ impl Service for Client {
     type Packet = ServerPacket;
 }
 
+#[derive(Component, Default)]
+pub(crate) struct Peer;
+
+impl Service for Peer {
+    type Other = Peer;
+    type Packet = PeerPacket;
+}
+
 pub(crate) trait Service: Send + Sync + 'static + Component {
     type Packet: Packet;
     type Other: Service;
 }
 
 // systems
-fn spawn_accept_task(mut commands: Commands, quit: Res<Quit>) {
+fn spawn_connector_task(mut commands: Commands, quit: Res<Quit>, errors: Res<NetworkErrors>) {
     let io_pool = IoTaskPool::get();
 
-    #[cfg(feature = "client")]
-    {
-        let (connect_to_tx, connect_to_rx) = async_std::channel::bounded(1);
-        commands.insert_resource(ConnectionRequester(connect_to_tx));
-
-        let (new_connections_tx, new_connections_rx) = crossbeam_channel::unbounded();
-        commands.insert_resource(ConnectionReceiver::<Client>::new(new_connections_rx));
-        let stop = quit.receiver.clone();
-
-        let task = io_pool.spawn(async move {
-            let connector = QuicConnector::new(connect_to_rx);
-            AcceptConnectionsTask::new(connector, new_connections_tx)
-                ._run(stop)
-                .await;
-        });
-        commands.insert_resource(AcceptTask::<Client>::new(task));
-    }
+    let (connect_to_tx, connect_to_rx) = async_channel::bounded(1);
+    commands.insert_resource(ConnectionRequester(connect_to_tx));
 
-    #[cfg(feature = "server")]
-    {
-        let (new_connections_tx, new_connections_rx) = crossbeam_channel::unbounded();
-        commands.insert_resource(ConnectionReceiver::<Server>::new(new_connections_rx));
-        let stop = quit.receiver.clone();
-
-        let task = io_pool.spawn(async move {
-            let listener = QuicListener::new("127.0.0.1:56565".parse().unwrap());
-            AcceptConnectionsTask::new(listener, new_connections_tx)
-                ._run(stop)
-                .await;
-        });
+    let (new_connections_tx, new_connections_rx) = crossbeam_channel::unbounded();
+    commands.insert_resource(ConnectionReceiver::<Client>::new(new_connections_rx));
+    let (failures_tx, failures_rx) = crossbeam_channel::unbounded();
+    commands.insert_resource(ConnectionFailures::<Client>::new(failures_rx));
+    let stop = quit.receiver.clone();
+    let errors = errors.sender.clone();
+
+    let task = io_pool.spawn(async move {
+        let connector = match QuicConnector::new(connect_to_rx, failures_tx) {
+            Ok(connector) => connector,
+            Err(error) => {
+                error!("Failed to create connector: {}", error);
+                let _ = errors.send(NetworkError { id: None, error });
+                return;
+            }
+        };
+        AcceptConnectionsTask::new(connector, new_connections_tx)
+            ._run(stop)
+            .await;
+    });
+    commands.insert_resource(AcceptTask::<Client>::new(task));
+}
 
-        commands.insert_resource(AcceptTask::<Server>::new(task));
-    }
+fn spawn_listener_task(
+    mut commands: Commands,
+    settings: Res<NetworkSettings>,
+    guard_settings: Res<GuardSettings>,
+    quit: Res<Quit>,
+    errors: Res<NetworkErrors>,
+) {
+    let io_pool = IoTaskPool::get();
+
+    let (new_connections_tx, new_connections_rx) = crossbeam_channel::unbounded();
+    commands.insert_resource(ConnectionReceiver::<Server>::new(new_connections_rx));
+    let (failures_tx, failures_rx) = crossbeam_channel::unbounded();
+    commands.insert_resource(ConnectionFailures::<Server>::new(failures_rx));
+    let stop = quit.receiver.clone();
+
+    // bind right away so the server is reachable once startup has run
+    // refuse to listen without the ban list rather than let banned clients in
+    let listener = match ConnectionGuard::load(&guard_settings).and_then(|guard| {
+        commands.insert_resource(guard.clone());
+        QuicListener::new(settings.addr, failures_tx, guard)
+    }) {
+        Ok(listener) => listener,
+        Err(error) => {
+            error!("Failed to create listener: {}", error);
+            let _ = errors.sender.send(NetworkError { id: None, error });
+            return;
+        }
+    };
+
+    let task = io_pool.spawn(async move {
+        AcceptConnectionsTask::new(listener, new_connections_tx)
+            ._run(stop)
+            .await;
+    });
+
+    commands.insert_resource(AcceptTask::<Server>::new(task));
 }
 
+#[allow(clippy::too_many_arguments)]
 fn connect_to_server(
+    settings: Res<NetworkSettings>,
     connection_requester: Res<ConnectionRequester>,
     query: Query<&Network<Server>>,
+    mut failures: EventReader<ConnectionFailed>,
+    mut connecting: EventWriter<Connecting>,
+    mut reconnecting: EventWriter<Reconnecting>,
     mut requested: Local<bool>,
+    mut attempted: Local<bool>,
 ) {
+    if failures.iter().count() > 0 {
+        *requested = false;
+    }
+
     if query.is_empty() {
         if *requested {
             return;
         }
 
-        let _ = connection_requester
-            .0
-            .send_blocking("127.0.0.1:56565".parse().unwrap());
+        let addr = settings.addr;
+        let _ = connection_requester.0.send_blocking(addr);
+        if *attempted {
+            reconnecting.send(Reconnecting { addr });
+        } else {
+            connecting.send(Connecting { addr });
+        }
         *requested = true;
+        *attempted = true;
     } else {
         *requested = false;
     }
 }
 
+fn raise_network_errors(errors: Res<NetworkErrors>, mut network_errors: EventWriter<NetworkError>) {
+    network_errors.send_batch(errors.receiver.try_iter());
+}
+
+pub(super) fn raise_connection_failures<S>(
+    failures: Res<ConnectionFailures<S>>,
+    mut connection_failed: EventWriter<ConnectionFailed>,
+) where
+    S: Send + Sync + 'static,
+{
+    connection_failed.send_batch(failures.receiver.try_iter());
+}
+
+#[allow(clippy::too_many_arguments)]
 fn spawn_server(
     mut commands: Commands,
+    mut connected: EventWriter<Connected>,
     conn_receiver: Res<ConnectionReceiver<Client>>,
     disconnections: Res<Disconnections<Client>>,
+    errors: Res<NetworkErrors>,
     packet_mediator: Res<AnyPacketMediator<ServerPacket>>,
-    server: Query<Entity, With<Network<Server>>>,
+    server: Query<Entity, (With<Network<Server>>, Without<Transferring>)>,
+    mut pending_transfer: Option<ResMut<PendingTransfer>>,
+    spectating: Option<Res<Spectating>>,
+    account: Option<Res<Account>>,
     quit: Res<Quit>,
 ) {
     if conn_receiver.receiver.is_empty() {
@@ -380,25 +698,62 @@ fn spawn_server(
 
     for connection in conn_receiver.receiver.try_iter() {
         info!("{:?}", *packet_mediator);
-        let sender =
-            spawn_connection_tasks(&disconnections, pool, &packet_mediator, &quit, connection);
-
-        commands.spawn(Network::<Server>::new(sender));
+        let conn_id = connection.connection_id();
+        let sender = spawn_connection_tasks(
+            &disconnections,
+            &errors,
+            pool,
+            &packet_mediator,
+            &quit,
+            connection,
+        );
+
+        let network = Network::<Server>::new(conn_id, sender);
+
+        let entity = match pending_transfer.as_mut() {
+            // the current server is kept until the new one accepts the ticket
+            Some(pending) => {
+                let _ = network.send(PresentTicket {
+                    ticket: pending.ticket(),
+                });
+                let entity = commands.spawn((network, Transferring)).id();
+                pending.connection = Some(entity);
+                entity
+            }
+            None => {
+                let role = match spectating {
+                    Some(ref spectating) => Role::Spectator(spectating.0),
+                    None => Role::Player,
+                };
+                let _ = network.send(Join {
+                    role,
+                    account: account.as_deref().cloned(),
+                });
+                if let Ok(entity) = server.get_single() {
+                    commands.entity(entity).despawn();
+                }
+                commands.spawn(network).id()
+            }
+        };
 
-        if let Ok(entity) = server.get_single() {
-            commands.entity(entity).despawn();
-        }
+        connected.send(Connected {
+            id: conn_id,
+            entity,
+        });
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn spawn_new_client_connections(
     mut commands: Commands,
     mut network_to_world: ResMut<NetworkToWorld<Server>>,
-    mut new_connections: EventWriter<NewConnection>,
+    mut connected: EventWriter<Connected>,
     conn_receiver: Res<ConnectionReceiver<Server>>,
     disconnections: Res<Disconnections<Server>>,
+    errors: Res<NetworkErrors>,
     packet_mediator: Res<AnyPacketMediator<ClientPacket>>,
     quit: Res<Quit>,
+    states: Res<ConnectionStates>,
 ) {
     if conn_receiver.receiver.is_empty() {
         return;
@@ -408,93 +763,190 @@ fn spawn_new_client_connections(
 
     for connection in conn_receiver.receiver.try_iter() {
         let conn_id = connection.connection_id();
-
-        let sender =
-            spawn_connection_tasks(&disconnections, pool, &packet_mediator, &quit, connection);
-        let network = Network::<Client>::new(sender);
-
-        let _ = network.send(AcceptConnection {
-            connection_id: conn_id,
-        });
+        let addr = RemoteAddr(connection.value.remote_address());
+        let quic = ClientConnection(connection.value.clone());
+        // before any packet can be received
+        states.set(conn_id, ConnectionState::Handshaking);
+
+        let sender = spawn_connection_tasks(
+            &disconnections,
+            &errors,
+            pool,
+            &packet_mediator,
+            &quit,
+            connection,
+        );
+        let network = Network::<Client>::new(conn_id, sender);
 
         let entity = commands
             .spawn((
                 conn_id,
                 network,
-                Position { x: 0, y: 0 },
-                MovementSpeed(3),
-                Path::default(),
-                Player,
-                MaybeNextPosition::default(),
+                addr,
+                quic,
+                PendingUpdates::default(),
+                VisibleEntities::default(),
+                SnapshotHistory::default(),
             ))
             .id();
 
         info!("creating network entity: {}", conn_id);
         network_to_world.insert(conn_id, entity);
-        new_connections.send(NewConnection { id: conn_id });
+        connected.send(Connected {
+            id: conn_id,
+            entity,
+        });
     }
 }
 
-fn spawn_connection_tasks<'d, S>(
+pub(super) fn spawn_player(
+    commands: &mut Commands,
+    states: &ConnectionStates,
+    entity: Entity,
+    client: &Network<Client>,
+    position: Position,
+) {
+    // before the client learns it may play
+    states.set(client.id(), ConnectionState::InGame);
+    let _ = client.send(AcceptConnection {
+        connection_id: client.id(),
+    });
+
+    commands.entity(entity).insert((
+        position,
+        MovementSpeed(3),
+        Path::default(),
+        Player,
+        MaybeNextPosition::default(),
+    ));
+}
+
+pub(super) fn spawn_connection_tasks<S>(
     disconnections: &Disconnections<S>,
+    errors: &NetworkErrors,
     pool: &IoTaskPool,
     packet_mediator: &AnyPacketMediator<<S as Service>::Packet>,
     quit: &Quit,
-    connection: Connection<quinn::Connection>,
-) -> async_std::channel::Sender<EncodedPacket>
+    mut connection: Connection<quinn::Connection>,
+) -> async_channel::Sender<EncodedPacket>
 where
     S: Send + Sync + 'static + Service,
     <S::Packet as Packet>::Kind: for<'r> From<&'r S::Packet>,
-    S::Packet: speedy::Readable<'d, speedy::LittleEndian>,
+    S::Packet: for<'a> speedy::Readable<'a, speedy::LittleEndian>,
 {
     let conn_id = connection.connection_id();
-    let broadcast_disconnect = BroadcastChannel::channel();
-    let (sender, receiver) = async_std::channel::unbounded();
-    let disconnect = broadcast_disconnect.notified.clone();
+    let broadcast_disconnect = BroadcastChannel::<()>::channel();
+    let (sender, receiver) = async_channel::unbounded();
+    // whichever task stops first records why before waking the other one
+    let (reason_tx, reason_rx) = async_channel::bounded(2);
     let disc_sender = disconnections.sender.clone();
+    let permit = connection.take_permit();
     pool.spawn(async move {
-        let _ = disconnect.recv().await;
-        info!("{} disconnected", conn_id);
-        let _ = disc_sender.send(conn_id);
+        let reason = reason_rx.recv().await.unwrap_or(DisconnectReason::Closed);
+        info!("{} disconnected: {:?}", conn_id, reason);
+        drop(permit);
+        let _ = disc_sender.send((conn_id, reason));
     })
     .detach();
     let mediator = packet_mediator.clone();
     let receive_task = broadcast_disconnect.clone();
+    let receive_reason = reason_tx.clone();
+    let receive_errors = errors.sender.clone();
     let stop = quit.receiver.clone();
     let conn = connection.value.clone();
     pool.spawn(async move {
-        let reader = conn.accept_uni().await.unwrap();
-        ReceivePacketsTask::new(reader, mediator, conn_id)
-            ._run(stop, receive_task)
+        let reader = match conn.accept_uni().await {
+            Ok(reader) => reader,
+            Err(e) => {
+                return close_on_stream_error(&conn, conn_id, e, &receive_reason, &receive_errors)
+            }
+        };
+        let reason = ReceivePacketsTask::new(reader, mediator, conn_id, receive_errors)
+            ._run(stop, receive_task.notified)
             .await;
+        let _ = receive_reason.try_send(reason);
+        let _ = receive_task.notify.try_send(());
     })
     .detach();
+    let send_errors = errors.sender.clone();
     let stop = quit.receiver.clone();
     pool.spawn(async move {
-        let writer = connection.value.open_uni().await.unwrap();
-        SendPacketsTask::new(writer, receiver, conn_id)
-            ._run::<<S::Packet as Packet>::OtherPacket>(stop, broadcast_disconnect)
+        let writer = match connection.value.open_uni().await {
+            Ok(writer) => writer,
+            Err(e) => {
+                return close_on_stream_error(&connection, conn_id, e, &reason_tx, &send_errors)
+            }
+        };
+        let reason = SendPacketsTask::new(writer, receiver, conn_id)
+            ._run::<<S::Packet as Packet>::OtherPacket>(stop, broadcast_disconnect.notified)
             .await;
+        let _ = reason_tx.try_send(reason);
+        let _ = broadcast_disconnect.notify.try_send(());
     })
     .detach();
     sender
 }
 
+/// Closes a connection whose streams could not be opened so that the sibling
+/// task stops waiting on it, and reports the error.
+fn close_on_stream_error(
+    connection: &quinn::Connection,
+    conn_id: NetworkId,
+    e: quinn::ConnectionError,
+    reasons: &async_channel::Sender<DisconnectReason>,
+    errors: &Sender<NetworkError>,
+) {
+    let error = Error::from(e);
+    error!("Failed to open streams for {}: {}", conn_id, error);
+    connection.close(0u32.into(), b"");
+    let _ = reasons.try_send(DisconnectReason::Error(error.to_string()));
+    let _ = errors.send(NetworkError {
+        id: Some(conn_id),
+        error,
+    });
+}
+
 fn spawn_self(
     mut commands: Commands,
     mut network_to_world: ResMut<NetworkToWorld<Client>>,
     accept_connections: Res<Packets<AcceptConnection>>,
-    server: Query<&Network<Server>>,
+    pending_transfer: Option<Res<PendingTransfer>>,
+    spectating: Option<Res<Spectating>>,
+    servers: Query<(Entity, &Network<Server>, Option<&Transferring>)>,
+    mut me: Query<(Entity, &mut NetworkId), With<Me>>,
 ) {
     if accept_connections.receiver.is_empty() {
         return;
     }
 
-    let Ok(server) = server.get_single() else {
+    if let Some(pending) = pending_transfer {
+        for conn in accept_connections.receiver.try_iter() {
+            complete_transfer(
+                &mut commands,
+                &mut network_to_world,
+                &pending,
+                &servers,
+                &mut me,
+                conn,
+            );
+        }
+        return;
+    }
+
+    let Some((_, server, _)) = servers
+        .iter()
+        .find(|(_, _, transferring)| transferring.is_none())
+    else {
         return;
     };
 
     for conn in accept_connections.receiver.try_iter() {
+        // spectators are not part of the world they watch
+        if spectating.is_some() {
+            commands.spawn((conn.connection_id, Me));
+            continue;
+        }
+
         let entity = commands
             .spawn((conn.connection_id, MovementSpeed(3), Player, Me))
             .id();
@@ -507,19 +959,69 @@ fn spawn_self(
     }
 }
 
-fn despawn_disconnections<S>(
+/// Switches over to the server that accepted the transfer ticket, keeping `Me`
+/// but dropping everything that was replicated by the previous server.
+fn complete_transfer(
+    commands: &mut Commands,
+    network_to_world: &mut NetworkToWorld<Client>,
+    pending: &PendingTransfer,
+    servers: &Query<(Entity, &Network<Server>, Option<&Transferring>)>,
+    me: &mut Query<(Entity, &mut NetworkId), With<Me>>,
+    conn: AcceptConnection,
+) {
+    let Some(Ok((_, server, _))) = pending.connection.map(|entity| servers.get(entity)) else {
+        error!("Accepted by a server that is not being transferred to");
+        return;
+    };
+
+    for (entity, _, transferring) in servers.iter() {
+        if transferring.is_some() {
+            commands.entity(entity).remove::<Transferring>();
+        } else {
+            commands.entity(entity).despawn();
+        }
+    }
+
+    let me = me.get_single_mut().ok();
+    for (_, entity) in network_to_world.drain() {
+        if Some(entity) != me.as_ref().map(|(me, _)| *me) {
+            commands.entity(entity).despawn();
+        }
+    }
+
+    if let Some((entity, mut id)) = me {
+        *id = conn.connection_id;
+        network_to_world.insert(conn.connection_id, entity);
+    }
+
+    commands.remove_resource::<PendingTransfer>();
+
+    let _ = server.send(QueryEntity {
+        id: conn.connection_id,
+    });
+}
+
+pub(super) fn despawn_disconnections<S>(
     mut commands: Commands,
+    mut disconnected: EventWriter<Disconnected>,
     disconnections: Res<Disconnections<S>>,
     mut network_to_world: ResMut<NetworkToWorld<S>>,
+    connections: Query<(Entity, &Network<S::Other>)>,
 ) where
-    S: Send + Sync + 'static,
+    S: Service,
 {
-    for disconnection in disconnections.receiver.try_iter() {
-        let Some(entity) = network_to_world.remove(&disconnection) else {
+    for (id, reason) in disconnections.receiver.try_iter() {
+        let Some((entity, _)) = connections.iter().find(|(_, network)| network.id() == id) else {
             continue;
         };
 
+        // on the client the connection id is local and is not part of the network id mapping
+        if network_to_world.get(&id) == Some(&entity) {
+            network_to_world.remove(&id);
+        }
+
         commands.entity(entity).despawn();
+        disconnected.send(Disconnected { id, entity, reason });
     }
 }
 
@@ -529,16 +1031,11 @@ fn raise_query_entity_events(
     mut events: EventWriter<EntityQuery>,
 ) {
     events.send_batch(packets.receiver.try_iter().filter_map(|packet| {
-        return network_to_world
-            .get(&packet.connection_id)
-            .and_then(|entity| {
-                network_to_world
-                    .get(&packet.packet.id)
-                    .map(|querier| (entity, querier))
-            })
-            .map(|(entity, querier)| EntityQuery {
-                entity: *entity,
-                querier: *querier,
-            });
+        let querier = network_to_world.get(&packet.connection_id)?;
+        let entity = network_to_world.get(&packet.packet.id)?;
+        Some(EntityQuery {
+            entity: *entity,
+            querier: *querier,
+        })
     }));
 }
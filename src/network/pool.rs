@@ -0,0 +1,175 @@
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex},
+};
+
+/// Buffers kept for reuse at most, any more are freed.
+const MAX_POOLED: usize = 256;
+/// Buffers that grew beyond this are freed rather than kept, so that a single
+/// large packet does not hold on to its memory.
+const MAX_POOLED_CAPACITY: usize = 64 * 1024;
+
+/// Frames of encoded packets, shared by every connection.
+pub(crate) static FRAMES: BufferPool = BufferPool::new();
+
+/// Byte buffers that are reused instead of allocating a new one per packet.
+/// They are kept in the `Arc` they are shared through, so sharing a buffer
+/// does not allocate either.
+pub(crate) struct BufferPool {
+    buffers: Mutex<Vec<Arc<Vec<u8>>>>,
+}
+
+impl BufferPool {
+    pub(crate) const fn new() -> Self {
+        Self {
+            buffers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// An empty buffer, with the capacity of a previous one where possible.
+    pub(crate) fn take(&'static self) -> PooledBuffer {
+        let buffer = match self.buffers.lock() {
+            Ok(mut buffers) => buffers.pop(),
+            Err(_) => None,
+        };
+
+        PooledBuffer {
+            buffer: Some(buffer.unwrap_or_default()),
+            pool: self,
+        }
+    }
+
+    fn put(&self, mut buffer: Arc<Vec<u8>>) {
+        // only the last of the buffer's owners returns it
+        let Some(bytes) = Arc::get_mut(&mut buffer) else {
+            return;
+        };
+        if bytes.capacity() == 0 || bytes.capacity() > MAX_POOLED_CAPACITY {
+            return;
+        }
+        let Ok(mut buffers) = self.buffers.lock() else {
+            return;
+        };
+
+        if buffers.len() < MAX_POOLED {
+            bytes.clear();
+            buffers.push(buffer);
+        }
+    }
+}
+
+/// A buffer that goes back to its pool when dropped.
+pub(crate) struct PooledBuffer {
+    /// Only `None` once shared or dropped.
+    buffer: Option<Arc<Vec<u8>>>,
+    pool: &'static BufferPool,
+}
+
+impl PooledBuffer {
+    /// Makes the buffer read only, so that it can be shared without copying.
+    pub(crate) fn share(mut self) -> SharedBuffer {
+        SharedBuffer {
+            buffer: self.buffer.take(),
+            pool: self.pool,
+        }
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Self::Target {
+        self.buffer.as_ref().expect("buffer was shared")
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.buffer
+            .as_mut()
+            .and_then(Arc::get_mut)
+            .expect("buffer was shared")
+    }
+}
+
+impl fmt::Debug for PooledBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if let Some(buffer) = self.buffer.take() {
+            self.pool.put(buffer);
+        }
+    }
+}
+
+/// A read only buffer that goes back to its pool once every clone is dropped.
+pub(crate) struct SharedBuffer {
+    buffer: Option<Arc<Vec<u8>>>,
+    pool: &'static BufferPool,
+}
+
+impl Clone for SharedBuffer {
+    fn clone(&self) -> Self {
+        Self {
+            buffer: self.buffer.clone(),
+            pool: self.pool,
+        }
+    }
+}
+
+impl Deref for SharedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        self.buffer.as_deref().map_or(&[], Vec::as_slice)
+    }
+}
+
+impl fmt::Debug for SharedBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl Drop for SharedBuffer {
+    fn drop(&mut self) {
+        if let Some(buffer) = self.buffer.take() {
+            self.pool.put(buffer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static POOL: BufferPool = BufferPool::new();
+
+    fn pooled() -> usize {
+        POOL.buffers.lock().unwrap().len()
+    }
+
+    #[test]
+    fn shared_buffers_return_once_every_clone_is_dropped() {
+        let mut buffer = POOL.take();
+        buffer.extend_from_slice(b"frame");
+        let shared = buffer.share();
+        let clone = shared.clone();
+        assert_eq!(&*clone, b"frame");
+
+        drop(shared);
+        assert_eq!(pooled(), 0);
+        drop(clone);
+        assert_eq!(pooled(), 1);
+
+        let reused = POOL.take();
+        assert!(reused.is_empty());
+        assert!(reused.capacity() >= 5);
+        assert_eq!(pooled(), 0);
+    }
+}
@@ -0,0 +1,283 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::{
+    Commands, Entity, EventWriter, Local, Query, Res, ResMut, Resource, With, Without,
+};
+use tracing::{error, info};
+
+use super::{
+    event::Queued,
+    guard::{ConnectionGuard, Rejection},
+    mediator::PacketWithConnId,
+    metrics::ServerMetrics,
+    packet::{Join, QueuePosition, Role},
+    plugin::{spawn_player, Client, ClientConnection, Network, Packets, RemoteAddr, Server},
+    spectate::{spawn_spectator, Spectator},
+    state::{ConnectionState, ConnectionStates},
+};
+use crate::{ambit::plugin::Player, id::NetworkToWorld, path::plugin::Position};
+
+// resources
+/// Limits how many clients may play at once. Clients joining beyond the limit
+/// wait in a queue and are admitted in order as players leave. Transferred
+/// clients were admitted by another server and do not wait.
+#[derive(Resource, Clone, Debug)]
+pub struct CapacitySettings {
+    /// `None` for no limit.
+    pub max_players: Option<usize>,
+    /// Clients connecting from these addresses skip the queue, even when the
+    /// server is full.
+    pub reserved: HashSet<IpAddr>,
+    /// Time between queue position updates sent to waiting clients.
+    pub position_interval: Duration,
+}
+
+impl Default for CapacitySettings {
+    fn default() -> Self {
+        Self {
+            max_players: None,
+            reserved: HashSet::new(),
+            position_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+pub(crate) struct LoginQueue {
+    waiting: VecDeque<Waiting>,
+}
+
+impl LoginQueue {
+    fn contains(&self, entity: Entity) -> bool {
+        self.waiting.iter().any(|waiting| waiting.entity == entity)
+    }
+}
+
+struct Waiting {
+    entity: Entity,
+    since: Instant,
+    /// Whether the client has been sent its position, clients that are
+    /// admitted right away never are.
+    notified: bool,
+}
+
+// systems
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+pub(super) fn admit_players(
+    mut commands: Commands,
+    settings: Res<CapacitySettings>,
+    mut queue: ResMut<LoginQueue>,
+    mut metrics: ResMut<ServerMetrics>,
+    joins: Res<Packets<PacketWithConnId<Join>>>,
+    states: Res<ConnectionStates>,
+    guard: Option<Res<ConnectionGuard>>,
+    network_to_world: Res<NetworkToWorld<Server>>,
+    clients: Query<
+        (
+            &Network<Client>,
+            Option<&RemoteAddr>,
+            Option<&ClientConnection>,
+        ),
+        (Without<Player>, Without<Spectator>),
+    >,
+    players: Query<(), With<Player>>,
+    mut last_update: Local<Option<Instant>>,
+) {
+    let mut player_count = players.iter().count();
+
+    // clients that disconnected while waiting
+    let waiting = queue.waiting.len();
+    queue
+        .waiting
+        .retain(|waiting| clients.contains(waiting.entity));
+    let mut changed = queue.waiting.len() != waiting;
+
+    for join in joins.iter() {
+        let Some(&entity) = network_to_world.get(&join.connection_id) else {
+            continue;
+        };
+
+        let Ok((client, addr, connection)) = clients.get(entity) else {
+            continue;
+        };
+
+        if queue.contains(entity) {
+            continue;
+        }
+
+        if let Some(account) = &join.packet.account {
+            if guard
+                .as_ref()
+                .is_some_and(|guard| guard.is_account_banned(account))
+            {
+                info!("Refusing {} of banned account {}", client.id(), account);
+                states.set(client.id(), ConnectionState::Disconnecting);
+                if let Some(connection) = connection {
+                    connection.close(&Rejection::AccountBanned.to_string());
+                }
+                continue;
+            }
+            commands.entity(entity).insert(account.clone());
+        }
+
+        if let Role::Spectator(viewpoint) = join.packet.role {
+            spawn_spectator(&mut commands, &states, entity, client, viewpoint);
+            continue;
+        }
+
+        if addr.is_some_and(|addr| settings.reserved.contains(&addr.0.ip())) {
+            info!("Admitting {} to a reserved slot", client.id());
+            spawn_player(
+                &mut commands,
+                &states,
+                entity,
+                client,
+                Position { x: 0, y: 0 },
+            );
+            player_count += 1;
+            continue;
+        }
+
+        queue.waiting.push_back(Waiting {
+            entity,
+            since: Instant::now(),
+            notified: false,
+        });
+        changed = true;
+    }
+
+    while settings.max_players.is_none_or(|max| player_count < max) {
+        let Some(waiting) = queue.waiting.pop_front() else {
+            break;
+        };
+        let Ok((client, ..)) = clients.get(waiting.entity) else {
+            continue;
+        };
+
+        if waiting.notified {
+            info!(
+                "Admitting {} after {:?} in the queue",
+                client.id(),
+                waiting.since.elapsed()
+            );
+            metrics.admitted_from_queue += 1;
+            changed = true;
+        }
+        spawn_player(
+            &mut commands,
+            &states,
+            waiting.entity,
+            client,
+            Position { x: 0, y: 0 },
+        );
+        player_count += 1;
+    }
+
+    let due = last_update.is_none_or(|sent| sent.elapsed() >= settings.position_interval);
+    if !queue.waiting.is_empty() && (changed || due) {
+        let length = queue.waiting.len() as u32;
+        for (index, waiting) in queue.waiting.iter_mut().enumerate() {
+            let Ok((client, ..)) = clients.get(waiting.entity) else {
+                continue;
+            };
+
+            if let Err(e) = client.send(QueuePosition {
+                position: index as u32 + 1,
+                length,
+            }) {
+                error!("Failed to send queue position to {}: {}", client.id(), e);
+            }
+            waiting.notified = true;
+        }
+        *last_update = Some(Instant::now());
+    }
+
+    metrics.players = player_count;
+    metrics.max_players = settings.max_players;
+    metrics.queued = queue.waiting.len();
+    metrics.longest_queue_wait = queue
+        .waiting
+        .front()
+        .map(|waiting| waiting.since.elapsed())
+        .unwrap_or_default();
+}
+
+pub(super) fn raise_queue_positions(
+    packets: Res<Packets<QueuePosition>>,
+    mut queued: EventWriter<Queued>,
+) {
+    queued.send_batch(packets.iter().map(|packet| Queued {
+        position: packet.position,
+        length: packet.length,
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{ecs::event::Events, prelude::With};
+
+    use super::*;
+    use crate::network::harness::Harness;
+
+    fn harness_with_capacity(clients: usize, settings: CapacitySettings) -> Harness {
+        Harness::with_server(clients, |server| {
+            server.insert_resource(settings);
+        })
+    }
+
+    fn was_queued(harness: &Harness, client: usize, position: u32) -> bool {
+        let events = harness.clients[client].world.resource::<Events<Queued>>();
+        events
+            .get_reader()
+            .iter(events)
+            .any(|queued| queued.position == position)
+    }
+
+    #[test]
+    fn full_server_queues_clients_until_a_slot_frees_up() {
+        let mut harness = harness_with_capacity(
+            1,
+            CapacitySettings {
+                max_players: Some(1),
+                ..Default::default()
+            },
+        );
+        harness.wait_for_players();
+
+        harness.add_client();
+        harness.step_until("the second client is queued", |harness| {
+            was_queued(harness, 1, 1)
+        });
+        assert_eq!(harness.server.world.resource::<ServerMetrics>().queued, 1);
+        assert_eq!(harness.count::<(), With<Player>>(None), 1);
+
+        drop(harness.clients.remove(0));
+
+        harness.step_until("the queued client is admitted", |harness| {
+            harness.client_id(0).is_some()
+        });
+        let metrics = harness.server.world.resource::<ServerMetrics>();
+        assert_eq!(metrics.queued, 0);
+        assert_eq!(metrics.admitted_from_queue, 1);
+    }
+
+    #[test]
+    fn reserved_addresses_skip_the_queue() {
+        let mut harness = harness_with_capacity(
+            1,
+            CapacitySettings {
+                max_players: Some(0),
+                reserved: HashSet::from(["127.0.0.1".parse().unwrap()]),
+                ..Default::default()
+            },
+        );
+
+        harness.wait_for_players();
+
+        assert_eq!(harness.server.world.resource::<ServerMetrics>().queued, 0);
+    }
+}
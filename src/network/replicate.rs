@@ -0,0 +1,243 @@
+use bevy::{
+    ecs::system::EntityCommands,
+    prelude::{
+        App, Changed, Commands, Component, CoreStage, Entity, IntoSystemDescriptor, Query,
+        RemovedComponents, Res, Resource,
+    },
+    utils::HashMap,
+};
+use speedy::{LittleEndian, Readable, Writable};
+use tracing::error;
+
+use super::{
+    budget::PendingUpdates,
+    error::Result,
+    packet::{RemoveComponent, ReplicateComponent},
+    plugin::{Client, Packets},
+};
+use crate::{
+    ambit::plugin::VisibleEntities,
+    id::{NetworkId, NetworkToWorld},
+};
+
+/// A component the server sends to every client that sees its entity.
+pub(crate) trait Replicated:
+    Component + Clone + for<'a> Readable<'a, LittleEndian> + Writable<LittleEndian>
+{
+    /// Identifies the component on the wire, so it must never change, nor be
+    /// shared with another replicated component.
+    const ID: ComponentId;
+}
+
+#[derive(Readable, Writable, Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(crate) struct ComponentId(pub(crate) u16);
+
+pub(crate) trait ReplicateAppExt {
+    /// Sends `C` to the clients that see its entity whenever it is added,
+    /// changed or removed, and applies it on clients that receive it.
+    fn replicate<C: Replicated>(&mut self) -> &mut Self;
+}
+
+impl ReplicateAppExt for App {
+    fn replicate<C: Replicated>(&mut self) -> &mut Self {
+        self.init_resource::<ReplicationRegistry>();
+        self.world
+            .resource_mut::<ReplicationRegistry>()
+            .register::<C>();
+
+        // after every system that may change a component, and in time for
+        // removals to be seen
+        self.add_system_to_stage(
+            CoreStage::PostUpdate,
+            replicate_changes::<C>.before("send_updates"),
+        );
+        self.add_system_to_stage(
+            CoreStage::PostUpdate,
+            replicate_removals::<C>.before("send_updates"),
+        );
+        self
+    }
+}
+
+// resources
+/// How to apply each replicated component received from the server.
+#[derive(Resource, Default)]
+pub(crate) struct ReplicationRegistry {
+    components: HashMap<ComponentId, Replicator>,
+}
+
+struct Replicator {
+    name: &'static str,
+    insert: fn(&mut EntityCommands, &[u8]) -> Result<()>,
+    remove: fn(&mut EntityCommands),
+}
+
+impl ReplicationRegistry {
+    fn register<C: Replicated>(&mut self) {
+        let replicator = Replicator {
+            name: std::any::type_name::<C>(),
+            insert: |entity, bytes| {
+                entity.insert(C::read_from_buffer(bytes)?);
+                Ok(())
+            },
+            remove: |entity| {
+                entity.remove::<C>();
+            },
+        };
+
+        if let Some(existing) = self.components.get(&C::ID) {
+            assert_eq!(
+                existing.name,
+                replicator.name,
+                "{:?} is used by both {} and {}",
+                C::ID,
+                existing.name,
+                replicator.name
+            );
+        }
+        self.components.insert(C::ID, replicator);
+    }
+}
+
+// systems
+fn replicate_changes<C: Replicated>(
+    changed: Query<(Entity, &NetworkId, &C), Changed<C>>,
+    components: Query<&C>,
+    mut clients: Query<(Entity, &VisibleEntities, &mut PendingUpdates)>,
+) {
+    let encode = |id: NetworkId, component: &C| -> Result<ReplicateComponent> {
+        Ok(ReplicateComponent {
+            id,
+            component: C::ID,
+            bytes: component.write_to_vec()?,
+        })
+    };
+
+    for (client, visible, mut updates) in clients.iter_mut() {
+        for &(id, entity) in visible.entered() {
+            let Ok(component) = components.get(entity) else {
+                continue;
+            };
+            if let Err(e) = encode(id, component).and_then(|packet| updates.push(id, packet)) {
+                error!("Failed to replicate {:?} of {}: {}", C::ID, id, e);
+            }
+        }
+
+        for (entity, &id, component) in changed.iter() {
+            if entity != client && !visible.contains(id) {
+                continue;
+            }
+            if let Err(e) = encode(id, component).and_then(|packet| updates.push(id, packet)) {
+                error!("Failed to replicate {:?} of {}: {}", C::ID, id, e);
+            }
+        }
+    }
+}
+
+fn replicate_removals<C: Replicated>(
+    removed: RemovedComponents<C>,
+    ids: Query<&NetworkId>,
+    mut clients: Query<(Entity, &VisibleEntities, &mut PendingUpdates)>,
+) {
+    for entity in removed.iter() {
+        // despawned entities are despawned on clients as a whole
+        let Ok(&id) = ids.get(entity) else {
+            continue;
+        };
+
+        for (client, visible, mut updates) in clients.iter_mut() {
+            if entity != client && !visible.contains(id) {
+                continue;
+            }
+            let _ = updates.push(
+                id,
+                RemoveComponent {
+                    id,
+                    component: C::ID,
+                },
+            );
+        }
+    }
+}
+
+pub(super) fn receive_replicated_components(
+    mut commands: Commands,
+    registry: Res<ReplicationRegistry>,
+    replicated: Res<Packets<ReplicateComponent>>,
+    removed: Res<Packets<RemoveComponent>>,
+    network_to_world: Res<NetworkToWorld<Client>>,
+) {
+    for packet in replicated.iter() {
+        let Some(&entity) = network_to_world.get(&packet.id) else {
+            continue;
+        };
+        let Some(replicator) = registry.components.get(&packet.component) else {
+            error!("Received unregistered component {:?}", packet.component);
+            continue;
+        };
+
+        if let Err(e) = (replicator.insert)(&mut commands.entity(entity), &packet.bytes) {
+            error!("Failed to read {}: {}", replicator.name, e);
+        }
+    }
+
+    for packet in removed.iter() {
+        let (Some(&entity), Some(replicator)) = (
+            network_to_world.get(&packet.id),
+            registry.components.get(&packet.component),
+        ) else {
+            continue;
+        };
+        (replicator.remove)(&mut commands.entity(entity));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        id::NetworkToWorld,
+        network::{
+            harness::Harness,
+            plugin::{Client, Server},
+        },
+        stat::MovementSpeed,
+    };
+
+    fn speed_on_client(harness: &mut Harness, client: usize) -> Option<usize> {
+        let id = harness.client_id(client)?;
+        let world = &harness.clients[client].world;
+        let entity = *world.resource::<NetworkToWorld<Client>>().get(&id)?;
+        world.get::<MovementSpeed>(entity).map(|speed| **speed)
+    }
+
+    #[test]
+    fn changed_and_removed_components_reach_the_client() {
+        let mut harness = Harness::new(1);
+        harness.wait_for_players();
+        let id = harness.client_id(0).unwrap();
+        let entity = *harness
+            .server
+            .world
+            .resource::<NetworkToWorld<Server>>()
+            .get(&id)
+            .unwrap();
+
+        harness
+            .server
+            .world
+            .entity_mut(entity)
+            .insert(MovementSpeed(7));
+        harness.step_until("the client sees the new speed", |harness| {
+            speed_on_client(harness, 0) == Some(7)
+        });
+
+        harness
+            .server
+            .world
+            .entity_mut(entity)
+            .remove::<MovementSpeed>();
+        harness.step_until("the client lost its speed", |harness| {
+            speed_on_client(harness, 0).is_none()
+        });
+    }
+}
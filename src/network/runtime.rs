@@ -0,0 +1,88 @@
+//! The async runtime behind quinn and the transport timers. Transport tasks
+//! are polled on bevy's io pool either way; with the `tokio` feature quinn's
+//! sockets and every timer are driven by a tokio runtime owned by this module,
+//! otherwise async-std is used.
+
+use std::{future::Future, time::Duration};
+
+use futures::future::Either;
+
+#[cfg(not(any(feature = "async-std", feature = "tokio")))]
+compile_error!("either the `async-std` or the `tokio` feature must be enabled");
+
+#[cfg(feature = "tokio")]
+fn runtime() -> &'static tokio::runtime::Runtime {
+    use std::sync::OnceLock;
+
+    static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .thread_name("animus-network")
+            .enable_all()
+            .build()
+            .expect("failed to start tokio runtime")
+    })
+}
+
+/// Runs `f` in the context of the runtime. Quinn picks its runtime up from the
+/// context when endpoints are created.
+pub(crate) fn enter<T>(f: impl FnOnce() -> T) -> T {
+    #[cfg(feature = "tokio")]
+    let _guard = runtime().enter();
+
+    f()
+}
+
+pub(crate) fn sleep(duration: Duration) -> impl Future<Output = ()> {
+    #[cfg(feature = "tokio")]
+    {
+        enter(|| tokio::time::sleep(duration))
+    }
+
+    #[cfg(not(feature = "tokio"))]
+    {
+        async_std::task::sleep(duration)
+    }
+}
+
+/// Resolves to `None` if `future` does not complete within `duration`, in
+/// which case it is dropped.
+pub(crate) async fn timeout<F>(duration: Duration, future: F) -> Option<F::Output>
+where
+    F: Future,
+{
+    let future = std::pin::pin!(future);
+    let sleep = std::pin::pin!(sleep(duration));
+
+    match futures::future::select(future, sleep).await {
+        Either::Left((output, _)) => Some(output),
+        Either::Right(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    #[test]
+    fn sleep_outside_of_runtime_tasks() {
+        let started = Instant::now();
+
+        futures::executor::block_on(sleep(Duration::from_millis(10)));
+
+        assert!(started.elapsed() >= Duration::from_millis(10));
+    }
+
+    #[test]
+    fn timeout_drops_pending_futures() {
+        let timed_out = futures::executor::block_on(timeout(
+            Duration::from_millis(10),
+            futures::future::pending::<()>(),
+        ));
+
+        assert_eq!(timed_out, None);
+    }
+}
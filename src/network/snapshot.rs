@@ -0,0 +1,662 @@
+use std::collections::VecDeque;
+
+use bevy::{
+    prelude::{Component, Entity, EventWriter, Local, Query, Res, ResMut, Resource, Without},
+    utils::HashMap,
+};
+use speedy::{LittleEndian, Writable};
+use tracing::error;
+
+use super::{
+    budget::{BandwidthSettings, PendingUpdates},
+    mediator::PacketWithConnId,
+    metrics::ServerMetrics,
+    packet::{AckSnapshot, EntityDelta, Snapshot},
+    plugin::{Client, Network, Packets, Server},
+    socket::MAX_PACKET_LENGTH,
+    spectate::Spectator,
+    transfer::Transferring,
+};
+use crate::{
+    ambit::plugin::VisibleEntities,
+    id::{NetworkId, NetworkToWorld},
+    path::plugin::{Heading, MaybeNextPosition, Position, Target},
+    time::tick::Tick,
+};
+
+/// Snapshots a client has not acknowledged after this many newer ones can no
+/// longer serve as a baseline, and the next snapshot is sent in full.
+const HISTORY: usize = 32;
+/// Bytes of entity changes and removals a snapshot may carry, leaving room for
+/// its other fields in a packet the client accepts.
+const SNAPSHOT_ROOM: usize = MAX_PACKET_LENGTH - 64;
+
+// resources
+/// Snapshots received from the server, the newest last.
+#[derive(Resource, Default)]
+pub(crate) struct ReceivedSnapshots {
+    received: VecDeque<(u64, State)>,
+    /// The state targets were last raised for.
+    applied: State,
+}
+
+impl ReceivedSnapshots {
+    /// Rebuilds the complete state from `snapshot` and its baseline. Returns
+    /// `false` when the snapshot is outdated or its baseline unknown.
+    fn receive(&mut self, snapshot: &Snapshot) -> bool {
+        let state = match snapshot.baseline {
+            Some(baseline) => {
+                if self
+                    .received
+                    .back()
+                    .is_some_and(|(latest, _)| *latest >= snapshot.sequence)
+                {
+                    return false;
+                }
+                let Some((_, state)) = self
+                    .received
+                    .iter()
+                    .find(|(sequence, _)| *sequence == baseline)
+                else {
+                    return false;
+                };
+                apply(state.clone(), snapshot)
+            }
+            // the server may have lost track of the client, or be a different one after a transfer
+            None => {
+                self.received.clear();
+                apply(State::default(), snapshot)
+            }
+        };
+
+        self.received.push_back((snapshot.sequence, state));
+        if self.received.len() > HISTORY {
+            self.received.pop_front();
+        }
+
+        true
+    }
+}
+
+// components
+/// Snapshots sent to a client, kept until they are too old to be a baseline.
+#[derive(Component, Default)]
+pub(crate) struct SnapshotHistory {
+    sent: VecDeque<(u64, State)>,
+    acked: Option<u64>,
+    next_sequence: u64,
+    /// The latest snapshot, if changes were left out of it.
+    partial: Option<u64>,
+}
+
+impl SnapshotHistory {
+    /// Returns `None` when `state` has already been sent. Entities that do not
+    /// fit are left out, those `priority` puts last first, and are sent once
+    /// the snapshot is acknowledged. Until then it is sent again even if the
+    /// state did not change, in case the acknowledgement was lost.
+    fn snapshot<F, K>(&mut self, state: State, priority: F) -> Option<Snapshot>
+    where
+        F: Fn(NetworkId, &EntityState) -> K,
+        K: Ord,
+    {
+        let unacked_part = self
+            .partial
+            .is_some_and(|partial| self.acked.is_none_or(|acked| acked < partial));
+        if !unacked_part && self.sent.back().is_some_and(|(_, latest)| *latest == state) {
+            return None;
+        }
+
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+
+        let empty = State::default();
+        let (baseline, base) = match self
+            .acked
+            .and_then(|acked| self.sent.iter().find(|(sequence, _)| *sequence == acked))
+        {
+            Some((baseline, base)) => (Some(*baseline), base),
+            None => (None, &empty),
+        };
+
+        let mut entities = diff(base, &state);
+        entities.sort_by_key(|delta| priority(delta.id, &state[&delta.id]));
+        let mut removed: Vec<_> = base
+            .keys()
+            .filter(|id| !state.contains_key(*id))
+            .copied()
+            .collect();
+        let changes = entities.len() + removed.len();
+
+        let mut room = SNAPSHOT_ROOM;
+        fit(&mut removed, &mut room);
+        fit(&mut entities, &mut room);
+
+        let snapshot = Snapshot {
+            sequence,
+            baseline,
+            entities,
+            removed,
+        };
+        // what the client knows once it received the snapshot
+        let partial = snapshot.entities.len() + snapshot.removed.len() < changes;
+        self.partial = partial.then_some(sequence);
+        let state = if partial {
+            apply(base.clone(), &snapshot)
+        } else {
+            state
+        };
+
+        self.sent.push_back((sequence, state));
+        if self.sent.len() > HISTORY {
+            self.sent.pop_front();
+        }
+
+        Some(snapshot)
+    }
+
+    /// The state last sent, whether or not the client received it.
+    fn latest(&self) -> Option<&State> {
+        self.sent.back().map(|(_, state)| state)
+    }
+
+    fn ack(&mut self, sequence: u64) {
+        // acks may arrive out of order, and never for snapshots that were not sent
+        if sequence < self.next_sequence && self.acked.is_none_or(|acked| acked < sequence) {
+            self.acked = Some(sequence);
+        }
+    }
+}
+
+// util
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct EntityState {
+    target: Position,
+    /// Where the entity set out towards `target` from.
+    origin: Position,
+}
+
+type State = HashMap<NetworkId, EntityState>;
+
+/// How closely an entity's state in a client's snapshots follows its actual
+/// state, lower the further the entity is from the client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Detail {
+    /// Updated every tick.
+    Full,
+    /// Updated every few ticks.
+    Coalesced,
+    /// Updated once the actual state is far from what the client knows.
+    Significant,
+}
+
+impl Detail {
+    fn at(distance: u32, settings: &BandwidthSettings) -> Self {
+        if distance <= settings.near_distance {
+            Self::Full
+        } else if distance <= settings.mid_distance {
+            Self::Coalesced
+        } else {
+            Self::Significant
+        }
+    }
+
+    /// The state of the entity `id` to send at `tick`, given the state its
+    /// client was last sent.
+    fn state(
+        self,
+        settings: &BandwidthSettings,
+        tick: usize,
+        id: NetworkId,
+        known: Option<&EntityState>,
+        actual: EntityState,
+    ) -> EntityState {
+        let Some(&known) = known else {
+            return actual;
+        };
+
+        let update = match self {
+            Self::Full => true,
+            // entities take turns, so that not all of them are updated at once
+            Self::Coalesced => (tick as u64)
+                .wrapping_add(*id)
+                .is_multiple_of(settings.mid_interval.max(1) as u64),
+            Self::Significant => {
+                known.target.taxi_distance(actual.target) >= settings.far_change
+                    || known.origin.taxi_distance(actual.origin) >= settings.far_change
+            }
+        };
+
+        if update {
+            actual
+        } else {
+            known
+        }
+    }
+}
+
+fn diff(base: &State, state: &State) -> Vec<EntityDelta> {
+    fn changed(old: Option<i32>, new: i32) -> Option<i32> {
+        (old != Some(new)).then_some(new)
+    }
+
+    state
+        .iter()
+        .filter(|&(id, state)| base.get(id) != Some(state))
+        .map(|(&id, state)| {
+            let old = base.get(&id);
+            EntityDelta {
+                id,
+                target_x: changed(old.map(|o| o.target.x), state.target.x),
+                target_y: changed(old.map(|o| o.target.y), state.target.y),
+                origin_x: changed(old.map(|o| o.origin.x), state.origin.x),
+                origin_y: changed(old.map(|o| o.origin.y), state.origin.y),
+            }
+        })
+        .collect()
+}
+
+/// Keeps as many of the first `items` as fit in `room` bytes, taking up their
+/// room.
+fn fit<T: Writable<LittleEndian>>(items: &mut Vec<T>, room: &mut usize) {
+    let fitting = items
+        .iter()
+        .take_while(|item| match item.bytes_needed() {
+            Ok(length) if length <= *room => {
+                *room -= length;
+                true
+            }
+            _ => false,
+        })
+        .count();
+    items.truncate(fitting);
+}
+
+fn apply(mut state: State, snapshot: &Snapshot) -> State {
+    for id in &snapshot.removed {
+        state.remove(id);
+    }
+
+    for delta in &snapshot.entities {
+        let entity = match state.get(&delta.id) {
+            Some(old) => EntityState {
+                target: Position {
+                    x: delta.target_x.unwrap_or(old.target.x),
+                    y: delta.target_y.unwrap_or(old.target.y),
+                },
+                origin: Position {
+                    x: delta.origin_x.unwrap_or(old.origin.x),
+                    y: delta.origin_y.unwrap_or(old.origin.y),
+                },
+            },
+            None => {
+                let (Some(target_x), Some(target_y), Some(origin_x), Some(origin_y)) = (
+                    delta.target_x,
+                    delta.target_y,
+                    delta.origin_x,
+                    delta.origin_y,
+                ) else {
+                    error!("Incomplete state for new entity {}", delta.id);
+                    continue;
+                };
+                EntityState {
+                    target: Position {
+                        x: target_x,
+                        y: target_y,
+                    },
+                    origin: Position {
+                        x: origin_x,
+                        y: origin_y,
+                    },
+                }
+            }
+        };
+        state.insert(delta.id, entity);
+    }
+
+    state
+}
+
+// systems
+pub(super) fn receive_snapshot_acks(
+    packets: Res<Packets<PacketWithConnId<AckSnapshot>>>,
+    network_to_world: Res<NetworkToWorld<Server>>,
+    mut clients: Query<&mut SnapshotHistory>,
+) {
+    for packet in packets.iter() {
+        let Some(mut history) = network_to_world
+            .get(&packet.connection_id)
+            .and_then(|&entity| clients.get_mut(entity).ok())
+        else {
+            continue;
+        };
+
+        history.ack(packet.packet.sequence);
+    }
+}
+
+#[allow(clippy::type_complexity)]
+pub(super) fn queue_snapshots(
+    tick: Res<Tick>,
+    settings: Res<BandwidthSettings>,
+    network_to_world: Res<NetworkToWorld<Server>>,
+    mut metrics: ResMut<ServerMetrics>,
+    mut clients: Query<(
+        Entity,
+        &NetworkId,
+        Option<&Position>,
+        Option<&Spectator>,
+        &VisibleEntities,
+        &mut SnapshotHistory,
+        &mut PendingUpdates,
+    )>,
+    entities: Query<(
+        &NetworkId,
+        &Position,
+        Option<&MaybeNextPosition>,
+        Option<&Heading>,
+    )>,
+    mut last_tick: Local<Option<usize>>,
+) {
+    if *last_tick == Some(tick.current()) {
+        return;
+    }
+    *last_tick = Some(tick.current());
+
+    for (entity, id, position, spectator, visible, mut history, mut updates) in clients.iter_mut() {
+        // clients in the login queue see nothing yet
+        let Some(center) = position
+            .copied()
+            .or_else(|| spectator.and_then(Spectator::center))
+        else {
+            continue;
+        };
+
+        let known = history.latest();
+        let mut coalesced = 0;
+        let state = visible
+            .iter()
+            .filter_map(|other| network_to_world.get(&other).copied())
+            .chain([entity])
+            .filter_map(|other| entities.get(other).ok())
+            .map(|(&other, &position, next_position, heading)| {
+                let actual = match heading {
+                    Some(heading) => EntityState {
+                        target: heading.target,
+                        origin: heading.origin,
+                    },
+                    None => {
+                        let position = next_position.and_then(|n| n.position()).unwrap_or(position);
+                        EntityState {
+                            target: position,
+                            origin: position,
+                        }
+                    }
+                };
+
+                // clients always know where they themselves are going
+                let detail = if other == *id {
+                    Detail::Full
+                } else {
+                    Detail::at(center.taxi_distance(position), &settings)
+                };
+                let known = known.and_then(|known| known.get(&other));
+                let state = detail.state(&settings, tick.current(), other, known, actual);
+                coalesced += u64::from(state != actual);
+                (other, state)
+            })
+            .collect();
+        metrics.updates_coalesced += coalesced;
+
+        // the client's own entity first, then the closest
+        let priority =
+            |other, state: &EntityState| (other != *id, center.taxi_distance(state.target));
+        let Some(snapshot) = history.snapshot(state, priority) else {
+            continue;
+        };
+
+        metrics.full_snapshots_sent += u64::from(snapshot.baseline.is_none());
+        if let Err(e) = updates.push(*id, snapshot) {
+            error!("Failed to queue snapshot: {}", e);
+        }
+    }
+}
+
+pub(super) fn apply_snapshots(
+    mut snapshots: ResMut<ReceivedSnapshots>,
+    mut targets: EventWriter<Target>,
+    packets: Res<Packets<Snapshot>>,
+    network_to_world: Res<NetworkToWorld<Client>>,
+    server: Query<&Network<Server>, Without<Transferring>>,
+) {
+    for snapshot in packets.iter() {
+        if !snapshots.receive(&snapshot) {
+            continue;
+        }
+
+        if let Ok(server) = server.get_single() {
+            let _ = server.send(AckSnapshot {
+                sequence: snapshot.sequence,
+            });
+        }
+    }
+
+    let ReceivedSnapshots { received, applied } = &mut *snapshots;
+    let Some((_, latest)) = received.back() else {
+        return;
+    };
+
+    // entities may only be spawned after their state arrived
+    applied.retain(|id, _| network_to_world.contains_key(id));
+    for (id, state) in latest {
+        let Some(&entity) = network_to_world.get(id) else {
+            continue;
+        };
+
+        if applied.get(id) == Some(state) {
+            continue;
+        }
+
+        targets.send(Target::new(entity, state.target, state.origin));
+        applied.insert(*id, *state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{
+        connection::next_id,
+        harness::Harness,
+        packet::{EncodedPacket, ServerPacket},
+    };
+
+    fn state(entities: &[(u64, i32)]) -> State {
+        entities
+            .iter()
+            .map(|&(id, x)| {
+                (
+                    NetworkId::from(id),
+                    EntityState {
+                        target: Position { x, y: 0 },
+                        origin: Position { x: 0, y: 0 },
+                    },
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn snapshots_only_carry_changes_since_the_acked_baseline() {
+        let mut history = SnapshotHistory::default();
+        let mut received = ReceivedSnapshots::default();
+
+        let full = history
+            .snapshot(state(&[(1, 1), (2, 2)]), |_, _| 0)
+            .unwrap();
+        assert_eq!(full.baseline, None);
+        assert!(received.receive(&full));
+        history.ack(full.sequence);
+
+        let delta = history
+            .snapshot(state(&[(1, 5), (3, 3)]), |_, _| 0)
+            .unwrap();
+        assert_eq!(delta.baseline, Some(full.sequence));
+        assert_eq!(delta.removed, vec![NetworkId::from(2)]);
+        let mut entities = delta.entities.clone();
+        entities.sort_by_key(|delta| *delta.id);
+        assert_eq!(
+            entities,
+            vec![
+                EntityDelta {
+                    id: NetworkId::from(1),
+                    target_x: Some(5),
+                    target_y: None,
+                    origin_x: None,
+                    origin_y: None,
+                },
+                EntityDelta {
+                    id: NetworkId::from(3),
+                    target_x: Some(3),
+                    target_y: Some(0),
+                    origin_x: Some(0),
+                    origin_y: Some(0),
+                },
+            ]
+        );
+
+        assert!(received.receive(&delta));
+        assert_eq!(
+            received.received.back().unwrap().1,
+            state(&[(1, 5), (3, 3)])
+        );
+        assert!(history
+            .snapshot(state(&[(1, 5), (3, 3)]), |_, _| 0)
+            .is_none());
+    }
+
+    #[test]
+    fn too_old_baselines_fall_back_to_full_snapshots() {
+        let mut history = SnapshotHistory::default();
+        let first = history.snapshot(state(&[(1, 0)]), |_, _| 0).unwrap();
+        history.ack(first.sequence);
+
+        for x in 1..=HISTORY as i32 + 1 {
+            let snapshot = history.snapshot(state(&[(1, x)]), |_, _| 0).unwrap();
+            assert_eq!(snapshot.baseline.is_none(), x > HISTORY as i32);
+        }
+    }
+
+    #[test]
+    fn snapshots_too_large_for_a_packet_are_sent_in_parts() {
+        let mut history = SnapshotHistory::default();
+        let mut received = ReceivedSnapshots::default();
+        let crowd = state(&(1..=500).map(|id| (id, id as i32)).collect::<Vec<_>>());
+        let distance = |id: NetworkId, _: &EntityState| *id;
+
+        let mut parts = Vec::new();
+        while received.received.back().map(|(_, state)| state) != Some(&crowd) {
+            let snapshot = history.snapshot(crowd.clone(), distance).unwrap();
+            let packet = EncodedPacket::try_encode::<_, ServerPacket>(snapshot.clone()).unwrap();
+            assert!(packet.bytes().len() - 4 <= MAX_PACKET_LENGTH);
+            assert!(received.receive(&snapshot));
+            history.ack(snapshot.sequence);
+            parts.push(snapshot);
+            assert!(parts.len() <= 10, "the crowd is never completely sent");
+        }
+
+        assert!(parts.len() > 1);
+        // the closest entities are sent first
+        assert!(parts[0].entities.iter().all(|delta| *delta.id <= 200));
+        assert!(parts[0].removed.is_empty());
+    }
+
+    #[test]
+    fn unacknowledged_parts_are_sent_again() {
+        let mut history = SnapshotHistory::default();
+        let crowd = state(&(1..=500).map(|id| (id, id as i32)).collect::<Vec<_>>());
+        let distance = |id: NetworkId, _: &EntityState| *id;
+
+        let first = history.snapshot(crowd, distance).unwrap();
+        // entities left out are held at what the client knows, like far ones
+        let known = history.latest().unwrap().clone();
+        let again = history
+            .snapshot(known.clone(), distance)
+            .expect("the part was not sent again");
+        assert_eq!(again.baseline, first.baseline);
+
+        history.ack(again.sequence);
+        assert!(history.snapshot(known, distance).is_none());
+    }
+
+    #[test]
+    fn clients_seeing_a_crowd_stay_connected() {
+        let mut harness = Harness::new(1);
+        harness.wait_for_players();
+        let crowd: Vec<_> = (0..300)
+            .map(|i| {
+                let id = NetworkId::from(next_id());
+                let position = Position {
+                    x: i % 9 - 4,
+                    y: i / 9 % 9 - 4,
+                };
+                let entity = harness.server.world.spawn((id, position)).id();
+                harness
+                    .server
+                    .world
+                    .resource_mut::<NetworkToWorld<Server>>()
+                    .insert(id, entity);
+                id
+            })
+            .collect();
+
+        harness.step_until("the client knows the whole crowd", |harness| {
+            let snapshots = harness.clients[0].world.resource::<ReceivedSnapshots>();
+            snapshots
+                .received
+                .back()
+                .is_some_and(|(_, state)| crowd.iter().all(|id| state.contains_key(id)))
+        });
+        assert!(harness.client_id(0).is_some());
+    }
+
+    #[test]
+    fn further_entities_are_updated_less_often() {
+        let settings = BandwidthSettings::default();
+        let id = NetworkId::from(1);
+        let known = EntityState {
+            target: Position { x: 0, y: 0 },
+            origin: Position { x: 0, y: 0 },
+        };
+        let moved = |x| EntityState {
+            target: Position { x, y: 0 },
+            ..known
+        };
+
+        let updated = |distance, x| {
+            (0..settings.mid_interval)
+                .filter(|&tick| {
+                    Detail::at(distance, &settings).state(
+                        &settings,
+                        tick,
+                        id,
+                        Some(&known),
+                        moved(x),
+                    ) != known
+                })
+                .count()
+        };
+
+        assert_eq!(updated(settings.near_distance, 1), settings.mid_interval);
+        assert_eq!(updated(settings.mid_distance, 1), 1);
+        assert_eq!(updated(settings.mid_distance + 1, 1), 0);
+        assert_eq!(
+            updated(settings.mid_distance + 1, settings.far_change as i32),
+            settings.mid_interval
+        );
+        // entities new to the client are always sent
+        assert_eq!(
+            Detail::Significant.state(&settings, 0, id, None, moved(1)),
+            moved(1)
+        );
+    }
+}
@@ -2,21 +2,23 @@ use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use speedy::Readable;
 use tracing::trace;
 
-use super::packet::{EncodedPacket, Packet};
+use super::{
+    packet::{EncodedPacket, Packet},
+    pool::{PooledBuffer, FRAMES},
+};
 
-const MAX_PACKET_LENGTH: usize = 5000;
+pub(super) const MAX_PACKET_LENGTH: usize = 5000;
 
 pub(super) struct Socket<T> {
     io: T,
-    buffer: Vec<u8>,
+    /// Taken from the pool once the first packet is received, so sending
+    /// sockets never hold one.
+    buffer: Option<PooledBuffer>,
 }
 
 impl<T> Socket<T> {
     pub(crate) fn new(io: T) -> Self {
-        Self {
-            io,
-            buffer: Vec::with_capacity(MAX_PACKET_LENGTH),
-        }
+        Self { io, buffer: None }
     }
 }
 
@@ -37,20 +39,26 @@ where
             ));
         }
 
-        self.buffer.resize(length, 0);
+        let buffer = self.buffer.get_or_insert_with(|| FRAMES.take());
+        buffer.resize(length, 0);
 
         Ok(length)
     }
 
-    pub(super) async fn next<'d, P>(&mut self) -> Result<P, std::io::Error>
+    /// Reads the packet [`Socket::ready`] announced into the pooled frame, and
+    /// decodes it from there. Strings are copied out of the frame once, and
+    /// never borrowed from it: packets are handed to systems over channels and
+    /// outlive the frame, which is reused for the next packet.
+    pub(super) async fn next<P>(&mut self) -> Result<P, std::io::Error>
     where
-        P: Packet + Readable<'d, speedy::LittleEndian>,
+        P: Packet + for<'a> Readable<'a, speedy::LittleEndian>,
     {
-        trace!("Reading packet of length {}", self.buffer.len());
-        self.io.read_exact(&mut self.buffer).await?;
-        trace!("Read packet: {:?}", &self.buffer);
+        let buffer = self.buffer.get_or_insert_with(|| FRAMES.take());
+        trace!("Reading packet of length {}", buffer.len());
+        self.io.read_exact(buffer).await?;
+        trace!("Read packet: {:?}", buffer);
 
-        let packet = speedy::Readable::read_from_buffer_copying_data(&self.buffer)?;
+        let packet = P::read_from_buffer(buffer)?;
 
         Ok(packet)
     }
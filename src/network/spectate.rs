@@ -0,0 +1,262 @@
+use std::sync::{Arc, RwLock};
+
+use bevy::{
+    prelude::{Added, Commands, Component, Entity, EventReader, Query, Res, ResMut, Resource},
+    utils::HashSet,
+};
+use speedy::{Readable, Writable};
+use tracing::{info, warn};
+
+use super::{
+    event::Disconnected,
+    mediator::{Middleware, PacketWithConnId, Verdict},
+    metrics::ServerMetrics,
+    packet::{AcceptConnection, AnyPacketWithConnId, ClientPacket, ClientPacketKind, Spectate},
+    plugin::{Client, Network, Packets, Server},
+    state::{ConnectionState, ConnectionStates},
+};
+use crate::{
+    id::{NetworkId, NetworkToWorld},
+    path::plugin::Position,
+};
+
+// resources
+/// Present on clients that join as spectators.
+#[derive(Resource, Clone, Copy, Debug)]
+pub(crate) struct Spectating(pub(crate) Viewpoint);
+
+/// The connections of spectators, shared with the middleware that keeps them
+/// from playing.
+#[derive(Resource, Clone, Default)]
+pub(super) struct SpectatorIds(Arc<RwLock<HashSet<NetworkId>>>);
+
+// components
+/// A client watching the game. Spectators have no position of their own, so
+/// nobody sees them, and they may not send gameplay packets.
+#[derive(Component, Debug)]
+pub(crate) struct Spectator {
+    viewpoint: Viewpoint,
+    /// Where the spectator looks from, kept when the watched entity is gone.
+    center: Option<Position>,
+    watched: Option<Entity>,
+}
+
+impl Spectator {
+    fn new(viewpoint: Viewpoint) -> Self {
+        Self {
+            viewpoint,
+            center: None,
+            watched: None,
+        }
+    }
+
+    pub(crate) fn center(&self) -> Option<Position> {
+        self.center
+    }
+
+    /// The entity whose view range, sight and visibility rules decide what the
+    /// spectator sees, while it is watching one.
+    pub(crate) fn watched(&self) -> Option<Entity> {
+        self.watched
+    }
+}
+
+// util
+/// What a spectator watches.
+#[derive(Readable, Writable, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Viewpoint {
+    /// Sees what the entity sees, following it around.
+    Entity(NetworkId),
+    /// Sees what an entity standing still at `x`, `y` would.
+    Region { x: i32, y: i32 },
+}
+
+pub(super) fn spawn_spectator(
+    commands: &mut Commands,
+    states: &ConnectionStates,
+    entity: Entity,
+    client: &Network<Client>,
+    viewpoint: Viewpoint,
+) {
+    info!("{} joined as a spectator of {:?}", client.id(), viewpoint);
+    states.set(client.id(), ConnectionState::InGame);
+    let _ = client.send(AcceptConnection {
+        connection_id: client.id(),
+    });
+
+    commands.entity(entity).insert(Spectator::new(viewpoint));
+}
+
+/// Drops the packets that would let a spectator act in the game.
+pub(super) fn reject_gameplay_packets(spectators: SpectatorIds) -> impl Middleware<ClientPacket> {
+    move |packet: AnyPacketWithConnId<ClientPacket>| {
+        let kind = packet.packet_kind();
+        let gameplay = matches!(
+            kind,
+            ClientPacketKind::SendMessage | ClientPacketKind::PathTargetRequest
+        );
+
+        if gameplay && spectators.0.read().unwrap().contains(&packet.connection_id) {
+            warn!(
+                "Rejected {:?} from spectator {}",
+                kind, packet.connection_id
+            );
+            return Verdict::Drop;
+        }
+        Verdict::Pass(packet)
+    }
+}
+
+// systems
+pub(super) fn track_spectators(
+    spectators: Res<SpectatorIds>,
+    mut disconnected: EventReader<Disconnected>,
+    added: Query<&NetworkId, Added<Spectator>>,
+) {
+    let mut ids = spectators.0.write().unwrap();
+    for event in disconnected.iter() {
+        ids.remove(&event.id);
+    }
+    ids.extend(added.iter().copied());
+}
+
+pub(super) fn move_spectators(
+    packets: Res<Packets<PacketWithConnId<Spectate>>>,
+    network_to_world: Res<NetworkToWorld<Server>>,
+    mut spectators: Query<&mut Spectator>,
+) {
+    for packet in packets.iter() {
+        let Some(mut spectator) = network_to_world
+            .get(&packet.connection_id)
+            .and_then(|&entity| spectators.get_mut(entity).ok())
+        else {
+            warn!(
+                "Rejected viewpoint change from player {}",
+                packet.connection_id
+            );
+            continue;
+        };
+
+        spectator.viewpoint = packet.packet.viewpoint;
+    }
+}
+
+/// Moves spectators to their viewpoint, from where ambit works out what they
+/// see as if they stood there.
+pub(super) fn follow_viewpoints(
+    mut metrics: ResMut<ServerMetrics>,
+    network_to_world: Res<NetworkToWorld<Server>>,
+    entities: Query<&Position>,
+    mut spectators: Query<&mut Spectator>,
+) {
+    metrics.spectators = spectators.iter().len();
+
+    for mut spectator in spectators.iter_mut() {
+        spectator.watched = match spectator.viewpoint {
+            Viewpoint::Entity(id) => network_to_world
+                .get(&id)
+                .copied()
+                .filter(|&entity| entities.contains(entity)),
+            Viewpoint::Region { .. } => None,
+        };
+        let center = match spectator.viewpoint {
+            Viewpoint::Entity(_) => spectator
+                .watched
+                .and_then(|entity| entities.get(entity).ok())
+                .copied(),
+            Viewpoint::Region { x, y } => Some(Position { x, y }),
+        };
+        if center.is_some() {
+            spectator.center = center;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::With;
+
+    use super::*;
+    use crate::{
+        ambit::{
+            plugin::Player,
+            rule::{Detection, Stealth},
+            view::ViewRange,
+        },
+        network::{harness::Harness, queue::CapacitySettings},
+        path::{packet::PathTargetRequest, plugin::Heading},
+    };
+
+    fn sees(harness: &Harness, client: usize, id: NetworkId) -> bool {
+        harness.clients[client]
+            .world
+            .resource::<NetworkToWorld<Client>>()
+            .contains_key(&id)
+    }
+
+    #[test]
+    fn spectators_watch_without_taking_a_player_slot() {
+        let mut harness = Harness::with_server(1, |server| {
+            server.insert_resource(CapacitySettings {
+                max_players: Some(1),
+                ..CapacitySettings::default()
+            });
+        });
+        harness.wait_for_players();
+        let player = harness.client_id(0).unwrap();
+
+        let spectator = harness.add_spectator(Viewpoint::Entity(player));
+        harness.wait_until_client_sees(spectator, player, Position { x: 0, y: 0 });
+
+        assert_eq!(harness.count::<(), With<Player>>(None), 1);
+        assert_eq!(harness.count::<(), With<Spectator>>(None), 1);
+        assert_eq!(harness.count::<(), With<Position>>(None), 1);
+        let metrics = harness.server.world.resource::<ServerMetrics>();
+        assert_eq!((metrics.spectators, metrics.queued), (1, 0));
+    }
+
+    #[test]
+    fn spectators_see_what_the_watched_entity_sees() {
+        let mut harness = Harness::new(2);
+        harness.wait_for_players();
+        let ids: Vec<_> = (0..2).map(|i| harness.client_id(i).unwrap()).collect();
+        let entity =
+            |harness: &Harness, id| harness.server.world.resource::<NetworkToWorld<Server>>()[&id];
+        let (scout, sneak) = (entity(&harness, ids[0]), entity(&harness, ids[1]));
+        harness.server.world.entity_mut(scout).insert(Detection(3));
+        harness.server.world.entity_mut(sneak).insert(Stealth(3));
+
+        let follower = harness.add_spectator(Viewpoint::Entity(ids[0]));
+        let onlooker = harness.add_spectator(Viewpoint::Region { x: 0, y: 0 });
+        harness.step_until("only the follower sees through the stealth", |harness| {
+            sees(harness, follower, ids[1])
+                && sees(harness, onlooker, ids[0])
+                && !sees(harness, onlooker, ids[1])
+        });
+
+        harness.server.world.entity_mut(sneak).remove::<Stealth>();
+        harness.server.world.entity_mut(scout).insert(ViewRange {
+            enter: 0,
+            hysteresis: 0,
+            ..ViewRange::default()
+        });
+        harness.step_until("only the onlooker sees the other player", |harness| {
+            !sees(harness, follower, ids[1]) && sees(harness, onlooker, ids[1])
+        });
+    }
+
+    #[test]
+    fn gameplay_packets_from_spectators_are_rejected() {
+        let mut harness = Harness::new(1);
+        harness.wait_for_players();
+        let player = harness.client_id(0).unwrap();
+        let spectator = harness.add_spectator(Viewpoint::Region { x: 0, y: 0 });
+        harness.wait_until_client_sees(spectator, player, Position { x: 0, y: 0 });
+
+        harness.send_from_client(spectator, PathTargetRequest { x: 5, y: 5 });
+        harness.send_from_client(0, PathTargetRequest { x: 1, y: 0 });
+        harness.wait_until_client_sees(spectator, player, Position { x: 1, y: 0 });
+
+        assert_eq!(harness.count::<(), With<Heading>>(None), 1);
+    }
+}
@@ -0,0 +1,155 @@
+use std::sync::{Arc, RwLock};
+
+use bevy::{
+    prelude::{EventReader, Res, Resource},
+    utils::HashMap,
+};
+use tracing::warn;
+
+use super::{
+    event::Disconnected,
+    mediator::{Middleware, Verdict},
+    packet::{AnyPacketWithConnId, ClientPacket},
+};
+use crate::id::NetworkId;
+
+// resources
+/// The state of every client connection, shared with the middleware that
+/// rejects packets sent out of state.
+#[derive(Resource, Clone, Default)]
+pub(crate) struct ConnectionStates(Arc<RwLock<HashMap<NetworkId, ConnectionState>>>);
+
+impl ConnectionStates {
+    /// Connections the server has not set up yet are still connecting.
+    pub(crate) fn get(&self, id: NetworkId) -> ConnectionState {
+        self.0
+            .read()
+            .unwrap()
+            .get(&id)
+            .copied()
+            .unwrap_or(ConnectionState::Connecting)
+    }
+
+    pub(crate) fn set(&self, id: NetworkId, state: ConnectionState) {
+        self.0.write().unwrap().insert(id, state);
+    }
+
+    pub(crate) fn remove(&self, id: NetworkId) {
+        self.0.write().unwrap().remove(&id);
+    }
+}
+
+// util
+/// Where a client connection is in its lifetime, which decides the packets it
+/// may send. See [`ClientPacketKind::valid_states`].
+///
+/// [`ClientPacketKind::valid_states`]: super::packet::ClientPacketKind::valid_states
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum ConnectionState {
+    /// The QUIC handshake is still under way.
+    Connecting,
+    /// Connected, waiting for the client to join or present a transfer ticket.
+    Handshaking,
+    /// A transfer ticket was presented and is being redeemed.
+    Authenticating,
+    /// Joined and waiting in the login queue.
+    Loading,
+    /// Playing or spectating.
+    InGame,
+    /// Sent off to another server, until the client either leaves or reports
+    /// that the transfer failed.
+    Transferring,
+    /// Being closed by the server.
+    Disconnecting,
+}
+
+/// Drops packets the sender's connection state does not allow, and moves the
+/// connection on when it sends a packet that ends a state.
+pub(super) fn reject_out_of_state_packets(
+    states: ConnectionStates,
+) -> impl Middleware<ClientPacket> {
+    move |packet: AnyPacketWithConnId<ClientPacket>| {
+        let kind = packet.packet_kind();
+        let state = states.get(packet.connection_id);
+
+        if !kind.valid_states().contains(&state) {
+            warn!(
+                "Rejected {:?} from {} while {:?}",
+                kind, packet.connection_id, state
+            );
+            return Verdict::Drop;
+        }
+
+        // right away, so that a second join is already rejected
+        if let Some(next) = kind.next_state() {
+            states.set(packet.connection_id, next);
+        }
+
+        Verdict::Pass(packet)
+    }
+}
+
+// systems
+pub(super) fn forget_connection_states(
+    states: Res<ConnectionStates>,
+    mut disconnected: EventReader<Disconnected>,
+) {
+    let mut states = states.0.write().unwrap();
+    for event in disconnected.iter() {
+        states.remove(&event.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        network::packet::{AbortTransfer, Join, Role},
+        path::packet::PathTargetRequest,
+    };
+
+    fn passes<M>(middleware: &M, packet: ClientPacket) -> bool
+    where
+        M: Middleware<ClientPacket>,
+    {
+        let packet = AnyPacketWithConnId {
+            connection_id: NetworkId::from(1),
+            packet,
+        };
+        matches!(middleware.handle(packet), Verdict::Pass(_))
+    }
+
+    #[test]
+    fn packets_are_only_accepted_in_their_states() {
+        let states = ConnectionStates::default();
+        let middleware = reject_out_of_state_packets(states.clone());
+        let id = NetworkId::from(1);
+        let join = || {
+            ClientPacket::from(Join {
+                role: Role::Player,
+                account: None,
+            })
+        };
+        let walk = || ClientPacket::from(PathTargetRequest { x: 1, y: 0 });
+
+        assert!(!passes(&middleware, join()));
+
+        states.set(id, ConnectionState::Handshaking);
+        assert!(!passes(&middleware, walk()));
+        assert!(passes(&middleware, join()));
+        assert_eq!(states.get(id), ConnectionState::Loading);
+        assert!(!passes(&middleware, join()));
+        assert!(!passes(&middleware, walk()));
+
+        states.set(id, ConnectionState::InGame);
+        assert!(passes(&middleware, walk()));
+
+        states.set(id, ConnectionState::Transferring);
+        assert!(!passes(&middleware, walk()));
+        assert!(passes(&middleware, ClientPacket::from(AbortTransfer)));
+        assert_eq!(states.get(id), ConnectionState::InGame);
+
+        states.set(id, ConnectionState::Disconnecting);
+        assert!(!passes(&middleware, walk()));
+    }
+}
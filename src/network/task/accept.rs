@@ -29,7 +29,7 @@ where
         }
     }
 
-    pub(in crate::network) async fn _run(mut self, stop: async_std::channel::Receiver<()>) {
+    pub(in crate::network) async fn _run(mut self, stop: async_channel::Receiver<()>) {
         let stop = stop.recv().fuse();
         pin_mut!(stop);
         loop {
@@ -40,8 +40,8 @@ where
                 },
             };
 
-            let stream = match maybe_stream {
-                Ok(socket) => socket,
+            let accepted = match maybe_stream {
+                Ok(accepted) => accepted,
                 Err(e) => {
                     error!("Failed to accept new connection: {}", e);
                     break;
@@ -49,7 +49,8 @@ where
             };
 
             info!("New incoming connection");
-            match self.new_connections.send(Connection::new(stream)) {
+            let connection = Connection::new(accepted.connection, accepted.permit);
+            match self.new_connections.send(connection) {
                 Ok(_) => continue,
                 Err(e) => {
                     error!("Failed to send new connection: {}", e);
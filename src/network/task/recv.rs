@@ -1,11 +1,13 @@
+use crossbeam_channel::Sender;
 use futures::{pin_mut, AsyncRead, FutureExt};
 use speedy::Readable;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 use crate::{
-    channel::BroadcastChannel,
     id::NetworkId,
     network::{
+        error::Error,
+        event::{DisconnectReason, NetworkError},
         mediator::AnyPacketMediator,
         packet::{AnyPacketWithConnId, Packet},
         socket::Socket,
@@ -19,54 +21,58 @@ where
     socket: Socket<R>,
     packet_mediator: AnyPacketMediator<T>,
     connection_id: NetworkId,
+    errors: Sender<NetworkError>,
 }
 
-impl<'d, R, T> ReceivePacketsTask<R, T>
+impl<R, T> ReceivePacketsTask<R, T>
 where
-    R: AsyncRead + Unpin + 'd,
+    R: AsyncRead + Unpin,
     T: Packet,
 {
     pub(in crate::network) fn new(
         socket: R,
         packet_mediator: AnyPacketMediator<T>,
         connection_id: NetworkId,
+        errors: Sender<NetworkError>,
     ) -> Self {
         Self {
             socket: Socket::new(socket),
             packet_mediator,
             connection_id,
+            errors,
         }
     }
 
     pub(in crate::network) async fn _run(
         mut self,
-        stop: async_std::channel::Receiver<()>,
-        disconnect_broadcast: BroadcastChannel<()>,
-    ) where
+        stop: async_channel::Receiver<()>,
+        disconnected: async_channel::Receiver<()>,
+    ) -> DisconnectReason
+    where
         <T as Packet>::Kind: for<'a> From<&'a T>,
-        T: Readable<'d, speedy::LittleEndian>,
+        T: for<'a> Readable<'a, speedy::LittleEndian>,
     {
         let stop = stop.recv().fuse();
-        let disconnect = disconnect_broadcast.notified.recv().fuse();
+        let disconnect = disconnected.recv().fuse();
         pin_mut!(stop, disconnect);
 
-        loop {
+        let reason = loop {
             futures::select! {
                 length = self.socket.ready().fuse() => {
                     if let Err(e) = length {
                         error!("Failed to receive packet length: {}", e);
-                        break;
+                        break DisconnectReason::from(e);
                     }
                 },
-                _ = disconnect => break,
-                _ = stop => break,
+                _ = disconnect => break DisconnectReason::Closed,
+                _ = stop => break DisconnectReason::Quit,
             };
 
             let packet = match self.socket.next().await {
                 Ok(packet) => packet,
                 Err(e) => {
                     error!("Failed to receive packet: {}", e);
-                    break;
+                    break DisconnectReason::from(e);
                 }
             };
 
@@ -74,13 +80,28 @@ where
                 packet,
                 connection_id: self.connection_id,
             };
-            if let Err(e) = self.packet_mediator.send(packet_with_conn_id) {
-                error!("Failed to mediate packet: {}", e);
-                break;
+            match self.packet_mediator.send(packet_with_conn_id) {
+                Ok(()) => {}
+                // the packet is dropped but the connection is still usable
+                Err(error @ Error::UnregisteredPacket(_)) => {
+                    error!("Failed to mediate packet: {}", error);
+                    let _ = self.errors.send(NetworkError {
+                        id: Some(self.connection_id),
+                        error,
+                    });
+                }
+                Err(Error::Rejected(reason)) => {
+                    warn!("Rejected packet from {}: {}", self.connection_id, reason);
+                    break DisconnectReason::Rejected(reason);
+                }
+                Err(e) => {
+                    error!("Failed to mediate packet: {}", e);
+                    break DisconnectReason::Error(e.to_string());
+                }
             }
-        }
-        let _ = disconnect_broadcast.notify.send(());
+        };
         info!("Disconnecting receive packets task: {}", self.connection_id);
+        reason
     }
 }
 
@@ -128,7 +149,7 @@ mod tests {
     //     let (_network_events, packets, receive_packets_task) =
     //         make_receive_packets_task::<T>(reader);
     //
-    //     let (quit, quit_receiver) = async_std::channel::unbounded(());
+    //     let (quit, quit_receiver) = async_channel::unbounded(());
     //     let receive_task = BroadcastChannel::<()>::channel();
     //     let thread = tokio::task::spawn(async move {
     //         receive_packets_task
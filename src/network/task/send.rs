@@ -4,17 +4,18 @@ use futures::{pin_mut, AsyncWrite, FutureExt};
 use tracing::{error, info};
 
 use crate::{
-    channel::BroadcastChannel,
     id::NetworkId,
     network::{
+        event::DisconnectReason,
         packet::{EncodedPacket, Heartbeat, Packet},
+        runtime,
         socket::Socket,
     },
 };
 
 pub(in crate::network) struct SendPacketsTask<W> {
     socket: Socket<W>,
-    queued_packets: async_std::channel::Receiver<EncodedPacket>,
+    queued_packets: async_channel::Receiver<EncodedPacket>,
     connection_id: NetworkId,
 }
 
@@ -24,7 +25,7 @@ where
 {
     pub(in crate::network) fn new(
         io: W,
-        queued_packets: async_std::channel::Receiver<EncodedPacket>,
+        queued_packets: async_channel::Receiver<EncodedPacket>,
         connection_id: NetworkId,
     ) -> Self {
         Self {
@@ -36,35 +37,41 @@ where
 
     pub(in crate::network) async fn _run<P: Packet>(
         mut self,
-        stop: async_std::channel::Receiver<()>,
-        disconnect_broadcast: BroadcastChannel<()>,
-    ) {
+        stop: async_channel::Receiver<()>,
+        disconnected: async_channel::Receiver<()>,
+    ) -> DisconnectReason {
         let stop = stop.recv().fuse();
-        let disconnect = disconnect_broadcast.notified.recv().fuse();
+        let disconnect = disconnected.recv().fuse();
         // heartbeat.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
         pin_mut!(stop, disconnect);
 
-        loop {
+        let reason = loop {
             let packet = futures::select! {
                 maybe_packet = self.queued_packets.recv().fuse() => {
                     match maybe_packet {
                         Ok(packet) => packet,
-                        Err(_) => break,
+                        Err(_) => break DisconnectReason::Closed,
                     }
                 },
-                _ = async_std::task::sleep(Duration::from_secs(1)).fuse() => {
-                    EncodedPacket::try_encode::<Heartbeat, P>(Heartbeat).unwrap()
+                _ = runtime::sleep(Duration::from_secs(1)).fuse() => {
+                    match EncodedPacket::try_encode::<Heartbeat, P>(Heartbeat) {
+                        Ok(packet) => packet,
+                        Err(e) => {
+                            error!("Failed to encode heartbeat: {}", e);
+                            break DisconnectReason::Error(e.to_string());
+                        }
+                    }
                 },
-                _ = disconnect => break,
-                _ = stop => break,
+                _ = disconnect => break DisconnectReason::Closed,
+                _ = stop => break DisconnectReason::Quit,
             };
 
             if let Err(e) = self.socket.send(packet).await {
                 error!("{}", e);
-                break;
+                break DisconnectReason::from(e);
             }
-        }
-        let _ = disconnect_broadcast.notify.send(());
+        };
         info!("Disconnecting send packets task: {}", self.connection_id);
+        reason
     }
 }
@@ -0,0 +1,356 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use bevy::{
+    prelude::{
+        Commands, Component, Entity, EventReader, EventWriter, Query, Res, ResMut, Resource,
+        Without,
+    },
+    utils::{HashMap, HashSet},
+};
+use rand::{rngs::OsRng, Rng};
+use speedy::{Readable, Writable};
+use tracing::{error, info};
+
+use super::{
+    event::{ConnectionFailed, Disconnected},
+    guard::{Account, ConnectionGuard},
+    mediator::PacketWithConnId,
+    packet::{AbortTransfer, PresentTicket, Transfer},
+    plugin::{spawn_player, Client, ConnectionRequester, Network, Packets, Server},
+    state::{ConnectionState, ConnectionStates},
+};
+use crate::{
+    ambit::plugin::Player,
+    id::{NetworkId, NetworkToWorld},
+    path::plugin::Position,
+};
+
+const TICKET_LIFETIME: Duration = Duration::from_secs(30);
+/// How long a presented ticket is held before it is rejected as unknown. The
+/// client may arrive before the handoff announcing it, as the two travel over
+/// different connections.
+const HANDOFF_GRACE: Duration = Duration::from_secs(5);
+
+/// Lets a client into the server it was transferred to without joining again,
+/// so it must not be guessable.
+#[derive(Readable, Writable, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct Ticket(u128);
+
+impl Ticket {
+    fn generate() -> Self {
+        Self(OsRng.gen())
+    }
+}
+
+// resources
+/// Tickets this server has been told to expect from clients handed off by
+/// another server.
+#[derive(Resource, Default)]
+pub(crate) struct TransferTickets {
+    expected: HashMap<Ticket, ExpectedTransfer>,
+    /// Tickets presented before their handoff arrived.
+    presented: Vec<PresentedTicket>,
+}
+
+impl TransferTickets {
+    pub(crate) fn expect(&mut self, ticket: Ticket, position: Position, account: Option<Account>) {
+        self.expected.insert(
+            ticket,
+            ExpectedTransfer {
+                position,
+                account,
+                expires_at: Instant::now() + TICKET_LIFETIME,
+            },
+        );
+    }
+
+    fn redeem(&mut self, ticket: Ticket) -> Option<ExpectedTransfer> {
+        let now = Instant::now();
+        self.expected.retain(|_, expected| expected.expires_at > now);
+        self.expected.remove(&ticket)
+    }
+}
+
+/// Addresses clients can be transferred to, those of servers a peer link is
+/// open to.
+#[derive(Resource, Default)]
+pub(crate) struct TransferDestinations(pub(crate) HashSet<SocketAddr>);
+
+struct ExpectedTransfer {
+    position: Position,
+    /// The account the client joined the other server with.
+    account: Option<Account>,
+    expires_at: Instant,
+}
+
+struct PresentedTicket {
+    connection_id: NetworkId,
+    ticket: Ticket,
+    presented_at: Instant,
+}
+
+/// A transfer the client is currently performing. The old server connection
+/// is kept until the new server has accepted the ticket.
+#[derive(Resource)]
+pub(crate) struct PendingTransfer {
+    addr: SocketAddr,
+    ticket: Ticket,
+    pub(super) connection: Option<Entity>,
+}
+
+impl PendingTransfer {
+    pub(crate) fn ticket(&self) -> Ticket {
+        self.ticket
+    }
+}
+
+// components
+/// Marks the server connection that is being transferred to.
+#[derive(Component, Default)]
+pub(crate) struct Transferring;
+
+// events
+/// Hands the client of `entity` off to the server listening on `addr`.
+pub(crate) struct TransferClient {
+    pub(crate) entity: Entity,
+    pub(crate) addr: SocketAddr,
+}
+
+/// The client of `entity` could not be transferred and stays on this server.
+pub(crate) struct TransferFailed {
+    pub(crate) entity: Entity,
+}
+
+/// A ticket has been sent to a client. The server at `addr` must be told to
+/// expect it with [`TransferTickets::expect`] before the client arrives.
+pub(crate) struct TicketIssued {
+    pub(crate) ticket: Ticket,
+    pub(crate) addr: SocketAddr,
+    pub(crate) position: Position,
+    pub(crate) account: Option<Account>,
+}
+
+// systems
+pub(super) fn issue_tickets(
+    mut transfers: EventReader<TransferClient>,
+    mut issued: EventWriter<TicketIssued>,
+    mut failed: EventWriter<TransferFailed>,
+    destinations: Res<TransferDestinations>,
+    states: Res<ConnectionStates>,
+    clients: Query<(&Network<Client>, &Position, Option<&Account>)>,
+) {
+    for transfer in transfers.iter() {
+        let Ok((client, &position, account)) = clients.get(transfer.entity) else {
+            continue;
+        };
+
+        // nobody could tell the other server to expect the client
+        if !destinations.0.contains(&transfer.addr) {
+            error!(
+                "No peer link to {} to transfer {} to",
+                transfer.addr,
+                client.id()
+            );
+            failed.send(TransferFailed {
+                entity: transfer.entity,
+            });
+            continue;
+        }
+
+        let ticket = Ticket::generate();
+
+        if let Err(e) = client.send(Transfer {
+            ip: transfer.addr.ip(),
+            port: transfer.addr.port(),
+            ticket,
+        }) {
+            error!("Failed to send transfer to {}: {}", client.id(), e);
+            failed.send(TransferFailed {
+                entity: transfer.entity,
+            });
+            continue;
+        }
+
+        info!("Transferring {} to {}", client.id(), transfer.addr);
+        states.set(client.id(), ConnectionState::Transferring);
+        issued.send(TicketIssued {
+            ticket,
+            addr: transfer.addr,
+            position,
+            account: account.cloned(),
+        });
+    }
+}
+
+pub(super) fn redeem_tickets(
+    mut commands: Commands,
+    mut tickets: ResMut<TransferTickets>,
+    packets: Res<Packets<PacketWithConnId<PresentTicket>>>,
+    states: Res<ConnectionStates>,
+    guard: Option<Res<ConnectionGuard>>,
+    mut network_to_world: ResMut<NetworkToWorld<Server>>,
+    clients: Query<&Network<Client>, Without<Player>>,
+) {
+    let now = Instant::now();
+    tickets
+        .presented
+        .extend(packets.iter().map(|packet| PresentedTicket {
+            connection_id: packet.connection_id,
+            ticket: packet.packet.ticket,
+            presented_at: now,
+        }));
+
+    for presented in std::mem::take(&mut tickets.presented) {
+        let Some(&entity) = network_to_world.get(&presented.connection_id) else {
+            continue;
+        };
+
+        let Ok(client) = clients.get(entity) else {
+            error!(
+                "{} presented a ticket after joining",
+                presented.connection_id
+            );
+            continue;
+        };
+
+        let expected = match tickets.redeem(presented.ticket) {
+            Some(expected) => expected,
+            None if now - presented.presented_at < HANDOFF_GRACE => {
+                tickets.presented.push(presented);
+                continue;
+            }
+            None => {
+                error!("{} presented an unknown ticket", presented.connection_id);
+                refuse(&mut commands, &states, &mut network_to_world, client.id());
+                continue;
+            }
+        };
+
+        if let Some(account) = expected.account {
+            if guard
+                .as_ref()
+                .is_some_and(|guard| guard.is_account_banned(&account))
+            {
+                info!("Refusing the transfer of banned account {}", account);
+                refuse(&mut commands, &states, &mut network_to_world, client.id());
+                continue;
+            }
+            commands.entity(entity).insert(account);
+        }
+        spawn_player(&mut commands, &states, entity, client, expected.position);
+    }
+}
+
+/// Drops a client that may not play here. Despawned here, so no disconnection
+/// event will forget it.
+fn refuse(
+    commands: &mut Commands,
+    states: &ConnectionStates,
+    network_to_world: &mut NetworkToWorld<Server>,
+    id: NetworkId,
+) {
+    states.remove(id);
+    // dropping the network component closes the connection
+    if let Some(entity) = network_to_world.remove(&id) {
+        commands.entity(entity).despawn();
+    }
+}
+
+/// Clients whose transfer failed already play here again, as the packet moved
+/// their connection back in game.
+pub(super) fn receive_aborted_transfers(
+    packets: Res<Packets<PacketWithConnId<AbortTransfer>>>,
+    network_to_world: Res<NetworkToWorld<Server>>,
+    mut failed: EventWriter<TransferFailed>,
+) {
+    for packet in packets.iter() {
+        info!("{} stays, its transfer failed", packet.connection_id);
+        if let Some(&entity) = network_to_world.get(&packet.connection_id) {
+            failed.send(TransferFailed { entity });
+        }
+    }
+}
+
+pub(super) fn begin_transfer(
+    mut commands: Commands,
+    packets: Res<Packets<Transfer>>,
+    connection_requester: Res<ConnectionRequester>,
+) {
+    for transfer in packets.iter() {
+        info!("Transferring to {}", transfer.addr());
+        let _ = connection_requester.0.send_blocking(transfer.addr());
+        commands.insert_resource(PendingTransfer {
+            addr: transfer.addr(),
+            ticket: transfer.ticket,
+            connection: None,
+        });
+    }
+}
+
+pub(super) fn abort_transfer(
+    mut commands: Commands,
+    pending: Option<Res<PendingTransfer>>,
+    mut failures: EventReader<ConnectionFailed>,
+    mut disconnections: EventReader<Disconnected>,
+    servers: Query<&Network<Server>, Without<Transferring>>,
+) {
+    let Some(pending) = pending else {
+        failures.clear();
+        disconnections.clear();
+        return;
+    };
+
+    let failed = failures.iter().any(|failure| failure.addr == pending.addr);
+    let rejected = disconnections
+        .iter()
+        .any(|disconnected| Some(disconnected.entity) == pending.connection);
+
+    if failed || rejected {
+        error!("Transfer to {} failed", pending.addr);
+        commands.remove_resource::<PendingTransfer>();
+        // the old server holds back everything else until it hears of this
+        for server in servers.iter() {
+            let _ = server.send(AbortTransfer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::Events;
+
+    use super::*;
+    use crate::{
+        network::{harness::Harness, test_utils::next_local_addr},
+        path::packet::PathTargetRequest,
+    };
+
+    #[test]
+    fn transfers_without_a_peer_link_fail_and_leave_the_client_in_place() {
+        let mut harness = Harness::new(1);
+        harness.wait_for_players();
+        let id = harness.client_id(0).unwrap();
+        let entity = harness.server.world.resource::<NetworkToWorld<Server>>()[&id];
+
+        harness.server.world.send_event(TransferClient {
+            entity,
+            addr: next_local_addr().parse().unwrap(),
+        });
+        harness.step_until("the transfer failed", |harness| {
+            let events = harness.server.world.resource::<Events<TransferFailed>>();
+            events
+                .get_reader()
+                .iter(events)
+                .any(|failed| failed.entity == entity)
+        });
+
+        harness.send_from_client(0, PathTargetRequest { x: 2, y: 0 });
+        harness.wait_until_client_sees(0, id, Position { x: 2, y: 0 });
+        assert!(!harness.clients[0]
+            .world
+            .contains_resource::<PendingTransfer>());
+    }
+}
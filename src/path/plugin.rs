@@ -1,20 +1,26 @@
 use bevy::{
     app::PluginGroupBuilder,
+    ecs::world::EntityMut,
     prelude::{
         Changed, Commands, Component, Deref, DerefMut, DetectChanges, Entity, EventReader,
         EventWriter, Input, IntoSystemDescriptor, MouseButton, Plugin, PluginGroup, Query, Res,
-        ResMut, Resource, With,
+        ResMut, Resource, With, Without,
     },
 };
 use tracing::{error, info};
 
 use super::packet::{PathTarget, PathTargetRequest};
 use crate::{
+    ambit::plugin::VisibleEntities,
     client::camera::MouseWorldCoordinates,
     id::{NetworkId, NetworkToWorld},
     network::{
+        budget::PendingUpdates,
         mediator::PacketWithConnId,
         plugin::{Client, EntityQuery, Me, Network, Packets, Server},
+        replicate::ReplicateAppExt,
+        spectate::Spectating,
+        transfer::Transferring,
     },
     stat::MovementSpeed,
     time::{
@@ -45,13 +51,14 @@ impl PluginGroup for PathPlugins {
     }
 }
 
-struct BasePathPlugin;
-struct ServerPathPlugin;
-struct ClientPathPlugin;
+pub(crate) struct BasePathPlugin;
+pub(crate) struct ServerPathPlugin;
+pub(crate) struct ClientPathPlugin;
 
 impl Plugin for BasePathPlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
         app.add_event::<Target>();
+        app.replicate::<MovementSpeed>();
         app.insert_resource(PathResource(InnerTimingWheelTree::new(2)));
         app.add_system(pathfind.label("pathfind"));
         app.add_system(schedule_next_position.after("pathfind").label("schedule"));
@@ -73,7 +80,7 @@ impl Plugin for ServerPathPlugin {
 impl Plugin for ClientPathPlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
         app.add_system(request_path);
-        app.add_system(receive_from_server);
+        app.add_system(receive_from_server.label("path_targets"));
     }
 }
 
@@ -82,7 +89,7 @@ impl Plugin for ClientPathPlugin {
 struct PathResource<T>(T);
 
 // components
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Component)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Component)]
 pub(crate) struct Position {
     pub(crate) x: i32,
     pub(crate) y: i32,
@@ -140,19 +147,74 @@ impl NextPosition {
     }
 }
 
+/// The last target an entity was sent towards, and where it set out from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Component)]
+pub(crate) struct Heading {
+    pub(crate) target: Position,
+    pub(crate) origin: Position,
+}
+
+impl Heading {
+    /// The heading of an entity at `position` following `path`, unless it was
+    /// already sent one.
+    pub(crate) fn of(position: Position, path: &Path, heading: Option<&Heading>) -> Self {
+        heading.copied().unwrap_or(Self {
+            target: path.positions.first().copied().unwrap_or(position),
+            origin: position,
+        })
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Component, Default)]
 pub(crate) struct Path {
     positions: Vec<Position>,
 }
 
+impl Path {
+    /// The path from `position` to `target`.
+    pub(crate) fn between(position: Position, target: Position) -> Self {
+        let mut path = Self::default();
+        update_path(&mut path, target, position);
+        path
+    }
+}
+
 // events
 #[derive(Component)]
-struct Target {
-    entity: Entity,
-    position: Position,
+pub(crate) struct Target {
+    pub(crate) entity: Entity,
+    pub(crate) position: Position,
     current_or_next_position: Position,
 }
 
+impl Target {
+    pub(crate) fn new(
+        entity: Entity,
+        position: Position,
+        current_or_next_position: Position,
+    ) -> Self {
+        Self {
+            entity,
+            position,
+            current_or_next_position,
+        }
+    }
+}
+
+// util
+/// Moves `entity` to `position` at once, dropping the path it was on.
+pub(crate) fn teleport(entity: &mut EntityMut, position: Position) {
+    entity.insert((
+        position,
+        MaybeNextPosition::default(),
+        Path::default(),
+        Heading {
+            target: position,
+            origin: position,
+        },
+    ));
+}
+
 // systems
 
 fn set_position_from_next_position(
@@ -203,13 +265,13 @@ fn pathfind(
     mut commands: Commands,
     mut query: Query<(
         Option<&mut Path>,
-        Option<&Position>,
+        Option<&mut Position>,
         Option<&mut MaybeNextPosition>,
     )>,
     mut path_targets: EventReader<Target>,
 ) {
     for target in path_targets.iter() {
-        let (mut path, position, mut next_position) = match query.get_mut(target.entity) {
+        let (mut path, mut position, mut next_position) = match query.get_mut(target.entity) {
             Ok((Some(path), Some(position), Some(next_position))) => {
                 (path, position, next_position)
             }
@@ -246,6 +308,9 @@ fn pathfind(
                 .abs_diff(target.current_or_next_position.y)
                 > 4
         {
+            // too far off to walk back, e.g. after the server teleported it
+            *position = target.current_or_next_position;
+            next_position.next_position = None;
             update_path(&mut path, target.position, target.current_or_next_position);
         } else if current_target.map_or(true, |&current_target| current_target != target.position) {
             update_path(&mut path, target.position, current_or_next_position);
@@ -259,9 +324,9 @@ fn pathfind(
 }
 
 fn receive_from_client(
+    mut commands: Commands,
     mut path_targets: EventWriter<Target>,
     packets: Res<Packets<PacketWithConnId<PathTargetRequest>>>,
-    clients: Query<&Network<Client>>,
     positions: Query<(&Position, &MaybeNextPosition)>,
     entities: Res<NetworkToWorld<Server>>,
 ) {
@@ -280,24 +345,22 @@ fn receive_from_client(
             .position()
             .unwrap_or(*current_position);
 
-        let path_target = PathTarget {
-            id: packet.connection_id,
+        let target = Position {
             x: packet.packet.x,
             y: packet.packet.y,
-            current_or_next_x: current_or_next_position.x,
-            current_or_next_y: current_or_next_position.y,
         };
 
         path_targets.send(Target {
             entity: *entity,
-            position: Position {
-                x: packet.packet.x,
-                y: packet.packet.y,
-            },
+            position: target,
             current_or_next_position,
         });
 
-        let _ = Network::<Client>::send_all(clients.iter(), path_target);
+        // clients learn about it with their next snapshot
+        commands.entity(*entity).insert(Heading {
+            target,
+            origin: current_or_next_position,
+        });
     }
 }
 
@@ -328,12 +391,13 @@ fn receive_from_server(
 
 fn request_path(
     mut path_targets: EventWriter<Target>,
-    server: Query<&Network<Server>>,
+    server: Query<&Network<Server>, Without<Transferring>>,
     me: Query<(Entity, &Position), With<Me>>,
     mouse_world_coords: Res<MouseWorldCoordinates>,
     mouse_events: Res<Input<MouseButton>>,
+    spectating: Option<Res<Spectating>>,
 ) {
-    if mouse_events.just_pressed(MouseButton::Left) {
+    if mouse_events.just_pressed(MouseButton::Left) && spectating.is_none() {
         let Ok(server) = server.get_single() else {
             error!("Client not yet connected");
             return;
@@ -366,27 +430,33 @@ fn request_path(
 
 fn respond_to_queries(
     mut queries: EventReader<EntityQuery>,
-    query: Query<(&NetworkId, &Path, &Position)>,
-    clients: Query<&Network<Client>>,
+    query: Query<(&NetworkId, &Path, &Position, Option<&Heading>)>,
+    mut clients: Query<(&VisibleEntities, &mut PendingUpdates)>,
 ) {
     for entity_query in queries.iter() {
-        let Ok((&id, path, position)) = query.get(entity_query.entity) else {
+        let Ok((&id, path, position, heading)) = query.get(entity_query.entity) else {
             continue;
         };
 
-        let Ok(client) = clients.get(entity_query.querier) else {
+        let Ok((visible, mut updates)) = clients.get_mut(entity_query.querier) else {
             continue;
         };
+        // clients only learn about themselves and the entities they see
+        if entity_query.entity != entity_query.querier && !visible.contains(id) {
+            continue;
+        }
 
+        // the same state snapshots carry, so that neither undoes the other
+        let Heading { target, origin } = Heading::of(*position, path, heading);
         let path_target = PathTarget {
             id,
-            x: path.positions.first().map(|p| p.x).unwrap_or(position.x),
-            y: path.positions.first().map(|p| p.y).unwrap_or(position.y),
-            current_or_next_x: position.x,
-            current_or_next_y: position.y,
+            x: target.x,
+            y: target.y,
+            current_or_next_x: origin.x,
+            current_or_next_y: origin.y,
         };
 
-        let _ = client.send(path_target);
+        let _ = updates.push(id, path_target);
     }
 }
 
@@ -425,7 +495,18 @@ fn update_path(path: &mut Path, target: Position, position: Position) {
 
 #[cfg(test)]
 mod tests {
+    use std::sync::{Arc, Mutex};
+
     use super::*;
+    use crate::{
+        ambit::packet::QueryEntity,
+        network::{
+            harness::Harness,
+            mediator::Verdict,
+            packet::{AnyPacketWithConnId, ServerPacket},
+            plugin::AddPacketAppExt,
+        },
+    };
 
     #[test]
     fn should_update_path_pos_x_and_z() {
@@ -503,4 +584,55 @@ mod tests {
         assert_eq!(path.positions[4], Position { x: 3, y: -2 });
         assert_eq!(path.positions[5], Position { x: 3, y: -3 });
     }
+
+    #[test]
+    fn path_target_syncs_to_client() {
+        let mut harness = Harness::new(1);
+        harness.wait_for_players();
+        let mover = harness.client_id(0).unwrap();
+
+        harness.send_from_client(0, PathTargetRequest { x: 2, y: -3 });
+
+        harness.wait_until_client_sees(0, mover, Position { x: 2, y: -3 });
+    }
+
+    #[test]
+    fn movement_is_only_sent_to_observers() {
+        let mut harness = Harness::new(2);
+        harness.wait_for_players();
+        let walker = harness.client_id(0).unwrap();
+
+        harness.send_from_client(0, PathTargetRequest { x: 0, y: 13 });
+        harness.wait_until_client_sees(0, walker, Position { x: 0, y: 13 });
+        harness.step_until("the walker is out of view", |harness| {
+            !harness.clients[1]
+                .world
+                .resource::<NetworkToWorld<Client>>()
+                .contains_key(&walker)
+        });
+
+        // every entity the other client hears about from now on
+        let heard = Arc::new(Mutex::new(Vec::new()));
+        let record = heard.clone();
+        harness.clients[1].add_middleware(move |packet: AnyPacketWithConnId<ServerPacket>| {
+            let mut heard = record.lock().unwrap();
+            match &packet.packet {
+                ServerPacket::PathTarget(target) => heard.push(target.id),
+                ServerPacket::Snapshot(snapshot) => {
+                    heard.extend(snapshot.entities.iter().map(|delta| delta.id))
+                }
+                _ => {}
+            }
+            Verdict::Pass(packet)
+        });
+
+        harness.send_from_client(0, PathTargetRequest { x: 0, y: 20 });
+        harness.send_from_client(1, QueryEntity { id: walker });
+        harness.wait_until_client_sees(0, walker, Position { x: 0, y: 20 });
+        for _ in 0..50 {
+            harness.step();
+        }
+
+        assert!(!heard.lock().unwrap().contains(&walker));
+    }
 }
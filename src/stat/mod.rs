@@ -1,4 +1,11 @@
 use bevy::prelude::{Component, Deref};
+use speedy::{Readable, Writable};
 
-#[derive(Component, Deref)]
+use crate::network::replicate::{ComponentId, Replicated};
+
+#[derive(Component, Deref, Readable, Writable, Clone, Debug, PartialEq, Eq)]
 pub(crate) struct MovementSpeed(pub usize);
+
+impl Replicated for MovementSpeed {
+    const ID: ComponentId = ComponentId(0);
+}